@@ -0,0 +1,49 @@
+use common::PluginId;
+use serde::Deserialize;
+
+/// The id of the host itself.
+///
+/// Commands sent to this id, from a plugin or from the config file,
+/// are parsed as `AdminCommand`s and run by the host instead of a plugin.
+pub const HOST_ID: &str = "host";
+
+/// An operation on the host, encoded as json like `{"op": "reload", "plugin": "plugin_fw"}`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AdminCommand {
+    /// Replaces a plugin with one constructed from the current version of its library.
+    Reload { plugin: PluginId },
+    /// Closes a plugin and forgets about its library.
+    Unload { plugin: PluginId },
+    /// Loads the config file again, see `reload`.
+    ReloadConfig,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_command() {
+        let command: AdminCommand =
+            serde_json::from_str(r#"{"op": "reload", "plugin": "plugin_fw"}"#).unwrap();
+        assert_eq!(
+            command,
+            AdminCommand::Reload {
+                plugin: PluginId::from("plugin_fw")
+            }
+        );
+
+        let command: AdminCommand =
+            serde_json::from_str(r#"{"op": "unload", "plugin": "plugin_server"}"#).unwrap();
+        assert_eq!(
+            command,
+            AdminCommand::Unload {
+                plugin: PluginId::from("plugin_server")
+            }
+        );
+
+        let command: AdminCommand = serde_json::from_str(r#"{"op": "reload_config"}"#).unwrap();
+        assert_eq!(command, AdminCommand::ReloadConfig);
+    }
+}
@@ -1,34 +1,175 @@
 use core_extensions::StringExt;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
     mem,
+    path::PathBuf,
     time::{Duration, Instant},
 };
 
 use abi_stable::{
-    external_types::crossbeam_channel::{self, RReceiver, RSender},
-    sabi_trait::TD_Opaque,
-    std_types::{RArc, ROption::RSome, RStr},
+    external_types::crossbeam_channel::{unbounded, RReceiver, RSender},
+    std_types::{RArc, RBoxError, ROption::RSome, RStr, RString, RVec},
 };
 use common::{
-    Application, Application_TO, Error as AppError, PluginCommand, PluginFactory_Ref, PluginId,
-    PluginResponse, PluginType,
+    error::Unsupported,
+    openc2::{
+        args::ResponseRequested,
+        command::{OpenC2Action, OpenC2Command},
+        response::{CommandReply, OpenC2RespStatus, OpenC2Response},
+        target::{Features, Target},
+        view::CommandView,
+        OpenC2MsgType,
+    },
+    CommandDescription, Error as AppError, PluginCommand, PluginId, PluginResponse,
 };
+use serde_json::{json, Value};
+
+use crate::admin::{AdminCommand, HOST_ID};
+use crate::batch::{Batch, BatchId};
+use crate::correlation::Correlations;
+use crate::dedup::{CommandKey, RecentCommands};
+use crate::journal::{Accepted, EntryId, Journal};
+use crate::plugin::{
+    self,
+    watcher::LibraryWatcher,
+    worker::{Backoff, Closing, Job, JobId, PluginWorker, WorkerEvent},
+    PluginSource,
+};
+use crate::reload::LoadedConfig;
+use crate::router::Router;
+use crate::runtime::Event;
+use crate::schedule::{now_millis, Schedule};
+use crate::script::{Outcome, Script};
+use crate::transport::RemoteCommand;
+use crate::{info, warn};
 
 pub struct TheApplication {
-    pub(super) plugins: HashMap<PluginId, PluginType>,
+    pub(super) plugins: HashMap<PluginId, PluginWorker>,
     pub(super) state: ApplicationState,
+    pub(super) watcher: Option<LibraryWatcher>,
+    pub(super) router: Router,
+    /// How long each plugin gets to close when it's unloaded or reloaded.
+    pub(super) close_timeout: Duration,
+    /// How many actuators of a batch are handled at the same time,
+    /// the ones routed to the same plugin are still handled one after the other by its worker.
+    pub(super) batch_concurrency: usize,
+    pub(super) config: LoadedConfig,
 }
 
 pub struct ApplicationState {
-    pub(super) id_map: HashMap<PluginId, PluginFactory_Ref>,
+    pub(super) id_map: HashMap<PluginId, PluginSource>,
+    pub(super) library_paths: HashMap<PluginId, PathBuf>,
+    /// Incremented every time a library is reloaded, to name its copy.
+    pub(super) library_version: u64,
+    /// The commands declared by the plugins that declare them.
+    pub(super) supported_commands: HashMap<PluginId, RVec<CommandDescription>>,
+    /// The plugins in the order they were loaded, they're closed in reverse order.
+    pub(super) load_order: Vec<PluginId>,
     pub(super) commands: VecDeque<RArc<PluginCommand>>,
     pub(super) responses: VecDeque<RArc<PluginResponse>>,
     pub(super) sender: RSender<PluginCommand>,
     pub(super) receiver: RReceiver<PluginCommand>,
+    /// Where plugin workers send what they did.
+    pub(super) events: Sender<Event>,
+    /// How long to wait before constructing a failed plugin again.
+    pub(super) restart_backoff: Backoff,
     pub(super) last_run_at: Instant,
+    pub(super) next_job: JobId,
+    /// The commands that plugins are handling.
+    pub(super) in_flight: HashMap<JobId, InFlight>,
+    /// The commands waiting for their `start_time`.
+    pub(super) deferred: Vec<Deferred>,
+    /// The commands that plugins are handling, by their `request_id`.
+    pub(super) correlations: Correlations,
+    pub(super) next_batch: BatchId,
+    /// The commands addressed to several actuators that are running.
+    pub(super) batches: HashMap<BatchId, PendingBatch>,
+    /// Records the commands handed to plugins, if it's enabled.
+    pub(super) journal: Option<Journal>,
+    /// The commands that came in over transports lately, to answer the ones sent again.
+    pub(super) recent: RecentCommands,
+    /// The script of the config, if it has one.
+    pub(super) script: Option<Script>,
+    /// The plugins that were unloaded or replaced and are still closing.
+    pub(super) closing: Vec<Closing>,
 }
 
+/// A command that waits for its `start_time` to run.
+pub(super) struct Deferred {
+    pub(super) run_at: Instant,
+    pub(super) plugin_id: PluginId,
+    pub(super) command: CommandView,
+    pub(super) schedule: Schedule,
+    pub(super) origin: Origin,
+    pub(super) entry: Option<EntryId>,
+}
+
+/// A command addressed to several actuators, and who gets its aggregated response.
+pub(super) struct PendingBatch {
+    pub(super) batch: Batch,
+    pub(super) origin: Origin,
+}
+
+/// Who gets the reply to a command.
+#[derive(Clone)]
+pub(super) enum Origin {
+    /// A command from the config file, whose responses are printed.
+    Host,
+    /// A command that a plugin sent, whose reply is handed to that plugin.
+    Plugin(PluginId),
+    /// A command that came in over a transport, whose responses are sent back to it.
+    ///
+    /// The channel is disconnected once every clone of it is dropped,
+    /// which tells the transport that there won't be any more responses.
+    Remote {
+        transport: &'static str,
+        responses: Sender<OpenC2Response>,
+        /// Identifies the command, if it parses.
+        key: Option<CommandKey>,
+    },
+    /// The command for one actuator of a batch, whose final responses are aggregated.
+    Batch { batch: BatchId, actuator_id: String },
+    /// The command of a step of the script, whose final responses are checked.
+    Script { step: usize },
+}
+
+impl Origin {
+    /// Names where a command came from, in logs.
+    pub(super) fn source(&self) -> String {
+        match self {
+            Origin::Host => "config".into(),
+            Origin::Plugin(plugin_id) => format!("plugin {:?}", plugin_id),
+            Origin::Remote { transport, .. } => transport.to_string(),
+            Origin::Batch { batch, .. } => format!("batch {}", batch),
+            Origin::Script { step } => format!("step {} of the script", step + 1),
+        }
+    }
+
+    /// Whether the application handles the responses to the command,
+    /// so it gets the final ones whatever it asked for, and no others.
+    pub(super) fn is_internal(&self) -> bool {
+        matches!(
+            self,
+            Origin::Plugin(_) | Origin::Batch { .. } | Origin::Script { .. }
+        )
+    }
+}
+
+/// A command that a plugin is handling.
+pub(super) struct InFlight {
+    pub(super) plugin_id: PluginId,
+    pub(super) command: CommandView,
+    pub(super) response_requested: ResponseRequested,
+    pub(super) origin: Origin,
+    /// When the command times out, if it has a timeout.
+    pub(super) deadline: Option<Instant>,
+    pub(super) entry: Option<EntryId>,
+}
+
+/// The OpenC2 language version the application answers `query features` with.
+const OPENC2_VERSION: &str = "1.0";
+
 fn print_response(plugin_id: &PluginId, response: &str) {
     println!(
         "reponse:\n{}\nfrom:\n    {:?}\n\n",
@@ -37,92 +178,1560 @@ fn print_response(plugin_id: &PluginId, response: &str) {
     );
 }
 
+fn parse_openc2(command: RStr<'_>) -> Result<OpenC2Command, AppError> {
+    serde_json::from_str(command.as_str())
+        .map_err(|e| AppError::Deserialize(RBoxError::new(e), OpenC2MsgType::Request))
+}
+
+fn encode_response(response: &OpenC2Response) -> Result<RString, AppError> {
+    serde_json::to_string(response)
+        .map(RString::from)
+        .map_err(|e| AppError::Serialize(RBoxError::new(e), OpenC2MsgType::Response))
+}
+
+/// The request id of a command that doesn't parse, if it has one.
+fn request_id_of(command: &str) -> String {
+    serde_json::from_str::<Value>(command)
+        .ok()
+        .and_then(|command| command["header"]["request_id"].as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+/// The reply to a command that could not be handled.
+fn error_reply(error: &AppError) -> CommandReply {
+    let status = match error {
+        AppError::Deserialize(_, OpenC2MsgType::Request) => OpenC2RespStatus::BadRequest,
+        AppError::UnsupportedCommand(_) => OpenC2RespStatus::NotImplemented,
+        _ => OpenC2RespStatus::InternalError,
+    };
+    CommandReply::new_status(status, error.to_string().trim_end())
+}
+
 impl TheApplication {
     pub fn run_command(&mut self, plugin_id: &PluginId, command: RStr<'_>) -> Result<(), AppError> {
-        let state = Application_TO::from_ptr(&mut self.state, TD_Opaque);
-        let plugin = self.plugins.get_mut(plugin_id).unwrap();
-        let resp = plugin.send_command(command, state).into_result()?;
-        self.state.register_command_run();
-        print_response(&plugin_id, &resp);
+        self.run_command_from(plugin_id, command, Origin::Host)
+    }
+
+    /// Runs a command on a plugin, whose responses are sent to `origin`.
+    fn run_command_from(
+        &mut self,
+        plugin_id: &PluginId,
+        command: RStr<'_>,
+        origin: Origin,
+    ) -> Result<(), AppError> {
+        if plugin_id == HOST_ID {
+            if let Origin::Plugin(from) = &origin {
+                if !self.may_run_admin_commands(from) {
+                    warn!("{:?} is not allowed to run admin commands", from);
+                    let response = CommandReply::new_status(
+                        OpenC2RespStatus::Forbidden,
+                        &format!("{:?} is not allowed to run admin commands", from),
+                    )
+                    .into_response("", HOST_ID)?;
+                    return self.state.respond(&origin, plugin_id, response);
+                }
+            }
+            let resp = self.run_admin_command(command)?;
+            if let Origin::Host = origin {
+                print_response(plugin_id, &resp);
+                return Ok(());
+            }
+            let response =
+                CommandReply::new_status(OpenC2RespStatus::OK, &resp).into_response("", HOST_ID)?;
+            return self.state.respond(&origin, plugin_id, response);
+        }
+
+        let openc2 = parse_openc2(command)?;
+        let view = CommandView::new(&openc2, command.as_str());
+        self.run_parsed_command(plugin_id, &view, Schedule::of(&openc2), origin, None)
+    }
+
+    /// Hands a command that was already parsed to a plugin,
+    /// whose reply is sent to `origin` once it arrives in `handle_worker_event`.
+    ///
+    /// Commands whose `start_time` is still to come are deferred until then,
+    /// and commands that are past their `stop_time`, or whose plugin doesn't reply in time,
+    /// are answered with a timeout response.
+    ///
+    /// The command is recorded in the journal, unless it already is as `entry`.
+    fn run_parsed_command(
+        &mut self,
+        plugin_id: &PluginId,
+        command: &CommandView,
+        schedule: Schedule,
+        origin: Origin,
+        entry: Option<EntryId>,
+    ) -> Result<(), AppError> {
+        if !self.plugins.contains_key(plugin_id) {
+            return Err(AppError::invalid_plugin_id(plugin_id.clone()));
+        }
+        let entry = entry.or_else(|| self.state.accept(plugin_id, command, &origin));
+
+        let now = now_millis();
+        if let Some(until_start) = schedule.until_start(now) {
+            info!(
+                "deferring request {} to {:?} for {:?}",
+                command.request_id, plugin_id, until_start
+            );
+            self.state.deferred.push(Deferred {
+                run_at: Instant::now() + until_start,
+                plugin_id: plugin_id.clone(),
+                command: command.clone(),
+                schedule,
+                origin,
+                entry,
+            });
+            return Ok(());
+        }
+
+        if schedule.is_expired(now) {
+            let expired = CommandReply::new_status(
+                OpenC2RespStatus::Timeout,
+                "the command is past its stop_time",
+            );
+            return self
+                .state
+                .deliver_reply(&origin, plugin_id, command, entry, expired, true);
+        }
+
+        let response_requested = command.response_requested();
+        let acked = matches!(
+            response_requested,
+            ResponseRequested::Ack | ResponseRequested::Status
+        );
+        if acked && !origin.is_internal() {
+            let ack = CommandReply::new_status(OpenC2RespStatus::Processing, "");
+            let response = ack.into_response(&command.request_id, plugin_id)?;
+            self.state.respond(&origin, plugin_id, response)?;
+        }
+
+        self.start_job(plugin_id, command, origin, schedule.timeout(now), entry)
+    }
+
+    /// Queues a command on the worker of a plugin.
+    fn start_job(
+        &mut self,
+        plugin_id: &PluginId,
+        command: &CommandView,
+        origin: Origin,
+        timeout: Option<Duration>,
+        entry: Option<EntryId>,
+    ) -> Result<(), AppError> {
+        let worker = self
+            .plugins
+            .get(plugin_id)
+            .ok_or_else(|| AppError::invalid_plugin_id(plugin_id.clone()))?;
+
+        let job = self.state.next_job;
+        self.state.next_job += 1;
+        worker.send(Job::Command {
+            job,
+            command: command.clone(),
+        })?;
+
+        if let (Some(journal), Some(entry)) = (&mut self.state.journal, entry) {
+            journal.start(entry);
+        }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.state
+            .correlations
+            .start(&command.request_id, plugin_id, origin.source(), deadline);
+        self.state.in_flight.insert(
+            job,
+            InFlight {
+                plugin_id: plugin_id.clone(),
+                command: command.clone(),
+                response_requested: command.response_requested(),
+                origin,
+                deadline,
+                entry,
+            },
+        );
         Ok(())
     }
 
-    pub fn tick(&mut self) -> Result<(), AppError> {
-        if let Ok(command) = self.state.receiver.try_recv() {
-            self.state.send_command_to_plugin(RArc::new(command));
+    /// Runs the deferred commands whose `start_time` came.
+    pub fn run_deferred(&mut self) {
+        let now = Instant::now();
+        let (due, deferred) = mem::take(&mut self.state.deferred)
+            .into_iter()
+            .partition::<Vec<Deferred>, _>(|deferred| deferred.run_at <= now);
+        self.state.deferred = deferred;
+
+        for deferred in due {
+            if let Err(e) = self.run_parsed_command(
+                &deferred.plugin_id,
+                &deferred.command,
+                Schedule {
+                    start_time: None,
+                    ..deferred.schedule
+                },
+                deferred.origin.clone(),
+                deferred.entry,
+            ) {
+                eprintln!(
+                    "Error while running deferred request {} on:\n{:?}\nError:{}\n",
+                    deferred.command.request_id, deferred.plugin_id, e
+                );
+                if let Err(e) = self.state.deliver_reply(
+                    &deferred.origin,
+                    &deferred.plugin_id,
+                    &deferred.command,
+                    deferred.entry,
+                    error_reply(&e),
+                    true,
+                ) {
+                    eprintln!("Error in application loop:\n{}\n", e);
+                }
+            }
+        }
+    }
+
+    /// Parses an OpenC2 command and runs it on every loaded plugin registered for it
+    /// that supports it, answering with a 404 response when there's none.
+    ///
+    /// `query features` commands are answered by the application,
+    /// from the commands that the registered plugins support.
+    pub fn dispatch(&mut self, command: RStr<'_>) -> Result<(), AppError> {
+        self.dispatch_from(command, Origin::Host)
+    }
+
+    /// Dispatches a command that came in over a transport,
+    /// answering it with an error response if it can't be dispatched.
+    ///
+    /// A command that was already seen lately is answered with the final responses to it,
+    /// or with a `102 Processing` response if it's still running, instead of running again.
+    pub fn handle_remote_command(&mut self, remote: RemoteCommand) {
+        // Commands that don't parse are answered by `dispatch_from`.
+        let key = parse_openc2(remote.command.as_str().into())
+            .ok()
+            .map(|openc2| openc2.tuple_actuator_msg_id());
+        if let Some(key) = &key {
+            if self.answer_duplicate(key, remote.transport, &remote.responses) {
+                return;
+            }
+        }
+
+        let origin = Origin::Remote {
+            transport: remote.transport,
+            responses: remote.responses,
+            key,
+        };
+        let e = match self.dispatch_from(remote.command.as_str().into(), origin.clone()) {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        eprintln!(
+            "Error while dispatching command:\n{:?}\nError:{}\n",
+            remote.command, e
+        );
+        let request_id = request_id_of(&remote.command);
+        let res = error_reply(&e)
+            .into_response(&request_id, HOST_ID)
+            .and_then(|response| {
+                self.state
+                    .respond(&origin, &PluginId::from(HOST_ID), response)
+            });
+        if let Err(e) = res {
+            eprintln!("Error in application loop:\n{}\n", e);
+        }
+    }
+
+    /// Answers a command that was already seen lately, returning false if it wasn't.
+    fn answer_duplicate(
+        &mut self,
+        key: &CommandKey,
+        transport: &str,
+        responses: &Sender<OpenC2Response>,
+    ) -> bool {
+        if !self.state.recent.is_enabled() {
+            return false;
+        }
+        let (request_id, actuator_id) = key;
+        let answers = if self.state.is_running(key) {
+            vec![OpenC2Response::new_status(
+                vec![],
+                request_id.as_str(),
+                HOST_ID,
+                OpenC2RespStatus::Processing,
+                "the command is already running",
+            )]
+        } else {
+            match self.state.recent.check(key, Instant::now()) {
+                Some(cached) => cached.to_vec(),
+                None => return false,
+            }
+        };
+
+        info!(
+            "request {} to actuator {:?} from {} was already seen, answering with {} responses",
+            request_id,
+            actuator_id,
+            transport,
+            answers.len()
+        );
+        for response in answers {
+            let _ = responses.send(response);
         }
+        true
+    }
+
+    fn dispatch_from(&mut self, command: RStr<'_>, origin: Origin) -> Result<(), AppError> {
+        let openc2 = parse_openc2(command)?;
+        if let Some(batch) = Batch::of(&openc2, self.batch_concurrency) {
+            return self.start_batch(batch, origin);
+        }
+
+        let plugin_ids = self
+            .router
+            .route(&openc2)
+            .into_iter()
+            .filter(|plugin_id| self.plugins.contains_key(plugin_id))
+            .collect::<Vec<PluginId>>();
+
+        let (action, target) = openc2.get_command_identity();
+        if plugin_ids.is_empty() {
+            let desc = format!(
+                "no plugin is registered for actuator '{}' to {} {}",
+                openc2.actuator_id_to_string(),
+                action.as_ref(),
+                target
+            );
+            let response = OpenC2Response::new_status(
+                vec![],
+                openc2.get_request_id().as_str(),
+                HOST_ID,
+                OpenC2RespStatus::NotFound,
+                desc.as_str(),
+            );
+            return self.state.respond_to_command(&origin, &openc2, response);
+        }
+
+        if let (OpenC2Action::Query, Target::Features(features)) = (&action, openc2.get_target()) {
+            let response = self.query_features(&openc2, features, &plugin_ids);
+            return self.state.respond_to_command(&origin, &openc2, response);
+        }
+
+        let (supported, unsupported): (Vec<PluginId>, Vec<PluginId>) = plugin_ids
+            .into_iter()
+            .partition(|plugin_id| self.state.supports(plugin_id, &action, &target));
+
+        let mut errs = Vec::<AppError>::new();
+        if supported.is_empty() {
+            for plugin_id in unsupported {
+                let mut unsupported = Unsupported {
+                    plugin_name: plugin_id.clone(),
+                    command_name: format!("{} {}", action.as_ref(), target).into(),
+                    error: RBoxError::from_fmt(&"the plugin did not declare this command"),
+                };
+                unsupported.list_supported_commands(&self.state.supported_commands[&plugin_id]);
+                errs.push(AppError::unsupported_command(unsupported));
+            }
+        }
+
+        let view = CommandView::new(&openc2, command.as_str());
+        let schedule = Schedule::of(&openc2);
+        for plugin_id in supported {
+            if let Err(e) =
+                self.run_parsed_command(&plugin_id, &view, schedule, origin.clone(), None)
+            {
+                errs.push(e);
+            }
+        }
+        match errs.len() {
+            0 => Ok(()),
+            1 => Err(errs.remove(0)),
+            _ => Err(AppError::Many(errs.into())),
+        }
+    }
+
+    /// Runs a command addressed to several actuators as one command per actuator,
+    /// like `dispatch` does for each of them,
+    /// answering with the aggregated response once every actuator was handled.
+    fn start_batch(&mut self, batch: Batch, origin: Origin) -> Result<(), AppError> {
+        let command = batch.command();
+        if let Some(ResponseRequested::Ack | ResponseRequested::Status) =
+            command.get_response_requested()
+        {
+            let ack = CommandReply::new_status(OpenC2RespStatus::Processing, "");
+            let response = ack.into_response(command.get_request_id(), HOST_ID)?;
+            self.state
+                .respond(&origin, &PluginId::from(HOST_ID), response)?;
+        }
+
+        let id = self.state.next_batch;
+        self.state.next_batch += 1;
+        self.state
+            .batches
+            .insert(id, PendingBatch { batch, origin });
+        self.advance_batch(id);
+        Ok(())
+    }
+
+    /// Advances every batch, see `advance_batch`.
+    pub fn advance_batches(&mut self) {
+        let ids = self.state.batches.keys().copied().collect::<Vec<BatchId>>();
+        for id in ids {
+            self.advance_batch(id);
+        }
+    }
+
+    /// Marks the actuators of a batch that nothing is handling anymore as done,
+    /// and dispatches the commands for the next ones,
+    /// sending the aggregated response once there are none left.
+    fn advance_batch(&mut self, id: BatchId) {
+        loop {
+            let pending = match self.state.batches.get(&id) {
+                Some(x) => x,
+                None => return,
+            };
+            let done = pending
+                .batch
+                .running()
+                .iter()
+                .filter(|actuator_id| !self.state.is_handling(id, actuator_id))
+                .cloned()
+                .collect::<Vec<String>>();
+            let pending = self.state.batches.get_mut(&id).unwrap();
+            for actuator_id in &done {
+                pending.batch.finish(actuator_id);
+            }
+
+            if pending.batch.is_done() {
+                let PendingBatch { batch, origin } = self.state.batches.remove(&id).unwrap();
+                let requested = match batch.command().get_response_requested() {
+                    Some(ResponseRequested::None) => false,
+                    Some(ResponseRequested::Ack) => !batch.is_ok(),
+                    _ => true,
+                };
+                if requested {
+                    let response = batch.into_response(HOST_ID);
+                    if let Err(e) = self
+                        .state
+                        .respond(&origin, &PluginId::from(HOST_ID), response)
+                    {
+                        eprintln!("Error in application loop:\n{}\n", e);
+                    }
+                }
+                return;
+            }
+
+            let started = pending.batch.start_next();
+            if started.is_empty() {
+                return;
+            }
+            for actuator_id in started {
+                let pending = self.state.batches.get(&id).unwrap();
+                let request_id = pending.batch.command().get_request_id().clone();
+                let origin = Origin::Batch {
+                    batch: id,
+                    actuator_id: actuator_id.clone(),
+                };
+                let res = pending
+                    .batch
+                    .device_command(&actuator_id)
+                    .and_then(|command| {
+                        self.dispatch_from(command.as_str().into(), origin.clone())
+                    });
+                if let Err(e) = res {
+                    eprintln!(
+                        "Error while dispatching request {} to actuator {:?}:\n{}\n",
+                        request_id, actuator_id, e
+                    );
+                    let res = error_reply(&e)
+                        .into_response(&request_id, HOST_ID)
+                        .and_then(|response| {
+                            self.state
+                                .respond(&origin, &PluginId::from(HOST_ID), response)
+                        });
+                    if let Err(e) = res {
+                        eprintln!("Error in application loop:\n{}\n", e);
+                    }
+                }
+            }
+        }
+    }
 
+    /// Checks the step of the script that's running once nothing is handling its command anymore,
+    /// and sends the command of the next one once its delay passed.
+    pub fn advance_script(&mut self) {
+        loop {
+            let script = match self.state.script.as_mut() {
+                Some(x) => x,
+                None => return,
+            };
+            if let Some(step) = script.running() {
+                if self.state.is_running_step(step) {
+                    return;
+                }
+                let script = self.state.script.as_mut().unwrap();
+                match script.finish(Instant::now()) {
+                    Outcome::Done => info!("step {} of the script done", step + 1),
+                    Outcome::Passed => info!("step {} of the script passed", step + 1),
+                    Outcome::Failed(reason) => {
+                        eprintln!("Step {} of the script failed: {}", step + 1, reason)
+                    }
+                }
+                if script.is_done() {
+                    info!(
+                        "script finished, {} of {} steps failed",
+                        script.failed(),
+                        script.len()
+                    );
+                    return;
+                }
+                continue;
+            }
+
+            let (step, plugin_id, command) = match script.start_next(Instant::now()) {
+                Some((step, x)) => (step, x.plugin.clone(), x.command.get().to_string()),
+                None => return,
+            };
+            let origin = Origin::Script { step };
+            let res = match &plugin_id {
+                Some(plugin_id) => {
+                    self.run_command_from(plugin_id, command.as_str().into(), origin.clone())
+                }
+                None => self.dispatch_from(command.as_str().into(), origin.clone()),
+            };
+            if let Err(e) = res {
+                eprintln!(
+                    "Error while running step {} of the script:\n{}\n",
+                    step + 1,
+                    e
+                );
+                let request_id = parse_openc2(command.as_str().into())
+                    .map(|openc2| openc2.get_request_id().clone())
+                    .unwrap_or_default();
+                let res = error_reply(&e)
+                    .into_response(&request_id, HOST_ID)
+                    .and_then(|response| {
+                        self.state
+                            .respond(&origin, &PluginId::from(HOST_ID), response)
+                    });
+                if let Err(e) = res {
+                    eprintln!("Error in application loop:\n{}\n", e);
+                }
+            }
+        }
+    }
+
+    /// Answers a `query features` command for the plugins registered for its actuator.
+    ///
+    /// Supports the `versions` and `pairs` features, ignoring the others.
+    fn query_features(
+        &self,
+        openc2: &OpenC2Command,
+        features: &Features,
+        plugin_ids: &[PluginId],
+    ) -> OpenC2Response {
+        let mut results = serde_json::Map::new();
+        for feature in features.inner() {
+            match feature.as_str() {
+                "versions" => {
+                    results.insert(feature.clone(), json!([OPENC2_VERSION]));
+                }
+                "pairs" => {
+                    let mut pairs = BTreeMap::<&str, Vec<&str>>::new();
+                    for desc in plugin_ids
+                        .iter()
+                        .filter_map(|plugin_id| self.state.supported_commands.get(plugin_id))
+                        .flat_map(|supported| supported.iter())
+                    {
+                        let targets = pairs.entry(desc.action.as_ref()).or_default();
+                        if !targets.contains(&desc.target.as_str()) {
+                            targets.push(desc.target.as_str());
+                        }
+                    }
+                    results.insert(feature.clone(), json!(pairs));
+                }
+                _ => {}
+            }
+        }
+
+        let results = if results.is_empty() {
+            vec![]
+        } else {
+            vec![Value::Object(results)]
+        };
+        OpenC2Response::new(results, openc2.get_request_id().as_str(), HOST_ID)
+    }
+
+    /// Runs the next queued command, then hands every queued response to its plugin.
+    pub fn tick(&mut self) -> Result<(), AppError> {
         if let Some(command) = self.state.commands.pop_front() {
             self.run_command_(command)?;
         }
 
-        let mut responses = mem::replace(&mut self.state.responses, VecDeque::new());
-        for response in responses.drain(..) {
-            let state = Application_TO::from_ptr(&mut self.state, TD_Opaque);
-            let plugin_id = response.to.clone();
-            let plugin = self.plugins.get_mut(&plugin_id).unwrap();
-            if let RSome(res) = plugin
-                .handle_response(response.clone(), state)
-                .into_result()?
+        while let Some(response) = self.state.responses.pop_front() {
+            let worker = self
+                .plugins
+                .get(&response.to)
+                .ok_or_else(|| AppError::invalid_plugin_id(response.to.clone()))?;
+            worker.send(Job::Response(response))?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs ticks until there are no commands or responses left.
+    pub fn run_queued(&mut self) {
+        while !self.is_idle() {
+            if let Err(e) = self.tick() {
+                eprintln!("Error in application loop:\n{}\n", e);
+            }
+        }
+    }
+
+    /// Whether there are no commands or responses waiting to be handed to plugins.
+    pub fn is_idle(&self) -> bool {
+        self.state.commands.is_empty() && self.state.responses.is_empty()
+    }
+
+    /// Handles what a plugin did on its worker thread.
+    pub fn handle_worker_event(&mut self, event: WorkerEvent) {
+        match event {
+            WorkerEvent::Status { job, reply } => {
+                let in_flight = match self.state.in_flight.get(&job) {
+                    Some(x) => x,
+                    None => return,
+                };
+                let requested = match in_flight.response_requested {
+                    ResponseRequested::Status | ResponseRequested::Complete => {
+                        !in_flight.origin.is_internal()
+                    }
+                    _ => false,
+                };
+                if requested {
+                    let origin = in_flight.origin.clone();
+                    let plugin_id = in_flight.plugin_id.clone();
+                    let request_id = in_flight.command.request_id.clone();
+                    let res = reply
+                        .into_response(&request_id, &plugin_id)
+                        .and_then(|response| self.state.respond(&origin, &plugin_id, response));
+                    if let Err(e) = res {
+                        eprintln!(
+                            "Could not report the status of request {}, because of this error: {}",
+                            request_id, e
+                        );
+                    }
+                }
+            }
+            WorkerEvent::Replied { job, reply } => {
+                self.state.register_command_run();
+                let in_flight = match self.state.in_flight.remove(&job) {
+                    Some(x) => x,
+                    None => {
+                        warn!("Dropping a reply that came after its command timed out");
+                        return;
+                    }
+                };
+                match reply {
+                    Ok(reply) => {
+                        if let Err(e) = self.state.end_job(in_flight, reply, false) {
+                            eprintln!("Error in application loop:\n{}\n", e);
+                        }
+                    }
+                    Err(e) => {
+                        let e = self.state.fill_supported_commands(&in_flight.plugin_id, e);
+                        eprintln!(
+                            "Error while running request {} on:\n{:?}\nError:{}\n",
+                            in_flight.command.request_id, in_flight.plugin_id, e
+                        );
+                        if let Err(e) = self.state.end_job(in_flight, error_reply(&e), true) {
+                            eprintln!("Error in application loop:\n{}\n", e);
+                        }
+                    }
+                }
+            }
+            WorkerEvent::HandledResponse {
+                plugin_id,
+                response,
+            } => match response {
+                Ok(RSome(res)) => print_response(&plugin_id, &res.response),
+                Ok(_) => {}
+                Err(e) => eprintln!("Error in application loop:\n{}\n", e),
+            },
+            WorkerEvent::Failed {
+                plugin_id,
+                reason,
+                retry_in,
+            } => eprintln!(
+                "Plugin {:?} failed, constructing it again in {:?}, because of this error: {}",
+                plugin_id, retry_in, reason
+            ),
+            WorkerEvent::Restarted { plugin_id } => info!("restart {:?} success", plugin_id),
+            WorkerEvent::Closed { .. } => self.state.finish_closing(),
+        }
+    }
+
+    /// Answers the commands whose plugin didn't reply in time with a timeout response,
+    /// their replies are dropped if they come later.
+    pub fn expire_in_flight(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .state
+            .in_flight
+            .iter()
+            .filter(|(_, in_flight)| in_flight.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(job, _)| *job)
+            .collect::<Vec<JobId>>();
+
+        for job in expired {
+            let in_flight = self.state.in_flight.remove(&job).unwrap();
+            let desc = format!(
+                "the plugin did not reply to request {} in time",
+                in_flight.command.request_id
+            );
+            let reply = CommandReply::new_status(OpenC2RespStatus::Timeout, &desc);
+            if let Err(e) = self.state.end_job(in_flight, reply, true) {
+                eprintln!("Error in application loop:\n{}\n", e);
+            }
+        }
+    }
+
+    /// Handles the commands and responses that are already queued or that arrive
+    /// for at most `drain_timeout`,
+    /// then answers the commands that are still waiting for their `start_time`
+    /// and the batches that are still running with 500 responses,
+    /// and closes every plugin in the reverse order they were loaded.
+    ///
+    /// A plugin that takes longer than `close_timeout` to close makes the process exit.
+    pub fn shutdown(&mut self, events: &Receiver<Event>, drain_timeout: Duration) {
+        self.watcher = None;
+
+        let started_at = Instant::now();
+        loop {
+            self.run_queued();
+            self.expire_in_flight();
+            self.advance_batches();
+            if self.is_idle() && self.state.in_flight.is_empty() && self.state.batches.is_empty() {
+                break;
+            }
+            let remaining = drain_timeout.saturating_sub(started_at.elapsed());
+            if remaining.is_zero() {
+                warn!(
+                    "Dropping {} commands, {} responses and {} replies that were not handled within {:?}",
+                    self.state.commands.len(),
+                    self.state.responses.len(),
+                    self.state.in_flight.len(),
+                    drain_timeout,
+                );
+                break;
+            }
+            let timeout = self
+                .state
+                .until_next_deadline()
+                .map_or(remaining, |t| t.min(remaining));
+            match events.recv_timeout(timeout) {
+                Ok(Event::Command(command)) => self.state.commands.push_back(RArc::new(command)),
+                Ok(Event::Worker(event)) => self.handle_worker_event(event),
+                Ok(Event::Remote(command)) => self.handle_remote_command(command),
+                Ok(Event::Terminate | Event::ReloadConfig) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        self.abandon_deferred();
+        self.abandon_batches();
+        if let Some(script) = self
+            .state
+            .script
+            .as_ref()
+            .filter(|script| !script.is_done())
+        {
+            warn!("Stopping the script before its step {}", script.next() + 1);
+        }
+
+        for closing in mem::take(&mut self.state.closing) {
+            let plugin_id = closing.plugin_id().clone();
+            if !closing.wait() {
+                eprintln!(
+                    "Plugin {:?} did not close within {:?}",
+                    plugin_id, self.close_timeout
+                );
+            }
+        }
+        for plugin_id in mem::take(&mut self.state.load_order).iter().rev() {
+            if let Some(worker) = self.plugins.remove(plugin_id) {
+                plugin::close(worker, self.close_timeout, true);
+                info!("close {:?} success", plugin_id);
+            }
+        }
+    }
+
+    /// Answers the commands that are still waiting for their `start_time` with a 500 response.
+    fn abandon_deferred(&mut self) {
+        let deferred = mem::take(&mut self.state.deferred);
+        if !deferred.is_empty() {
+            warn!(
+                "Answering {} commands that were waiting for their start_time",
+                deferred.len()
+            );
+        }
+        for deferred in deferred {
+            let reply = CommandReply::new_status(
+                OpenC2RespStatus::InternalError,
+                "the application shut down before the start_time of the command",
+            );
+            if let Err(e) = self.state.deliver_reply(
+                &deferred.origin,
+                &deferred.plugin_id,
+                &deferred.command,
+                deferred.entry,
+                reply,
+                true,
+            ) {
+                eprintln!("Error in application loop:\n{}\n", e);
+            }
+        }
+    }
+
+    /// Sends the aggregated responses of the batches that are still running,
+    /// with a 500 response for each actuator that didn't answer.
+    fn abandon_batches(&mut self) {
+        let batches = mem::take(&mut self.state.batches);
+        if !batches.is_empty() {
+            warn!(
+                "Answering {} commands addressed to several actuators before they're done",
+                batches.len()
+            );
+        }
+        for (_, PendingBatch { mut batch, origin }) in batches {
+            let unanswered = OpenC2Response::new_status(
+                vec![],
+                batch.command().get_request_id().as_str(),
+                HOST_ID,
+                OpenC2RespStatus::InternalError,
+                "the application shut down before the actuator answered",
+            );
+            batch.abandon(unanswered);
+            if batch.command().get_response_requested() == Some(&ResponseRequested::None) {
+                continue;
+            }
+            let response = batch.into_response(HOST_ID);
+            if let Err(e) = self
+                .state
+                .respond(&origin, &PluginId::from(HOST_ID), response)
             {
-                print_response(&plugin_id, &res.response);
+                eprintln!("Error in application loop:\n{}\n", e);
+            }
+        }
+    }
+
+    /// Reloads the plugins whose library was rebuilt, if watching libraries is enabled.
+    pub(super) fn reload_changed_libraries(&mut self) {
+        let changed = match &mut self.watcher {
+            Some(watcher) => watcher.poll(&self.state.library_paths),
+            None => return,
+        };
+        for plugin_id in changed {
+            if let Err(e) = plugin::reload(
+                &mut self.plugins,
+                &mut self.state,
+                &plugin_id,
+                self.close_timeout,
+            ) {
+                eprintln!(
+                    "Could not reload plugin: {:?}, because of this error: {}",
+                    plugin_id, e
+                );
             }
         }
-        self.state.responses = responses;
+    }
 
-        Ok(())
+    fn run_admin_command(&mut self, command: RStr<'_>) -> Result<RString, AppError> {
+        let command = serde_json::from_str::<AdminCommand>(command.as_str())
+            .map_err(|e| AppError::Deserialize(RBoxError::new(e), OpenC2MsgType::Request))?;
+
+        match command {
+            AdminCommand::Reload { plugin } => {
+                plugin::reload(
+                    &mut self.plugins,
+                    &mut self.state,
+                    &plugin,
+                    self.close_timeout,
+                )?;
+                Ok(format!("reload {:?} success", plugin).into())
+            }
+            AdminCommand::Unload { plugin } => {
+                plugin::unload(
+                    &mut self.plugins,
+                    &mut self.state,
+                    &plugin,
+                    self.close_timeout,
+                )?;
+                Ok(format!("unload {:?} success", plugin).into())
+            }
+            AdminCommand::ReloadConfig => self
+                .reload_config()
+                .map(RString::from)
+                .map_err(|e| AppError::Custom(RBoxError::from_fmt(&e))),
+        }
     }
 
-    pub fn is_finished(&self) -> bool {
-        self.state.last_run_at.elapsed() >= Duration::from_secs(5)
+    /// Whether the plugin `plugin_id` may send admin commands to the host,
+    /// only the library plugins listed in `admin.allowed_plugins` may.
+    fn may_run_admin_commands(&self, plugin_id: &PluginId) -> bool {
+        matches!(
+            self.state.id_map.get(plugin_id),
+            Some(PluginSource::Library(_))
+        ) && self
+            .config
+            .current
+            .admin
+            .allowed_plugins
+            .contains(plugin_id)
     }
 
+    /// Hands a command that a plugin sent to the plugin it's addressed to,
+    /// whose reply is queued as a `PluginResponse` for the sender.
+    ///
+    /// Its `start_time` and `stop_time` apply like to the commands from the other origins,
+    /// and a command that can't be run is answered with an error response,
+    /// like `400 Bad Request` for one that doesn't parse.
     fn run_command_(&mut self, plugin_command: RArc<PluginCommand>) -> Result<(), AppError> {
-        let state = Application_TO::from_ptr(&mut self.state, TD_Opaque);
-        let plugin_id = plugin_command.to.clone();
-        let plugin = self.plugins.get_mut(&plugin_id).unwrap();
-        let response = plugin
-            .send_command(plugin_command.command.as_rstr(), state)
-            .into_result()?;
-
-        self.state.register_command_run();
-
-        let response = PluginResponse {
-            from: plugin_command.from.clone(),
-            to: plugin_command.to.clone(),
-            response,
+        let origin = Origin::Plugin(plugin_command.from.clone());
+        let e = match self.run_command_from(
+            &plugin_command.to,
+            plugin_command.command.as_rstr(),
+            origin.clone(),
+        ) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
         };
+        eprintln!(
+            "Error while running command from {:?} on {:?}:\n{:?}\nError:{}\n",
+            plugin_command.from, plugin_command.to, plugin_command.command, e
+        );
+        let request_id = request_id_of(&plugin_command.command);
+        let response = error_reply(&e).into_response(&request_id, HOST_ID)?;
+        self.state
+            .respond(&origin, &PluginId::from(HOST_ID), response)
+    }
 
-        self.state.responses.push_back(RArc::new(response));
-        Ok(())
+    /// Runs the commands that the journal recorded but didn't complete
+    /// before the process stopped, the ones past their `stop_time` are answered with a timeout
+    /// response instead.
+    ///
+    /// The replies to commands from transports are printed, since they're not connected anymore.
+    pub fn replay(&mut self, pending: Vec<Accepted>) {
+        for accepted in pending {
+            let plugin_id = PluginId::from(accepted.plugin_id.as_str());
+            let origin = match accepted.reply_to {
+                Some(from) if self.plugins.contains_key(from.as_str()) => {
+                    Origin::Plugin(from.as_str().into())
+                }
+                _ => Origin::Host,
+            };
+            let res = parse_openc2(accepted.command.as_str().into()).and_then(|openc2| {
+                let schedule = Schedule::of(&openc2);
+                let state = if schedule.is_expired(now_millis()) {
+                    "expired"
+                } else {
+                    "replaying"
+                };
+                info!(
+                    "{} request {} from {} to {:?}",
+                    state,
+                    openc2.get_request_id(),
+                    accepted.source,
+                    plugin_id
+                );
+                let view = CommandView::new(&openc2, &accepted.command);
+                self.run_parsed_command(&plugin_id, &view, schedule, origin, Some(accepted.id))
+            });
+            if let Err(e) = res {
+                eprintln!(
+                    "Could not replay a command from {} to {:?}, because of this error: {}",
+                    accepted.source, plugin_id, e
+                );
+                if let Some(journal) = &mut self.state.journal {
+                    journal.complete(accepted.id, None);
+                }
+            }
+        }
     }
 }
 
 impl ApplicationState {
-    pub(crate) fn new() -> Self {
-        let (sender, receiver) = crossbeam_channel::unbounded();
+    pub(crate) fn new(
+        events: Sender<Event>,
+        restart_backoff: Backoff,
+        correlations: Correlations,
+        journal: Option<Journal>,
+        recent: RecentCommands,
+        script: Option<Script>,
+    ) -> Self {
+        let (sender, receiver) = unbounded();
 
         Self {
             id_map: HashMap::new(),
+            library_paths: HashMap::new(),
+            library_version: 0,
+            supported_commands: HashMap::new(),
+            load_order: Vec::new(),
             commands: VecDeque::new(),
             responses: VecDeque::new(),
             sender,
             receiver,
+            events,
+            restart_backoff,
             last_run_at: Instant::now(),
+            next_job: 0,
+            in_flight: HashMap::new(),
+            deferred: Vec::new(),
+            correlations,
+            next_batch: 0,
+            batches: HashMap::new(),
+            journal,
+            recent,
+            script,
+            closing: Vec::new(),
         }
     }
 
+    /// Closes a plugin on its worker thread without waiting for it,
+    /// the event loop finishes closing it once it sends `WorkerEvent::Closed`.
+    pub(crate) fn close_later(&mut self, worker: PluginWorker, timeout: Duration) {
+        self.closing.push(worker.close_later(timeout));
+    }
+
+    /// Forgets the plugins that closed, or that didn't within their timeout, which is reported.
+    pub(super) fn finish_closing(&mut self) {
+        for closing in mem::take(&mut self.closing) {
+            let plugin_id = closing.plugin_id().clone();
+            match closing.poll() {
+                Some(closing) => self.closing.push(closing),
+                None => info!("close {:?} success", plugin_id),
+            }
+        }
+    }
+
+    /// Returns how long until the next plugin that is closing runs out of time.
+    pub(super) fn until_next_close_deadline(&self) -> Option<Duration> {
+        self.closing
+            .iter()
+            .map(|closing| closing.deadline().saturating_duration_since(Instant::now()))
+            .min()
+    }
+
     fn register_command_run(&mut self) {
         self.last_run_at = Instant::now();
     }
+
+    /// Returns how long until the next deferred command should run.
+    pub(super) fn until_next_deferred(&self) -> Option<Duration> {
+        self.deferred
+            .iter()
+            .map(|deferred| deferred.run_at.saturating_duration_since(Instant::now()))
+            .min()
+    }
+
+    /// Returns how long until the next command that plugins are handling times out.
+    pub(super) fn until_next_deadline(&self) -> Option<Duration> {
+        self.in_flight
+            .values()
+            .filter_map(|in_flight| in_flight.deadline)
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .min()
+    }
+
+    /// Whether a plugin is handling the command for an actuator of a batch,
+    /// or will once its `start_time` comes.
+    fn is_handling(&self, batch: BatchId, actuator_id: &str) -> bool {
+        let is_for = |origin: &Origin| match origin {
+            Origin::Batch {
+                batch: b,
+                actuator_id: a,
+            } => *b == batch && a == actuator_id,
+            _ => false,
+        };
+        self.in_flight
+            .values()
+            .any(|in_flight| is_for(&in_flight.origin))
+            || self
+                .deferred
+                .iter()
+                .any(|deferred| is_for(&deferred.origin))
+    }
+
+    /// Whether the command of a step of the script is running, on plugins or as a batch.
+    fn is_running_step(&self, step: usize) -> bool {
+        let is_for = |origin: &Origin| matches!(origin, Origin::Script { step: s } if *s == step);
+        self.in_flight
+            .values()
+            .any(|in_flight| is_for(&in_flight.origin))
+            || self
+                .deferred
+                .iter()
+                .any(|deferred| is_for(&deferred.origin))
+            || self.batches.values().any(|pending| is_for(&pending.origin))
+    }
+
+    /// Whether a command from a transport is running, on plugins or as a batch.
+    fn is_running(&self, key: &CommandKey) -> bool {
+        let is_for = |origin: &Origin| match origin {
+            Origin::Remote { key: Some(k), .. } => k == key,
+            _ => false,
+        };
+        self.in_flight
+            .values()
+            .any(|in_flight| is_for(&in_flight.origin))
+            || self
+                .deferred
+                .iter()
+                .any(|deferred| is_for(&deferred.origin))
+            || self.batches.values().any(|pending| is_for(&pending.origin))
+    }
+
+    /// Records a command handed to a plugin in the journal, if it's enabled,
+    /// except the ones from the config file and its script, which run again anyway.
+    fn accept(
+        &mut self,
+        plugin_id: &PluginId,
+        command: &CommandView,
+        origin: &Origin,
+    ) -> Option<EntryId> {
+        let journal = self.journal.as_mut()?;
+        let reply_to = match origin {
+            Origin::Host | Origin::Script { .. } => return None,
+            Origin::Plugin(from) => Some(from.to_string()),
+            _ => None,
+        };
+        Some(journal.accept(plugin_id, origin.source(), reply_to, &command.raw))
+    }
+
+    /// Delivers the reply of a plugin to a command it was handling, and stops tracking it.
+    fn end_job(
+        &mut self,
+        in_flight: InFlight,
+        reply: CommandReply,
+        always: bool,
+    ) -> Result<(), AppError> {
+        self.correlations
+            .finish(&in_flight.command.request_id, &in_flight.plugin_id);
+        self.deliver_reply(
+            &in_flight.origin,
+            &in_flight.plugin_id,
+            &in_flight.command,
+            in_flight.entry,
+            reply,
+            always,
+        )
+    }
+
+    /// Sends the reply to a command to where the command came from,
+    /// and completes its journal `entry` with it.
+    ///
+    /// For commands that the application doesn't handle itself,
+    /// this sends the reply if the command asked for it, or for any response if `always`.
+    fn deliver_reply(
+        &mut self,
+        origin: &Origin,
+        plugin_id: &PluginId,
+        command: &CommandView,
+        entry: Option<EntryId>,
+        reply: CommandReply,
+        always: bool,
+    ) -> Result<(), AppError> {
+        let response = reply.into_response(&command.request_id, plugin_id)?;
+        if let (Some(journal), Some(entry)) = (&mut self.journal, entry) {
+            journal.complete(entry, Some(response.clone()));
+        }
+
+        let requested = match command.response_requested() {
+            _ if origin.is_internal() => true,
+            ResponseRequested::None => false,
+            ResponseRequested::Ack => always,
+            ResponseRequested::Status | ResponseRequested::Complete => true,
+        };
+        if !requested {
+            return Ok(());
+        }
+        self.respond(origin, plugin_id, response)
+    }
+
+    /// Forgets the commands that got no response for too long,
+    /// like the ones whose plugin was unloaded while handling them.
+    pub(super) fn expire_correlations(&mut self) {
+        for (request_id, correlation) in self.correlations.expire(Instant::now()) {
+            warn!(
+                "Forgetting request {} from {}, which {:?} did not answer",
+                request_id, correlation.source, correlation.plugins
+            );
+        }
+    }
+
+    /// Sends a response from `plugin_id` to where its command came from.
+    fn respond(
+        &mut self,
+        origin: &Origin,
+        plugin_id: &PluginId,
+        response: OpenC2Response,
+    ) -> Result<(), AppError> {
+        self.correlations.record(&response);
+        match origin {
+            Origin::Host => print_response(plugin_id, &encode_response(&response)?),
+            Origin::Plugin(from) => {
+                self.responses.push_back(RArc::new(PluginResponse {
+                    from: plugin_id.clone(),
+                    to: from.clone(),
+                    response: encode_response(&response)?,
+                }));
+            }
+            // The transport went away, nobody is waiting for the response anymore.
+            Origin::Remote { responses, key, .. } => {
+                if let Some(key) = key {
+                    let (processing, _) = OpenC2RespStatus::Processing.into();
+                    if response.get_status() != processing {
+                        self.recent.record(key, &response);
+                    }
+                }
+                let _ = responses.send(response);
+            }
+            Origin::Batch { batch, actuator_id } => {
+                if let Some(pending) = self.batches.get_mut(batch) {
+                    pending.batch.record(actuator_id, response);
+                }
+            }
+            Origin::Script { step } => {
+                if let Some(script) = &mut self.script {
+                    script.record(*step, response);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a response that the application answered a command with, instead of a plugin,
+    /// unless the command asked for no response.
+    fn respond_to_command(
+        &mut self,
+        origin: &Origin,
+        command: &OpenC2Command,
+        response: OpenC2Response,
+    ) -> Result<(), AppError> {
+        if command.get_response_requested() == Some(&ResponseRequested::None)
+            && !origin.is_internal()
+        {
+            return Ok(());
+        }
+        self.respond(origin, &PluginId::from(HOST_ID), response)
+    }
+
+    /// Sets the commands a plugin declared to support,
+    /// `None` meaning that it supports every command.
+    pub(crate) fn set_supported_commands(
+        &mut self,
+        plugin_id: &PluginId,
+        supported_commands: Option<RVec<CommandDescription>>,
+    ) {
+        match supported_commands {
+            Some(x) => self.supported_commands.insert(plugin_id.clone(), x),
+            None => self.supported_commands.remove(plugin_id),
+        };
+    }
+
+    /// Whether the plugin supports a command, as identified by `OpenC2Command::get_command_identity`.
+    pub fn supports(&self, plugin_id: &PluginId, action: &OpenC2Action, target: &str) -> bool {
+        self.supported_commands
+            .get(plugin_id)
+            .is_none_or(|supported| supported.iter().any(|desc| desc.matches(action, target)))
+    }
+
+    /// Adds the commands the plugin supports to an `UnsupportedCommand` error it returned.
+    fn fill_supported_commands(&self, plugin_id: &PluginId, mut error: AppError) -> AppError {
+        if let AppError::UnsupportedCommand(unsupported) = &mut error {
+            if let Some(supported) = self.supported_commands.get(plugin_id) {
+                unsupported.list_supported_commands(supported);
+            }
+        }
+        error
+    }
 }
 
-impl Application for ApplicationState {
-    fn send_command_to_plugin(&mut self, command: RArc<PluginCommand>) {
-        self.commands.push_back(command);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, command, handled};
+    use std::thread;
+
+    #[test]
+    fn test_timed_out_commands_do_not_hold_up_the_others() {
+        let config = testing::config(json!({
+            "routes": [
+                {"plugin": "timeout_slow", "actuator_id": ["slow-01"]},
+                {"plugin": "timeout_fast", "actuator_id": ["fast-01"]}
+            ]
+        }));
+        let (mut app, events, _) =
+            testing::application(PathBuf::new(), config, &["timeout_slow", "timeout_fast"]);
+
+        let started = Instant::now();
+        let mut slow = command("slow-1", &["slow-01"], 1000);
+        slow["command"]["args"]["stop_time"] = json!(now_millis() + 200);
+        let slow = testing::send(&mut app, slow);
+        let fast = testing::send(&mut app, command("fast-1", &["fast-01"], 0));
+        testing::run(&mut app, &events);
+
+        let statuses = |responses: &Receiver<OpenC2Response>| {
+            responses
+                .try_iter()
+                .map(|response| response.get_status())
+                .collect::<Vec<u16>>()
+        };
+        assert_eq!(statuses(&slow), [408]);
+        assert_eq!(statuses(&fast), [200]);
+        assert!(started.elapsed() < Duration::from_millis(1000));
+        let fast = handled("timeout_fast");
+        assert_eq!(fast.len(), 1);
+        assert_eq!(fast[0].request_id, "fast-1");
+        assert!(handled("timeout_slow").is_empty());
     }
 
-    fn sender(&self) -> RSender<PluginCommand> {
-        self.sender.clone()
+    #[test]
+    fn test_plugins_run_admin_commands_only_if_allowed() {
+        let config = testing::config(json!({
+            "admin": {"allowed_plugins": ["admin_allowed"]}
+        }));
+        let (mut app, _events, _) = testing::application(
+            PathBuf::new(),
+            config,
+            &["admin_allowed", "admin_other", "admin_target"],
+        );
+        let unload = |app: &mut TheApplication, from: &str| {
+            app.run_command_(RArc::new(PluginCommand {
+                from: from.into(),
+                to: HOST_ID.into(),
+                command: r#"{"op": "unload", "plugin": "admin_target"}"#.into(),
+            }))
+            .unwrap();
+            let response = app.state.responses.pop_back().unwrap();
+            assert_eq!(response.to, from);
+            serde_json::from_str::<OpenC2Response>(&response.response)
+                .unwrap()
+                .get_status()
+        };
+
+        assert_eq!(unload(&mut app, "admin_other"), 403);
+        assert!(app.plugins.contains_key("admin_target"));
+
+        assert_eq!(unload(&mut app, "admin_allowed"), 200);
+        assert!(!app.plugins.contains_key("admin_target"));
+    }
+
+    #[test]
+    fn test_batches_run_concurrently_across_plugins_only() {
+        let config = testing::config(json!({
+            "routes": [
+                {"plugin": "batch_a", "actuator_id": ["a-01", "a-02"]},
+                {"plugin": "batch_b", "actuator_id": ["b-01"]}
+            ]
+        }));
+        let (mut app, events, _) =
+            testing::application(PathBuf::new(), config, &["batch_a", "batch_b"]);
+
+        let responses = testing::send(&mut app, command("batch-1", &["a-01", "a-02", "b-01"], 200));
+        testing::run(&mut app, &events);
+
+        let responses = responses.try_iter().collect::<Vec<OpenC2Response>>();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].get_status(), 200);
+        assert_eq!(responses[0].get_results().len(), 3);
+
+        let a = handled("batch_a");
+        let b = handled("batch_b");
+        assert_eq!(a.len(), 2);
+        assert_eq!(b.len(), 1);
+        assert!(!a[0].overlaps(&a[1]));
+        assert!(a.iter().any(|a| a.overlaps(&b[0])));
+        assert_eq!(b[0].actuator_id, ["b-01"]);
+    }
+
+    #[test]
+    fn test_replaying_expired_commands_answers_them_with_a_timeout() {
+        let (mut app, events, _) = testing::application(
+            PathBuf::new(),
+            testing::config(json!({})),
+            &["replay_target", "replay_sender"],
+        );
+
+        let mut expired = command("replay-1", &["device-01"], 0);
+        expired["command"]["args"]["stop_time"] = json!(now_millis() - 1000);
+        app.replay(vec![Accepted {
+            id: 1,
+            plugin_id: "replay_target".into(),
+            source: "replay_sender".into(),
+            reply_to: Some("replay_sender".into()),
+            command: expired.to_string(),
+        }]);
+
+        assert_eq!(app.state.responses.len(), 1);
+        let response = app.state.responses.front().unwrap();
+        assert_eq!(response.to, "replay_sender");
+        let response = serde_json::from_str::<OpenC2Response>(&response.response).unwrap();
+        assert_eq!(response.get_status(), 408);
+        assert_eq!(app.state.next_job, 0);
+        assert!(app.state.in_flight.is_empty());
+
+        testing::run(&mut app, &events);
+        assert!(handled("replay_target").is_empty());
+    }
+
+    #[test]
+    fn test_shutdown_answers_deferred_commands_and_running_batches() {
+        let config = testing::config(json!({
+            "routes": [
+                {"plugin": "abandon_a", "actuator_id": ["a-01"]},
+                {"plugin": "abandon_b", "actuator_id": ["b-01"]}
+            ]
+        }));
+        let (mut app, events, _) =
+            testing::application(PathBuf::new(), config, &["abandon_a", "abandon_b"]);
+
+        let mut deferred = command("abandon-1", &["a-01"], 0);
+        deferred["command"]["args"]["start_time"] = json!(now_millis() + 60_000);
+        let deferred = testing::send(&mut app, deferred);
+        let batch = testing::send(&mut app, command("abandon-2", &["a-01", "b-01"], 500));
+        app.shutdown(&events, Duration::from_millis(50));
+
+        let deferred = deferred.try_iter().collect::<Vec<OpenC2Response>>();
+        assert_eq!(deferred.len(), 1);
+        assert_eq!(deferred[0].get_status(), 500);
+        let batch = batch.try_iter().collect::<Vec<OpenC2Response>>();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].get_status(), 500);
+        assert_eq!(batch[0].get_results().len(), 2);
+        assert!(app.state.deferred.is_empty());
+        assert!(app.state.batches.is_empty());
+    }
+
+    #[test]
+    fn test_commands_from_plugins_are_scheduled_like_the_others() {
+        let (mut app, _events, _) = testing::application(
+            PathBuf::new(),
+            testing::config(json!({})),
+            &["p2p_sender", "p2p_target"],
+        );
+        let send = |app: &mut TheApplication, command: String| {
+            app.run_command_(RArc::new(PluginCommand {
+                from: "p2p_sender".into(),
+                to: "p2p_target".into(),
+                command: command.into(),
+            }))
+            .unwrap();
+            app.state.responses.pop_front().map(|response| {
+                assert_eq!(response.to, "p2p_sender");
+                serde_json::from_str::<OpenC2Response>(&response.response)
+                    .unwrap()
+                    .get_status()
+            })
+        };
+
+        let mut expired = command("p2p-1", &["device-01"], 0);
+        expired["command"]["args"]["stop_time"] = json!(now_millis() - 1000);
+        assert_eq!(send(&mut app, expired.to_string()), Some(408));
+        assert_eq!(send(&mut app, "not a command".into()), Some(400));
+        assert!(app.state.in_flight.is_empty());
+
+        let mut deferred = command("p2p-2", &["device-01"], 0);
+        deferred["command"]["args"]["start_time"] = json!(now_millis() + 60_000);
+        assert_eq!(send(&mut app, deferred.to_string()), None);
+        assert_eq!(app.state.deferred.len(), 1);
+
+        let mut running = command("p2p-3", &["device-01"], 0);
+        running["command"]["args"]["stop_time"] = json!(now_millis() + 60_000);
+        assert_eq!(send(&mut app, running.to_string()), None);
+        let in_flight = app.state.in_flight.values().next().unwrap();
+        assert!(in_flight.deadline.is_some());
+    }
+
+    #[test]
+    fn test_panicking_plugins_are_answered_and_restarted() {
+        let config = testing::config(json!({
+            "restart": {"initial_backoff_ms": 50, "max_backoff_ms": 50},
+            "routes": [
+                {"plugin": "panic_a", "actuator_id": ["a-01"]},
+                {"plugin": "panic_b", "actuator_id": ["b-01"]}
+            ]
+        }));
+        let (mut app, events, _) =
+            testing::application(PathBuf::new(), config, &["panic_a", "panic_b"]);
+
+        let mut panicking = command("panic-1", &["a-01"], 0);
+        panicking["command"]["target"]["artifact"]["payload"]["panic"] = json!(true);
+        let panicking = testing::send(&mut app, panicking);
+        let other = testing::send(&mut app, command("panic-2", &["b-01"], 0));
+        testing::run(&mut app, &events);
+
+        let panicking = panicking.try_iter().collect::<Vec<OpenC2Response>>();
+        assert_eq!(panicking.len(), 1);
+        assert_eq!(panicking[0].get_status(), 500);
+        assert!(panicking[0]
+            .get_desc()
+            .contains("asked to panic by panic-1"));
+        let other = other.try_iter().collect::<Vec<OpenC2Response>>();
+        assert_eq!(other.len(), 1);
+        assert_eq!(other[0].get_status(), 200);
+
+        // Constructed again once the backoff elapsed, and handles the next commands.
+        let constructed = testing::constructed("panic_a");
+        assert_eq!(constructed.len(), 2);
+        assert!(constructed[1] - constructed[0] >= Duration::from_millis(50));
+        let again = testing::send(&mut app, command("panic-3", &["a-01"], 0));
+        testing::run(&mut app, &events);
+        assert_eq!(again.try_iter().next().unwrap().get_status(), 200);
+        assert_eq!(testing::constructed("panic_b").len(), 1);
+    }
+
+    #[test]
+    fn test_replacing_a_plugin_hands_its_queued_commands_over() {
+        let config = testing::config(json!({
+            "routes": [{"plugin": "handover", "actuator_id": ["h-01"]}]
+        }));
+        let (mut app, events, _) = testing::application(PathBuf::new(), config, &["handover"]);
+
+        let responses = ["handover-1", "handover-2", "handover-3"]
+            .iter()
+            .enumerate()
+            .map(|(i, request_id)| {
+                let delay_ms = if i == 0 { 300 } else { 0 };
+                testing::send(&mut app, command(request_id, &["h-01"], delay_ms))
+            })
+            .collect::<Vec<Receiver<OpenC2Response>>>();
+        // Lets the first command start on the plugin being replaced.
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let staged = plugin::stage(
+            "handover".into(),
+            PathBuf::new(),
+            testing::source(),
+            &app.state,
+        )
+        .unwrap();
+        plugin::add(&mut app.plugins, &mut app.state, staged, app.close_timeout);
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert_eq!(app.state.closing.len(), 1);
+
+        testing::run(&mut app, &events);
+        for responses in &responses {
+            assert_eq!(responses.try_iter().next().unwrap().get_status(), 200);
+        }
+        let mut handled = handled("handover")
+            .into_iter()
+            .map(|handled| (handled.request_id, handled.instance))
+            .collect::<Vec<(String, usize)>>();
+        handled.sort();
+        assert_eq!(
+            handled,
+            [
+                ("handover-1".to_string(), 0),
+                ("handover-2".to_string(), 1),
+                ("handover-3".to_string(), 1)
+            ]
+        );
+        assert!(app.state.closing.is_empty());
     }
 }
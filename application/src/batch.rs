@@ -0,0 +1,261 @@
+//! Splits a command addressed to several actuators into one command per actuator,
+//! and aggregates their outcomes into one response.
+
+use abi_stable::std_types::RBoxError;
+use common::{
+    openc2::{
+        command::OpenC2Command,
+        response::{BatchResultInner, OpenC2RespStatus, OpenC2Response},
+        OpenC2MsgType,
+    },
+    Error as AppError,
+};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+
+/// Identifies a batch among the ones that are running.
+pub type BatchId = u64;
+
+/// A command whose actuator has several `actuator_id`s,
+/// run for at most `max_concurrency` of them at a time.
+///
+/// Each plugin handles its commands one after the other,
+/// so only the actuators routed to different plugins are handled at the same time.
+pub struct Batch {
+    command: OpenC2Command,
+    max_concurrency: usize,
+    queued: VecDeque<String>,
+    running: Vec<String>,
+    /// The responses for every actuator id, in the order of the command.
+    outcomes: Vec<(String, Vec<OpenC2Response>)>,
+}
+
+impl Batch {
+    /// Returns `None` if the command is addressed to fewer than two actuators.
+    pub fn of(command: &OpenC2Command, max_concurrency: usize) -> Option<Self> {
+        let ids = command.get_actuator().as_ref()?.get_actuator_id();
+        if ids.len() < 2 {
+            return None;
+        }
+        let mut outcomes = Vec::<(String, Vec<OpenC2Response>)>::new();
+        for id in ids {
+            if !outcomes.iter().any(|(x, _)| x == id) {
+                outcomes.push((id.clone(), Vec::new()));
+            }
+        }
+        Some(Batch {
+            command: command.clone(),
+            max_concurrency: max_concurrency.max(1),
+            queued: outcomes.iter().map(|(id, _)| id.clone()).collect(),
+            running: Vec::new(),
+            outcomes,
+        })
+    }
+
+    /// The command that was split.
+    pub fn command(&self) -> &OpenC2Command {
+        &self.command
+    }
+
+    /// Returns the JSON encoded command for a single actuator id.
+    pub fn device_command(&self, actuator_id: &str) -> Result<String, AppError> {
+        let mut command = self.command.clone();
+        if let Some(actuator) = self.command.get_actuator() {
+            command.set_actuator(actuator_id.into(), actuator.get_actuator_type().clone());
+        }
+        command
+            .to_json_string()
+            .map_err(|e| AppError::Serialize(RBoxError::new(e), OpenC2MsgType::Request))
+    }
+
+    /// Marks the next queued actuator ids as running, as many as there's room for,
+    /// and returns them.
+    pub fn start_next(&mut self) -> Vec<String> {
+        let mut started = Vec::new();
+        while self.running.len() < self.max_concurrency {
+            match self.queued.pop_front() {
+                Some(id) => {
+                    self.running.push(id.clone());
+                    started.push(id);
+                }
+                None => break,
+            }
+        }
+        started
+    }
+
+    /// The actuator ids whose commands are running.
+    pub fn running(&self) -> &[String] {
+        &self.running
+    }
+
+    /// Adds a final response to the command for an actuator id.
+    pub fn record(&mut self, actuator_id: &str, response: OpenC2Response) {
+        if let Some((_, responses)) = self.outcomes.iter_mut().find(|(id, _)| id == actuator_id) {
+            responses.push(response);
+        }
+    }
+
+    /// Marks that the command for an actuator id won't get any more responses.
+    pub fn finish(&mut self, actuator_id: &str) {
+        self.running.retain(|id| id != actuator_id);
+    }
+
+    /// Records `response` for every actuator id that is queued or running,
+    /// which won't get any other response.
+    pub fn abandon(&mut self, response: OpenC2Response) {
+        let ids = self.queued.drain(..).chain(self.running.drain(..));
+        for id in ids.collect::<Vec<String>>() {
+            self.record(&id, response.clone());
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.queued.is_empty() && self.running.is_empty()
+    }
+
+    /// Whether every actuator answered with `200 Ok`.
+    pub fn is_ok(&self) -> bool {
+        self.outcomes
+            .iter()
+            .all(|(_, responses)| outcome_status(responses) == ok_status())
+    }
+
+    /// Aggregates the outcomes into one response from `sender`,
+    /// whose results have a `BatchResultInner` for every actuator id.
+    ///
+    /// The response has the status that every actuator answered with,
+    /// or `500 Internal Error` when they answered with different ones.
+    pub fn into_response(self, sender: &str) -> OpenC2Response {
+        let mut results = Vec::<Value>::new();
+        let mut statuses = Vec::<u16>::new();
+        for (actuator_id, responses) in &self.outcomes {
+            let status = outcome_status(responses);
+            statuses.push(status);
+            let desc = responses
+                .iter()
+                .filter(|response| response.get_status() == status)
+                .map(|response| response.get_desc().as_str())
+                .filter(|desc| !desc.is_empty())
+                .collect::<Vec<&str>>()
+                .join("; ");
+            let inner = BatchResultInner {
+                status_code: i32::from(status),
+                status_desc: desc,
+                result: Some(json!({
+                    "actuator_id": actuator_id,
+                    "results": responses
+                        .iter()
+                        .flat_map(|response| response.get_results().iter().cloned())
+                        .collect::<Vec<Value>>(),
+                })),
+            };
+            results.push(json!(inner));
+        }
+
+        let request_id = self.command.get_request_id().as_str();
+        let mut response = OpenC2Response::new(results, request_id, sender);
+        if !statuses.windows(2).all(|pair| pair[0] == pair[1]) {
+            let failed = statuses.iter().filter(|&&s| s != ok_status()).count();
+            let (status, status_text) = OpenC2RespStatus::InternalError.into();
+            let desc = format!("{} of {} actuators failed", failed, statuses.len());
+            response.set_status(status, &status_text, &desc);
+        } else if let Some(&status) = statuses.first().filter(|&&s| s != ok_status()) {
+            let status_text = self
+                .outcomes
+                .iter()
+                .flat_map(|(_, responses)| responses)
+                .find(|response| response.get_status() == status)
+                .map(|response| response.get_status_text().as_str())
+                .unwrap_or_default();
+            response.set_status(status, status_text, "");
+        }
+        response
+    }
+}
+
+fn ok_status() -> u16 {
+    let (status, _) = OpenC2RespStatus::OK.into();
+    status
+}
+
+/// The status of the responses to the command for one actuator:
+/// `200 Ok` if every plugin answered with it, the first other status otherwise.
+fn outcome_status(responses: &[OpenC2Response]) -> u16 {
+    responses
+        .iter()
+        .map(|response| response.get_status())
+        .find(|&status| status != ok_status())
+        .unwrap_or(ok_status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(actuator_id: &[&str]) -> OpenC2Command {
+        serde_json::from_value(json!({
+            "header": {
+                "request_id": "42",
+                "msg_type": "request",
+                "version": "1.0",
+                "created": 0,
+                "sender": "gateway"
+            },
+            "command": {
+                "action": "query",
+                "target": {"features": ["versions"]},
+                "actuator": {"actuator_type": "device", "actuator_id": actuator_id}
+            }
+        }))
+        .unwrap()
+    }
+
+    fn response(status: OpenC2RespStatus, sender: &str) -> OpenC2Response {
+        OpenC2Response::new_status(vec![json!({"from": sender})], "42", sender, status, "")
+    }
+
+    #[test]
+    fn test_batch_runs_at_most_max_concurrency() {
+        assert!(Batch::of(&command(&["fw-01"]), 2).is_none());
+
+        let mut batch = Batch::of(&command(&["fw-01", "fw-02", "fw-01", "fw-03"]), 2).unwrap();
+        let device = batch.device_command("fw-02").unwrap();
+        assert!(device.contains(r#""actuator_id":["fw-02"]"#), "{}", device);
+
+        assert_eq!(batch.start_next(), vec!["fw-01", "fw-02"]);
+        assert!(batch.start_next().is_empty());
+        batch.record("fw-01", response(OpenC2RespStatus::OK, "plugin_fw"));
+        batch.finish("fw-01");
+        assert_eq!(batch.start_next(), vec!["fw-03"]);
+        batch.finish("fw-02");
+        batch.finish("fw-03");
+        assert!(batch.is_done());
+    }
+
+    #[test]
+    fn test_batch_aggregates_outcomes() {
+        let mut batch = Batch::of(&command(&["fw-01", "fw-02"]), 8).unwrap();
+        batch.record("fw-01", response(OpenC2RespStatus::OK, "plugin_fw"));
+        batch.record("fw-02", response(OpenC2RespStatus::OK, "plugin_fw"));
+        batch.record(
+            "fw-02",
+            response(OpenC2RespStatus::Timeout, "plugin_server"),
+        );
+        assert!(!batch.is_ok());
+
+        let aggregated = batch.into_response("host");
+        assert_eq!(aggregated.get_status(), 500);
+        assert_eq!(aggregated.get_desc(), "1 of 2 actuators failed");
+        let results = aggregated.get_results();
+        assert_eq!(results[0]["status_code"], 200);
+        assert_eq!(results[0]["result"]["actuator_id"], "fw-01");
+        assert_eq!(results[1]["status_code"], 408);
+        assert_eq!(results[1]["result"]["results"].as_array().unwrap().len(), 2);
+
+        let mut batch = Batch::of(&command(&["fw-01", "fw-02"]), 8).unwrap();
+        batch.record("fw-01", response(OpenC2RespStatus::NotFound, "host"));
+        batch.record("fw-02", response(OpenC2RespStatus::NotFound, "host"));
+        assert_eq!(batch.into_response("host").get_status(), 404);
+    }
+}
@@ -0,0 +1,172 @@
+//! Matches the responses of plugins to the commands that caused them,
+//! by the `request_id` of the commands.
+
+use common::{
+    openc2::{response::OpenC2Response, TraceIdent},
+    PluginId,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// The commands that plugins are handling, keyed by their `request_id`.
+///
+/// Request ids are expected to be unique among the commands that are running,
+/// the commands of an id that is reused are tracked as one,
+/// like the commands that a batch runs for each of its actuators.
+pub struct Correlations {
+    pending: HashMap<String, Correlation>,
+    /// How long an entry is kept without any response, past the deadline of its command.
+    stale_after: Duration,
+}
+
+/// A command that some plugins have yet to give their final response to.
+#[derive(Debug)]
+pub struct Correlation {
+    /// Where the command came from, like `config`, `http` or `plugin "plugin_fw"`.
+    pub source: String,
+    /// The plugins that didn't give their final response yet,
+    /// once for every time the command was handed to them.
+    pub plugins: Vec<PluginId>,
+    /// When the command times out, if it has a timeout.
+    pub deadline: Option<Instant>,
+    /// The responses sent so far, including the final ones.
+    pub responses: Vec<OpenC2Response>,
+    /// When the entry is dropped if it's still pending.
+    expires_at: Instant,
+}
+
+impl Correlations {
+    pub fn new(stale_after: Duration) -> Self {
+        Self {
+            pending: HashMap::new(),
+            stale_after,
+        }
+    }
+
+    /// Tracks a command that was handed to a plugin.
+    pub fn start(
+        &mut self,
+        request_id: &str,
+        plugin_id: &PluginId,
+        source: String,
+        deadline: Option<Instant>,
+    ) {
+        let now = Instant::now();
+        let expires_at = now.max(deadline.unwrap_or(now)) + self.stale_after;
+        let correlation = self
+            .pending
+            .entry(request_id.to_string())
+            .or_insert_with(|| Correlation {
+                source,
+                plugins: Vec::new(),
+                deadline,
+                responses: Vec::new(),
+                expires_at,
+            });
+        correlation.plugins.push(plugin_id.clone());
+        correlation.deadline = correlation.deadline.max(deadline);
+        correlation.expires_at = correlation.expires_at.max(expires_at);
+    }
+
+    /// Adds a response to the command it answers, if that command is tracked.
+    pub fn record(&mut self, response: &OpenC2Response) {
+        if let Some(correlation) = self.pending.get_mut(response.trace_id()) {
+            correlation.responses.push(response.clone());
+            correlation.expires_at = correlation
+                .expires_at
+                .max(Instant::now() + self.stale_after);
+        }
+    }
+
+    /// Marks that a plugin gave its final response to a command,
+    /// returning the entry of the command once every plugin did.
+    pub fn finish(&mut self, request_id: &str, plugin_id: &PluginId) -> Option<Correlation> {
+        let correlation = self.pending.get_mut(request_id)?;
+        if let Some(i) = correlation.plugins.iter().position(|x| x == plugin_id) {
+            correlation.plugins.remove(i);
+        }
+        if correlation.plugins.is_empty() {
+            self.pending.remove(request_id)
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, request_id: &str) -> Option<&Correlation> {
+        self.pending.get(request_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Removes the entries that got no response for too long, returning them with their request id.
+    pub fn expire(&mut self, now: Instant) -> Vec<(String, Correlation)> {
+        let stale = self
+            .pending
+            .iter()
+            .filter(|(_, correlation)| correlation.expires_at <= now)
+            .map(|(request_id, _)| request_id.clone())
+            .collect::<Vec<String>>();
+        stale
+            .into_iter()
+            .filter_map(|request_id| {
+                let correlation = self.pending.remove(&request_id)?;
+                Some((request_id, correlation))
+            })
+            .collect()
+    }
+
+    /// Returns how long until the next entry expires.
+    pub fn until_next_expiry(&self) -> Option<Duration> {
+        self.pending
+            .values()
+            .map(|correlation| {
+                correlation
+                    .expires_at
+                    .saturating_duration_since(Instant::now())
+            })
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_correlation_lifecycle() {
+        let mut correlations = Correlations::new(Duration::from_secs(60));
+        let fw = PluginId::from("plugin_fw");
+        let server = PluginId::from("plugin_server");
+        correlations.start("42", &fw, "http".into(), None);
+        correlations.start("42", &server, "http".into(), None);
+        // The same plugin handles the command for another actuator.
+        correlations.start("42", &fw, "http".into(), None);
+
+        correlations.record(&OpenC2Response::new(vec![], "42", "plugin_fw"));
+        correlations.record(&OpenC2Response::new(vec![], "unknown", "plugin_fw"));
+        assert_eq!(correlations.get("42").unwrap().responses.len(), 1);
+
+        assert!(correlations.finish("42", &fw).is_none());
+        assert!(correlations.finish("42", &fw).is_none());
+        let done = correlations.finish("42", &server).unwrap();
+        assert_eq!(done.source, "http");
+        assert!(done.plugins.is_empty());
+        assert!(correlations.is_empty());
+
+        let now = Instant::now();
+        correlations.start("43", &fw, "config".into(), None);
+        assert!(correlations.expire(now).is_empty());
+        let expired = correlations.expire(now + Duration::from_secs(61));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, "43");
+        assert!(correlations.is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+//! Remembers the commands that came in over transports for a while,
+//! so a command that's sent again, like when a client retries, doesn't run twice.
+
+use common::openc2::response::OpenC2Response;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Identifies a command, from `OpenC2Command::tuple_actuator_msg_id`:
+/// its `request_id` and its actuator ids joined with commas.
+pub type CommandKey = (String, String);
+
+/// The commands seen within `window`, and the final responses to them.
+pub struct RecentCommands {
+    window: Duration,
+    seen: HashMap<CommandKey, Recent>,
+}
+
+struct Recent {
+    seen_at: Instant,
+    responses: Vec<OpenC2Response>,
+}
+
+impl RecentCommands {
+    /// Remembers commands for `window`, or not at all if it's zero.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// Returns the final responses to a command seen within the window,
+    /// or remembers it and returns `None` if it wasn't.
+    pub fn check(&mut self, key: &CommandKey, now: Instant) -> Option<&[OpenC2Response]> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let window = self.window;
+        self.seen
+            .retain(|_, recent| now.saturating_duration_since(recent.seen_at) < window);
+        if self.seen.contains_key(key) {
+            return self.seen.get(key).map(|recent| &recent.responses[..]);
+        }
+        self.seen.insert(
+            key.clone(),
+            Recent {
+                seen_at: now,
+                responses: Vec::new(),
+            },
+        );
+        None
+    }
+
+    /// Adds a final response to a command that's remembered.
+    pub fn record(&mut self, key: &CommandKey, response: &OpenC2Response) {
+        if let Some(recent) = self.seen.get_mut(key) {
+            recent.responses.push(response.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_within_window() {
+        let key = ("42".to_string(), "fw-01,fw-02".to_string());
+        let now = Instant::now();
+        let mut recent = RecentCommands::new(Duration::from_secs(60));
+        assert!(recent.check(&key, now).is_none());
+        recent.record(&key, &OpenC2Response::new(vec![], "42", "plugin_fw"));
+        assert_eq!(
+            recent
+                .check(&key, now + Duration::from_secs(1))
+                .unwrap()
+                .len(),
+            1
+        );
+        // The window passed, so the command runs again.
+        assert!(recent.check(&key, now + Duration::from_secs(61)).is_none());
+
+        let mut disabled = RecentCommands::new(Duration::ZERO);
+        assert!(disabled.check(&key, now).is_none());
+        assert!(disabled.check(&key, now).is_none());
+    }
+}
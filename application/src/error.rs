@@ -20,6 +20,7 @@ pub enum RErrorKind {
     FileInvalid,
 
     InputInvalid,
+    ScriptFailed,
     UnKnowError,
 }
 
@@ -38,6 +39,10 @@ lazy_static! {
             RErrorKind::InputInvalid,
             (1002, "input not found, 输入无效".to_string()),
         );
+        map.insert(
+            RErrorKind::ScriptFailed,
+            (1003, "script failed, 脚本失败".to_string()),
+        );
         map.insert(
             RErrorKind::UnKnowError,
             (9999, "unknow, 未知错误".to_string()),
@@ -62,6 +67,12 @@ impl RError {
             }
         };
     }
+
+    /// Prefixes the message with what the error is about, like a file name.
+    pub fn context(mut self, context: &str) -> RError {
+        self.error_message = format!("{}: {}", context, self.error_message);
+        self
+    }
 }
 
 impl From<anyhow::Error> for RError {
@@ -85,6 +96,18 @@ impl From<SerdeError> for RError {
     }
 }
 
+impl From<toml::de::Error> for RError {
+    fn from(error: toml::de::Error) -> Self {
+        Self::new(RErrorKind::FileInvalid, error.to_string())
+    }
+}
+
+impl From<serde_yaml::Error> for RError {
+    fn from(error: serde_yaml::Error) -> Self {
+        Self::new(RErrorKind::FileInvalid, error.to_string())
+    }
+}
+
 impl fmt::Display for RError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.write_str(self.error_message.as_str())
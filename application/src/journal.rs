@@ -0,0 +1,223 @@
+//! An append-only log of the commands handed to plugins,
+//! so the ones that were not completed when the process stopped can be run again.
+//!
+//! Every line is a JSON encoded `Record`: a command is `accepted` once it's deferred or handed
+//! to a plugin, `started` once its plugin gets it, and `completed` with its final response.
+
+use crate::warn;
+use common::openc2::response::OpenC2Response;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+/// Identifies a command in the journal.
+pub type EntryId = u64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record {
+    Accepted(Accepted),
+    Started {
+        id: EntryId,
+    },
+    Completed {
+        id: EntryId,
+        #[serde(default)]
+        response: Option<OpenC2Response>,
+    },
+}
+
+/// A command handed to a plugin.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Accepted {
+    pub id: EntryId,
+    pub plugin_id: String,
+    /// Where the command came from, like `http`.
+    pub source: String,
+    /// The plugin that sent the command, which gets the reply.
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// The JSON encoded command.
+    pub command: String,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    next_id: EntryId,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, returning the commands that were not completed.
+    ///
+    /// The journal is rewritten with only these commands, so it doesn't grow forever.
+    /// Lines that can't be read, like one that was being written when the process stopped,
+    /// are skipped.
+    pub fn open(path: &Path) -> io::Result<(Journal, Vec<Accepted>)> {
+        let mut pending = BTreeMap::<EntryId, Accepted>::new();
+        let mut next_id = 0;
+        match File::open(path) {
+            Ok(file) => {
+                for (n, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<Record>(&line) {
+                        Ok(Record::Accepted(accepted)) => {
+                            next_id = next_id.max(accepted.id + 1);
+                            pending.insert(accepted.id, accepted);
+                        }
+                        Ok(Record::Started { .. }) => {}
+                        Ok(Record::Completed { id, .. }) => {
+                            pending.remove(&id);
+                        }
+                        Err(e) => warn!(
+                            "Skipping line {} of {}, because of this error: {}",
+                            n + 1,
+                            path.display(),
+                            e
+                        ),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        // Next to the journal, whatever its extension is.
+        let mut compacted = path.as_os_str().to_owned();
+        compacted.push(".compact");
+        let compacted = PathBuf::from(compacted);
+        {
+            let mut file = File::create(&compacted)?;
+            for accepted in pending.values() {
+                write_record(&mut file, &Record::Accepted(accepted.clone()))?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&compacted, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        let journal = Journal {
+            path: path.to_path_buf(),
+            file,
+            next_id,
+        };
+        Ok((journal, pending.into_values().collect()))
+    }
+
+    /// Records a command handed to a plugin, returning its id.
+    pub fn accept(
+        &mut self,
+        plugin_id: &str,
+        source: String,
+        reply_to: Option<String>,
+        command: &str,
+    ) -> EntryId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.append(&Record::Accepted(Accepted {
+            id,
+            plugin_id: plugin_id.into(),
+            source,
+            reply_to,
+            command: command.into(),
+        }));
+        id
+    }
+
+    pub fn start(&mut self, id: EntryId) {
+        self.append(&Record::Started { id });
+    }
+
+    pub fn complete(&mut self, id: EntryId, response: Option<OpenC2Response>) {
+        self.append(&Record::Completed { id, response });
+    }
+
+    /// Writes a record, and makes sure it's on disk.
+    ///
+    /// The command still runs if it can't be recorded, it just won't be run again.
+    fn append(&mut self, record: &Record) {
+        let res = write_record(&mut self.file, record).and_then(|_| self.file.sync_data());
+        if let Err(e) = res {
+            eprintln!(
+                "Could not write to {}, because of this error: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn write_record(file: &mut File, record: &Record) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_returns_commands_that_were_not_completed() {
+        let path = std::env::temp_dir().join(format!("journal-test-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let (mut journal, pending) = Journal::open(&path).unwrap();
+        assert!(pending.is_empty());
+        let done = journal.accept("plugin_fw", "http".into(), None, "{}");
+        journal.start(done);
+        journal.complete(done, Some(OpenC2Response::new(vec![], "1", "plugin_fw")));
+        let running = journal.accept(
+            "plugin_fw",
+            "unix".into(),
+            Some("plugin_server".into()),
+            "{}",
+        );
+        journal.start(running);
+        drop(journal);
+        // A record that was being written when the process stopped.
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"record\": \"compl")
+            .unwrap();
+
+        let (mut journal, pending) = Journal::open(&path).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, running);
+        assert_eq!(pending[0].reply_to.as_deref(), Some("plugin_server"));
+        assert!(journal.accept("plugin_fw", "http".into(), None, "{}") > running);
+        journal.complete(running, None);
+        drop(journal);
+
+        let (_, pending) = Journal::open(&path).unwrap();
+        assert_eq!(pending.len(), 1);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_open_leaves_the_files_next_to_the_journal_alone() {
+        let path = std::env::temp_dir().join(format!("journal-keep-{}.log", std::process::id()));
+        let neighbour = path.with_extension("tmp");
+        let _ = fs::remove_file(&path);
+        fs::write(&neighbour, "keep").unwrap();
+
+        let (mut journal, _) = Journal::open(&path).unwrap();
+        journal.accept("plugin_fw", "http".into(), None, "{}");
+        drop(journal);
+        let (_, pending) = Journal::open(&path).unwrap();
+
+        assert_eq!(pending.len(), 1);
+        assert_eq!(fs::read_to_string(&neighbour).unwrap(), "keep");
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&neighbour);
+    }
+}
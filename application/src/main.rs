@@ -1,44 +1,196 @@
 use app::{ApplicationState, TheApplication};
+use clap::Parser;
+use correlation::Correlations;
+use dedup::RecentCommands;
+use error::{RError, RErrorKind, RResult};
+use journal::Journal;
+use plugin::{watcher::LibraryWatcher, worker::Backoff};
+use reload::LoadedConfig;
+use router::Router;
+use runtime::RunMode;
+use script::Script;
 use shadow_rs::shadow;
-use std::{collections::HashMap, io};
-use utils::config::CONFIG;
+use std::{
+    collections::HashMap,
+    process,
+    time::{Duration, Instant},
+};
+use utils::{cli::Opts, config, log};
 
+pub mod admin;
 pub mod app;
+pub mod batch;
+pub mod correlation;
+pub mod dedup;
 pub mod error;
+pub mod journal;
 pub mod plugin;
+pub mod reload;
+pub mod router;
+pub mod runtime;
+pub mod schedule;
+pub mod script;
+#[cfg(test)]
+mod testing;
+pub mod transport;
 pub mod utils;
+pub mod validate;
 
 shadow!(build);
 
-fn main() -> io::Result<()> {
+/// How long `--oneshot` waits for plugins to send more commands before exiting.
+const ONESHOT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn main() {
+    let opts = Opts::parse();
+    log::set_level(opts.log_level);
+    if let Err(e) = run(opts) {
+        eprintln!("[{}] {}\n{}", e.status_code, e.status_text, e);
+        process::exit(1);
+    }
+}
+
+fn run(opts: Opts) -> RResult<()> {
+    let config = config::load(&opts.config)?;
+    if opts.validate {
+        let plugin_dirs = plugin::search_dirs(&opts.plugin_dirs, &config.plugin_dirs);
+        return validate::run(&opts.config, &config, &plugin_dirs);
+    }
+
+    let (event_sender, events) = crossbeam_channel::unbounded();
+    runtime::forward_term_signals(event_sender.clone())?;
+    runtime::forward_reload_signals(event_sender.clone())?;
+
     let mut plugins = HashMap::new();
-    let mut state = ApplicationState::new();
+    let restart_backoff = Backoff {
+        initial: Duration::from_millis(config.restart.initial_backoff_ms),
+        max: Duration::from_millis(config.restart.max_backoff_ms),
+    };
+    let correlations = Correlations::new(Duration::from_millis(config.correlation.stale_after_ms));
+    let (journal, pending) = match config.journal.enabled {
+        true => {
+            let (journal, pending) = Journal::open(&config.journal.path)?;
+            (Some(journal), pending)
+        }
+        false => (None, Vec::new()),
+    };
+    let mut state = ApplicationState::new(
+        event_sender.clone(),
+        restart_backoff,
+        correlations,
+        journal,
+        RecentCommands::new(Duration::from_millis(config.dedup.window_ms)),
+        (!config.script.is_empty()).then(|| Script::new(config.script.clone(), Instant::now())),
+    );
 
-    let loaded_libraries = plugin::check(&CONFIG.plugins, &mut state);
+    let plugin_dirs = plugin::search_dirs(&opts.plugin_dirs, &config.plugin_dirs);
+    let loaded_libraries = plugin::check(&config.plugins, &plugin_dirs, &mut state);
     plugin::load(&mut plugins, &mut state, loaded_libraries);
+    runtime::forward_plugin_commands(state.receiver.clone(), event_sender.clone())?;
 
-    let mut app = TheApplication { plugins, state };
-
-    let mut config_commands = CONFIG.commands.vec.clone().into_iter();
-    while !app.is_finished() {
-        if let Some((plugin_id, command)) = config_commands.next() {
-            let command = command.get();
-            if let Err(e) = app.run_command(&plugin_id, command.into()) {
-                eprintln!(
-                    "Error while running command on:\n{:?}\nError:{}\nCommand:\n{:?}\n",
-                    plugin_id, e, command
-                );
-            }
-        }
+    if config.http.enabled {
+        let addr = transport::http::serve(
+            &config.http.listen,
+            event_sender.clone(),
+            Duration::from_millis(config.http.response_timeout_ms),
+            config.http.max_requests,
+        )?;
+        info!(
+            "listening on http://{}{}",
+            addr,
+            transport::http::OPENC2_PATH
+        );
+    }
+
+    #[cfg(unix)]
+    if config.unix_socket.enabled {
+        transport::unix::serve(
+            &config.unix_socket.path,
+            event_sender.clone(),
+            config.unix_socket.max_in_flight,
+        )?;
+        info!("listening on {}", config.unix_socket.path.display());
+    }
+
+    #[cfg(feature = "mqtt")]
+    if config.mqtt.enabled {
+        transport::mqtt::serve(&config.mqtt, event_sender)?;
+        info!(
+            "subscribing to {:?} on mqtt://{}:{}",
+            config.mqtt.command_topics, config.mqtt.host, config.mqtt.port
+        );
+    }
+    #[cfg(not(feature = "mqtt"))]
+    if config.mqtt.enabled {
+        warn!("mqtt is enabled in the config file, but was not built, see the `mqtt` feature");
+    }
+
+    let watcher = config.watch.enabled.then(|| {
+        LibraryWatcher::new(
+            Duration::from_millis(config.watch.poll_interval_ms),
+            &state.library_paths,
+        )
+    });
+
+    let mut app = TheApplication {
+        plugins,
+        state,
+        watcher,
+        router: Router::new(config.routes.clone()),
+        close_timeout: Duration::from_millis(config.shutdown.close_timeout_ms),
+        batch_concurrency: config.batch.max_concurrency,
+        config: LoadedConfig::new(
+            opts.config.clone(),
+            opts.plugin_dirs.clone(),
+            config.clone(),
+        ),
+    };
 
-        if let Err(e) = app.tick() {
-            eprintln!("Error in application loop:\n{}\n", e);
+    app.replay(pending);
+
+    for (plugin_id, command) in &config.commands.vec {
+        let command = command.get();
+        if let Err(e) = app.run_command(plugin_id, command.into()) {
+            eprintln!(
+                "Error while running command on:\n{:?}\nError:{}\nCommand:\n{:?}\n",
+                plugin_id, e, command
+            );
         }
     }
 
-    if app.is_finished() {
-        println!("timeout waiting for events");
+    for command in &config.dispatch {
+        let command = command.get();
+        if let Err(e) = app.dispatch(command.into()) {
+            eprintln!(
+                "Error while dispatching command:\n{:?}\nError:{}\n",
+                command, e
+            );
+        }
     }
 
-    Ok(())
+    let mode = if opts.oneshot {
+        RunMode::OneShot {
+            idle_timeout: ONESHOT_IDLE_TIMEOUT,
+        }
+    } else {
+        RunMode::Service
+    };
+    app.run(&events, mode);
+    app.shutdown(
+        &events,
+        Duration::from_millis(app.config.current.shutdown.drain_timeout_ms),
+    );
+
+    match &app.state.script {
+        Some(script) if !script.is_done() || script.failed() > 0 => Err(RError::new(
+            RErrorKind::ScriptFailed,
+            format!(
+                "{} of {} steps failed, {} did not run",
+                script.failed(),
+                script.len(),
+                script.len() - script.next()
+            ),
+        )),
+        _ => Ok(()),
+    }
 }
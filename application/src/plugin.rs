@@ -1,17 +1,31 @@
 use abi_stable::{
-    library::{lib_header_from_path, LibraryError, LibrarySuffix, RawLibrary},
-    std_types::{RErr, ROk, RVec},
+    external_types::crossbeam_channel::RSender,
+    library::{lib_header_from_path, LibraryError},
+    sabi_trait::TD_Opaque,
+    std_types::{RBoxError, RVec},
+};
+use common::{
+    baseline, panics::CatchPanics, CommandDescription, Error as AppError, PluginCommand,
+    PluginFactory_Ref, PluginId, PluginType, Plugin_TO,
 };
-use common::{Error as AppError, PluginFactory_Ref, PluginId, PluginType};
-use core_extensions::SelfOps;
 use serde::Deserialize;
 use std::{
     collections::HashMap,
-    io,
+    env::consts::{DLL_PREFIX, DLL_SUFFIX},
+    fs, io,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
+use self::process::ProcessSpec;
+use self::worker::PluginWorker;
 use crate::app::ApplicationState;
+use crate::info;
+use crate::utils::cli;
+
+pub mod process;
+pub mod watcher;
+pub mod worker;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
@@ -23,59 +37,290 @@ pub enum PluginToLoad {
         #[serde(alias = "renamed")]
         rename: Option<String>,
     },
+    /// A plugin that runs as a separate process, see `process::ProcessPlugin`.
+    Process {
+        /// The executable, a bare file name is searched in the plugin directories.
+        process: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(alias = "renamed")]
+        rename: Option<String>,
+        /// How long the process gets to answer each message, in milliseconds.
+        #[serde(default = "default_process_timeout_ms")]
+        timeout_ms: u64,
+    },
+}
+
+fn default_process_timeout_ms() -> u64 {
+    30_000
 }
 
-/// Returns the path the plugin will be loaded from.
-fn compute_plugin_path(base_name: &str) -> io::Result<PathBuf> {
-    let debug_dir = "../target/debug/".as_ref_::<Path>().into_::<PathBuf>();
-    let release_dir = "../target/release/".as_ref_::<Path>().into_::<PathBuf>();
-
-    let debug_path = RawLibrary::path_in_directory(&debug_dir, base_name, LibrarySuffix::NoSuffix);
-    let release_path =
-        RawLibrary::path_in_directory(&release_dir, base_name, LibrarySuffix::NoSuffix);
-
-    match (debug_path.exists(), release_path.exists()) {
-        (false, false) => debug_path,
-        (true, false) => debug_path,
-        (false, true) => release_path,
-        (true, true) => {
-            if debug_path.metadata()?.modified()? < release_path.metadata()?.modified()? {
-                release_path
-            } else {
-                debug_path
+impl PluginToLoad {
+    /// Returns the id the plugin is loaded as.
+    pub fn plugin_id(&self) -> PluginId {
+        match self {
+            PluginToLoad::Named(named) => PluginId::from(named.as_str()),
+            PluginToLoad::WithRename { named, rename } => {
+                PluginId::from(rename.as_ref().unwrap_or(named).as_str())
             }
+            PluginToLoad::Process {
+                process, rename, ..
+            } => match rename {
+                Some(rename) => PluginId::from(rename.as_str()),
+                None => PluginId::from(
+                    process
+                        .file_stem()
+                        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned()),
+                ),
+            },
         }
     }
-    .piped(Ok)
+
+    /// Whether both are constructed from the same library,
+    /// or from the same executable with the same arguments and timeout,
+    /// whatever id they're loaded as.
+    pub fn same_source(&self, other: &PluginToLoad) -> bool {
+        match (self, other) {
+            (
+                PluginToLoad::Named(a) | PluginToLoad::WithRename { named: a, .. },
+                PluginToLoad::Named(b) | PluginToLoad::WithRename { named: b, .. },
+            ) => a == b,
+            (
+                PluginToLoad::Process {
+                    process: a,
+                    args: a_args,
+                    timeout_ms: a_timeout_ms,
+                    ..
+                },
+                PluginToLoad::Process {
+                    process: b,
+                    args: b_args,
+                    timeout_ms: b_timeout_ms,
+                    ..
+                },
+            ) => a == b && a_args == b_args && a_timeout_ms == b_timeout_ms,
+            _ => false,
+        }
+    }
+}
+
+/// What a plugin is constructed from.
+#[derive(Clone)]
+pub enum PluginSource {
+    /// A library exporting a `PluginFactory_Ref` root module.
+    Library(PluginFactory_Ref),
+    /// An executable following the protocol in `common::ipc`.
+    Process(ProcessSpec),
 }
 
-pub fn check(plugins: &RVec<PluginToLoad>, state: &mut ApplicationState) -> Vec<PluginId> {
-    let mut nonexistent_files = Vec::<(String, io::Error)>::new();
-    let mut library_errs = Vec::<(String, LibraryError)>::new();
+impl PluginSource {
+    pub fn construct(
+        &self,
+        sender: RSender<PluginCommand>,
+        plugin_id: PluginId,
+    ) -> Result<PluginType, AppError> {
+        match self {
+            PluginSource::Library(root_module) => {
+                root_module.new()(sender, plugin_id).into_result()
+            }
+            PluginSource::Process(spec) => spec
+                .spawn(sender, plugin_id)
+                .map(|plugin| Plugin_TO::from_value(CatchPanics(plugin), TD_Opaque)),
+        }
+    }
+
+    /// Returns the commands that the plugin supports,
+    /// or `None` if it doesn't declare them.
+    pub fn supported_commands(&self) -> Option<RVec<CommandDescription>> {
+        match self {
+            PluginSource::Library(root_module) => supported_commands(*root_module),
+            PluginSource::Process(_) => None,
+        }
+    }
+}
+
+/// A plugin library found while scanning the plugin search directories.
+#[derive(Clone)]
+pub struct DiscoveredLibrary {
+    pub path: PathBuf,
+    pub root_module: PluginFactory_Ref,
+}
+
+/// The result of scanning the plugin search directories.
+#[derive(Default)]
+pub struct Discovery {
+    /// The libraries exporting a `PluginFactory_Ref`, keyed by their base name.
+    pub libraries: HashMap<String, DiscoveredLibrary>,
+    /// Directories that could not be read.
+    pub dir_errs: Vec<(PathBuf, io::Error)>,
+    /// Libraries that export a root module but could not be loaded.
+    pub library_errs: Vec<(String, PathBuf, LibraryError)>,
+}
+
+/// Returns the directories plugins are searched in, by decreasing priority.
+///
+/// Directories passed on the command line come first,
+/// then the ones in the `PLUGIN_PATH` environment variable,
+/// then the ones from the config file.
+/// If none was given, plugins are searched next to the executable,
+/// which is where cargo puts the plugins of this workspace.
+pub fn search_dirs(cli_dirs: &[PathBuf], config_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs = Vec::<PathBuf>::new();
+    for dir in cli_dirs
+        .iter()
+        .cloned()
+        .chain(cli::env_plugin_dirs())
+        .chain(config_dirs.iter().cloned())
+    {
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+
+    if dirs.is_empty() {
+        if let Some(exe_dir) = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        {
+            dirs.push(exe_dir);
+        }
+    }
+    dirs
+}
+
+/// Returns the base name of a `lib<base_name>.so` file,
+/// using the naming convention of the current platform.
+fn library_base_name(path: &Path) -> Option<&str> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix(DLL_PREFIX)?
+        .strip_suffix(DLL_SUFFIX)
+        .filter(|name| !name.is_empty())
+}
+
+/// Scans `dirs` for every dynamic library that exports a `PluginFactory_Ref` root module.
+///
+/// If a library with the same base name is in more than one directory,
+/// the one in the earliest directory is used and the others are not loaded.
+pub fn discover(dirs: &[PathBuf]) -> Discovery {
+    let mut discovery = Discovery::default();
+
+    for dir in dirs {
+        let mut paths = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file())
+                .collect::<Vec<PathBuf>>(),
+            Err(e) => {
+                discovery.dir_errs.push((dir.clone(), e));
+                continue;
+            }
+        };
+        paths.sort();
+
+        for path in paths {
+            let base_name = match library_base_name(&path) {
+                Some(x) => x.to_string(),
+                None => continue,
+            };
+            if discovery.libraries.contains_key(&base_name) {
+                continue;
+            }
+
+            let res = (|| {
+                let header = lib_header_from_path(&path)?;
+                baseline::init_root_module(&header)
+            })();
+
+            match res {
+                Ok(root_module) => {
+                    discovery
+                        .libraries
+                        .insert(base_name, DiscoveredLibrary { path, root_module });
+                }
+                // Not a plugin, just some other library that lives in the same directory.
+                Err(LibraryError::GetSymbolError { .. }) => {}
+                Err(e) => discovery.library_errs.push((base_name, path, e)),
+            }
+        }
+    }
+
+    discovery
+}
+
+/// Returns the path of a plugin executable,
+/// a bare file name is searched in `dirs` like the libraries.
+pub fn find_executable(process: &Path, dirs: &[PathBuf]) -> Option<PathBuf> {
+    if process
+        .parent()
+        .is_some_and(|parent| !parent.as_os_str().is_empty())
+    {
+        return Some(process.to_path_buf()).filter(|path| path.is_file());
+    }
+    dirs.iter()
+        .map(|dir| dir.join(process))
+        .find(|path| path.is_file())
+}
+
+pub fn check(
+    plugins: &RVec<PluginToLoad>,
+    dirs: &[PathBuf],
+    state: &mut ApplicationState,
+) -> Vec<PluginId> {
+    let mut discovery = discover(dirs);
+    let mut nonexistent_libraries = Vec::<String>::new();
+    let mut nonexistent_executables = Vec::<PathBuf>::new();
     let mut loaded_libraries = Vec::<PluginId>::new();
 
     for plug in plugins {
         let (named, rename) = match plug {
             PluginToLoad::Named(named) => ((*named).clone(), None),
             PluginToLoad::WithRename { named, rename } => ((*named).clone(), rename.clone()),
-        };
-        let library_path: PathBuf = match compute_plugin_path(named.as_ref()) {
-            Ok(x) => x,
-            Err(e) => {
-                nonexistent_files.push((named.clone(), e));
+            PluginToLoad::Process {
+                process,
+                args,
+                rename,
+                timeout_ms,
+            } => {
+                let path = match find_executable(process, dirs) {
+                    Some(x) => x,
+                    None => {
+                        nonexistent_executables.push(process.clone());
+                        continue;
+                    }
+                };
+                let name_key = rename.clone().unwrap_or_else(|| {
+                    path.file_stem()
+                        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned())
+                });
+                let plugin_id = PluginId::from(name_key);
+
+                info!("resolved {:?} from {}", plugin_id, path.display());
+                loaded_libraries.push(plugin_id.clone());
+                state.library_paths.insert(plugin_id.clone(), path.clone());
+                state.set_supported_commands(&plugin_id, None);
+                state.id_map.insert(
+                    plugin_id,
+                    PluginSource::Process(ProcessSpec {
+                        path,
+                        args: args.clone(),
+                        timeout: Duration::from_millis(*timeout_ms),
+                    }),
+                );
                 continue;
             }
         };
 
-        let res = (|| {
-            let header = lib_header_from_path(&library_path)?;
-            header.init_root_module::<PluginFactory_Ref>()
-        })();
-
-        let root_module = match res {
-            Ok(x) => x,
-            Err(e) => {
-                library_errs.push((named.clone(), e));
+        let library = match discovery.libraries.get(&named) {
+            Some(x) => x.clone(),
+            None => {
+                if !discovery
+                    .library_errs
+                    .iter()
+                    .any(|(base_name, ..)| *base_name == named)
+                {
+                    nonexistent_libraries.push(named);
+                }
                 continue;
             }
         };
@@ -84,59 +329,353 @@ pub fn check(plugins: &RVec<PluginToLoad>, state: &mut ApplicationState) -> Vec<
 
         let plugin_id = PluginId::from(name_key);
 
+        info!("resolved {:?} from {}", plugin_id, library.path.display());
         loaded_libraries.push(plugin_id.clone());
-        state.id_map.insert(plugin_id, root_module);
+        state.library_paths.insert(plugin_id.clone(), library.path);
+        state.set_supported_commands(&plugin_id, supported_commands(library.root_module));
+        state
+            .id_map
+            .insert(plugin_id, PluginSource::Library(library.root_module));
     }
 
-    if !nonexistent_files.is_empty() {
-        for (name, e) in nonexistent_files {
-            eprintln!(
-                "Could not load librarr: {}, because of this error: {}",
-                name, e
-            )
-        }
+    for (dir, e) in discovery.dir_errs.drain(..) {
+        eprintln!(
+            "Could not read plugin directory: {}, because of this error: {}",
+            dir.display(),
+            e
+        )
+    }
+
+    for (name, path, e) in discovery.library_errs.drain(..) {
+        eprintln!(
+            "Could not load library: {} ({}), because of this error: {}",
+            name,
+            path.display(),
+            e
+        )
     }
 
-    if !library_errs.is_empty() {
-        for (name, e) in library_errs {
-            eprintln!(
-                "Could not load librarr: {}, because of this error: {}",
-                name, e
-            )
+    if !nonexistent_libraries.is_empty() {
+        let dirs = dirs
+            .iter()
+            .map(|dir| dir.display().to_string())
+            .collect::<Vec<String>>();
+        for name in nonexistent_libraries {
+            eprintln!("Could not find library: {}, searched in: {:?}", name, dirs)
         }
     }
+
+    for process in nonexistent_executables {
+        eprintln!(
+            "Could not find executable: {}, searched in: {:?}",
+            process.display(),
+            dirs
+        )
+    }
     loaded_libraries
 }
 
+/// Constructs the plugins on their worker thread.
+///
+/// A plugin that fails to construct is constructed again with a backoff until it succeeds,
+/// while the other plugins run.
 pub fn load(
-    plugins: &mut HashMap<PluginId, PluginType>,
+    plugins: &mut HashMap<PluginId, PluginWorker>,
     state: &mut ApplicationState,
     loaded_libraries: Vec<PluginId>,
 ) {
-    let mut plugin_new_errs = Vec::<(PluginId, AppError)>::new();
     for plugin_id in loaded_libraries {
-        let mod_ref = state.id_map.get_mut(&plugin_id).unwrap();
-        let plugin_constructor = mod_ref.new();
+        let source = state.id_map[&plugin_id].clone();
 
-        let plugin = match plugin_constructor(state.sender.clone(), plugin_id.clone()) {
-            ROk(x) => x,
-            RErr(e) => {
-                plugin_new_errs.push((plugin_id.clone(), e));
-                continue;
+        let worker = match spawn_worker(source.clone(), &plugin_id, state) {
+            Ok(x) => {
+                info!("load {:?} success", plugin_id);
+                x
+            }
+            Err(e) => {
+                eprintln!(
+                    "Could not instantiate plugin: {:?}, retrying in {:?}, because of this error: {}",
+                    plugin_id,
+                    state.restart_backoff.delay(1),
+                    e
+                );
+                match PluginWorker::spawn_failed(
+                    source,
+                    plugin_id.clone(),
+                    state.sender.clone(),
+                    state.events.clone(),
+                    state.restart_backoff,
+                ) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        eprintln!(
+                            "Could not start plugin: {:?}, because of this error: {}",
+                            plugin_id, e
+                        );
+                        continue;
+                    }
+                }
             }
         };
 
-        plugins.insert(plugin_id.clone(), plugin);
-        println!("load {:?} success", plugin_id);
+        plugins.insert(plugin_id.clone(), worker);
+        state.load_order.push(plugin_id.clone());
+    }
+}
+
+/// A plugin that's constructed but not added to the running ones yet.
+pub struct Staged {
+    pub plugin_id: PluginId,
+    pub path: PathBuf,
+    pub source: PluginSource,
+    pub worker: PluginWorker,
+}
+
+/// Returns the path of the library of the plugin `named` in the earliest of `dirs` that has it,
+/// without loading it.
+pub fn find_library(named: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    let file_name = format!("{}{}{}", DLL_PREFIX, named, DLL_SUFFIX);
+    dirs.iter()
+        .map(|dir| dir.join(&file_name))
+        .find(|path| path.is_file())
+}
+
+/// Finds what a plugin is constructed from in `dirs`,
+/// returning the path of its library or executable.
+///
+/// Only the library of this plugin is loaded, from a copy named after the next
+/// `library_version` like `reload` does, so a library rebuilt since it was first loaded isn't stale.
+pub fn resolve(
+    plug: &PluginToLoad,
+    dirs: &[PathBuf],
+    library_version: &mut u64,
+) -> Result<(PathBuf, PluginSource), AppError> {
+    match plug {
+        PluginToLoad::Named(named) | PluginToLoad::WithRename { named, .. } => {
+            let path = find_library(named, dirs).ok_or_else(|| {
+                AppError::Custom(RBoxError::from_fmt(&format!(
+                    "could not find library {}, searched in {:?}",
+                    named, dirs
+                )))
+            })?;
+            *library_version += 1;
+            let root_module = load_library_copy(&path, *library_version)?;
+            Ok((path, PluginSource::Library(root_module)))
+        }
+        PluginToLoad::Process {
+            process,
+            args,
+            timeout_ms,
+            ..
+        } => {
+            let path = find_executable(process, dirs).ok_or_else(|| {
+                AppError::Custom(RBoxError::from_fmt(&format!(
+                    "could not find executable {}, searched in {:?}",
+                    process.display(),
+                    dirs
+                )))
+            })?;
+            let spec = ProcessSpec {
+                path: path.clone(),
+                args: args.clone(),
+                timeout: Duration::from_millis(*timeout_ms),
+            };
+            Ok((path, PluginSource::Process(spec)))
+        }
+    }
+}
+
+/// Constructs a plugin without touching the plugins that are running.
+pub fn stage(
+    plugin_id: PluginId,
+    path: PathBuf,
+    source: PluginSource,
+    state: &ApplicationState,
+) -> Result<Staged, AppError> {
+    let worker = spawn_worker(source.clone(), &plugin_id, state)?;
+    Ok(Staged {
+        plugin_id,
+        path,
+        source,
+        worker,
+    })
+}
+
+/// Adds a staged plugin to the running ones,
+/// in place of the plugin with the same id if there's one.
+///
+/// The jobs that the replaced plugin didn't start yet are handed to the new one,
+/// then the replaced plugin is closed without waiting for it, see `ApplicationState::close_later`.
+pub fn add(
+    plugins: &mut HashMap<PluginId, PluginWorker>,
+    state: &mut ApplicationState,
+    staged: Staged,
+    close_timeout: Duration,
+) {
+    let plugin_id = staged.plugin_id;
+    if let Some(old_worker) = plugins.remove(&plugin_id) {
+        old_worker.hand_over(&staged.worker);
+        state.close_later(old_worker, close_timeout);
     }
+    plugins.insert(plugin_id.clone(), staged.worker);
+    state.library_paths.insert(plugin_id.clone(), staged.path);
+    state.set_supported_commands(&plugin_id, staged.source.supported_commands());
+    state.id_map.insert(plugin_id.clone(), staged.source);
+    if !state.load_order.contains(&plugin_id) {
+        state.load_order.push(plugin_id);
+    }
+}
+
+/// Constructs a plugin on its own worker thread.
+fn spawn_worker(
+    source: PluginSource,
+    plugin_id: &PluginId,
+    state: &ApplicationState,
+) -> Result<PluginWorker, AppError> {
+    PluginWorker::spawn(
+        source,
+        plugin_id.clone(),
+        state.sender.clone(),
+        state.events.clone(),
+        state.restart_backoff,
+    )
+}
+
+/// Returns the commands that the plugins of a library support,
+/// or `None` if the library was built before plugins could declare them.
+pub fn supported_commands(root_module: PluginFactory_Ref) -> Option<RVec<CommandDescription>> {
+    root_module
+        .supported_commands()
+        .map(|supported_commands| supported_commands())
+}
+
+/// Loads the root module of the library at `path` from a copy of it named after `version`.
+///
+/// The dynamic loader hands back the already loaded library when the same path is loaded twice,
+/// and the root module of a library is only initialized once,
+/// so loading a rebuilt library from its original path would return the stale plugin.
+pub fn load_library_copy(path: &Path, version: u64) -> Result<PluginFactory_Ref, AppError> {
+    let base_name = library_base_name(path).unwrap_or("plugin");
+    let copy_dir = std::env::temp_dir().join("plugin_reload");
+    let copy_path = copy_dir.join(format!(
+        "{}{}.{}.{}{}",
+        DLL_PREFIX,
+        base_name,
+        std::process::id(),
+        version,
+        DLL_SUFFIX
+    ));
+
+    fs::create_dir_all(&copy_dir)
+        .and_then(|_| fs::copy(path, &copy_path))
+        .map_err(|e| AppError::Custom(RBoxError::new(e)))?;
+
+    let res = (|| {
+        let header = lib_header_from_path(&copy_path)?;
+        baseline::init_root_module(&header)
+    })();
+
+    // The library stays mapped after its file is removed,
+    // libraries are never unloaded so the copies would otherwise pile up.
+    let _ = fs::remove_file(&copy_path);
+
+    res.map_err(|e| AppError::Custom(RBoxError::from_fmt(&e)))
+}
+
+/// Replaces a plugin with a new instance constructed from the current version of its library,
+/// or of its executable for plugins running as a separate process.
+///
+/// The new plugin is constructed before the old one is closed,
+/// so a library that fails to load or a plugin that fails to construct leaves the old one running.
+pub fn reload(
+    plugins: &mut HashMap<PluginId, PluginWorker>,
+    state: &mut ApplicationState,
+    plugin_id: &PluginId,
+    close_timeout: Duration,
+) -> Result<(), AppError> {
+    let path = state
+        .library_paths
+        .get(plugin_id)
+        .cloned()
+        .ok_or_else(|| AppError::invalid_plugin_id(plugin_id.clone()))?;
+
+    let source = match state.id_map.get(plugin_id) {
+        // The executable is started again from its path, which picks up the new version.
+        Some(source @ PluginSource::Process(_)) => source.clone(),
+        _ => {
+            state.library_version += 1;
+            PluginSource::Library(load_library_copy(&path, state.library_version)?)
+        }
+    };
+
+    let staged = stage(plugin_id.clone(), path.clone(), source, state)?;
+    add(plugins, state, staged, close_timeout);
+    info!("reload {:?} from {} success", plugin_id, path.display());
+    Ok(())
+}
 
-    if !plugin_new_errs.is_empty() {
-        for (plugin_id, e) in plugin_new_errs {
-            println!(
-                "Could not instantiate plugin: {:?}, because of this error: {}",
-                plugin_id, e
-            )
+/// Closes a plugin, reporting it if it doesn't close within `timeout`.
+///
+/// A plugin that doesn't close in time can't be interrupted,
+/// so when `exit_on_timeout` is true the process exits instead of waiting for it.
+pub fn close(worker: PluginWorker, timeout: Duration, exit_on_timeout: bool) {
+    let plugin_id = worker.plugin_id().clone();
+    if !worker.close(timeout) {
+        eprintln!("Plugin {:?} did not close within {:?}", plugin_id, timeout);
+        if exit_on_timeout {
+            std::process::exit(1);
         }
-        std::process::exit(1);
+    }
+}
+
+/// Closes a plugin without waiting for it and forgets about its library,
+/// the commands queued for it are still handled before it closes,
+/// the ones sent to it after fail with `Error::InvalidPlugin`.
+pub fn unload(
+    plugins: &mut HashMap<PluginId, PluginWorker>,
+    state: &mut ApplicationState,
+    plugin_id: &PluginId,
+    close_timeout: Duration,
+) -> Result<(), AppError> {
+    let plugin = plugins
+        .remove(plugin_id)
+        .ok_or_else(|| AppError::invalid_plugin_id(plugin_id.clone()))?;
+
+    state.close_later(plugin, close_timeout);
+
+    state.id_map.remove(plugin_id);
+    state.library_paths.remove(plugin_id);
+    state.set_supported_commands(plugin_id, None);
+    state.load_order.retain(|loaded| loaded != plugin_id);
+    info!("unload {:?} success", plugin_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_library_base_name() {
+        let path = PathBuf::from(format!(
+            "/opt/plugins/{}plugin_fw{}",
+            DLL_PREFIX, DLL_SUFFIX
+        ));
+        assert_eq!(library_base_name(&path), Some("plugin_fw"));
+
+        let path = PathBuf::from(format!("/opt/plugins/{}{}", DLL_PREFIX, DLL_SUFFIX));
+        assert_eq!(library_base_name(&path), None);
+
+        assert_eq!(
+            library_base_name(Path::new("/opt/plugins/plugin_fw.txt")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_discover_skips_unreadable_dirs() {
+        let discovery = discover(&[PathBuf::from("/nonexistent/plugin/dir")]);
+        assert!(discovery.libraries.is_empty());
+        assert_eq!(discovery.dir_errs.len(), 1);
     }
 }
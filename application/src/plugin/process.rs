@@ -0,0 +1,485 @@
+//! Plugins that run as a separate process,
+//! talking to the application over their stdin and stdout with the protocol in `common::ipc`.
+//!
+//! A process plugin implements `Plugin` like the plugins loaded from libraries,
+//! so the rest of the application handles both the same way.
+
+use abi_stable::{
+    external_types::crossbeam_channel::RSender,
+    std_types::{RArc, RBoxError, RErr, RNone, ROk, ROption, RResult, RSome, RStr, RString},
+};
+use common::{
+    ipc::{CommandResult, HostMessage, PluginMessage, PluginResponseMessage},
+    openc2::{response::CommandReply, view::CommandView, OpenC2MsgType},
+    panics, ApplicationMut, Error as AppError, Plugin, PluginCommand, PluginId, PluginResponse,
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use serde_json::Value;
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How to start a plugin process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessSpec {
+    pub path: PathBuf,
+    pub args: Vec<String>,
+    /// How long the process gets to answer each message.
+    pub timeout: Duration,
+}
+
+impl ProcessSpec {
+    /// Starts the plugin process and waits for it to answer `HostMessage::New`,
+    /// then checks that it answers `HostMessage::PluginId` with the id it was given.
+    pub fn spawn(
+        &self,
+        sender: RSender<PluginCommand>,
+        plugin_id: PluginId,
+    ) -> Result<ProcessPlugin, AppError> {
+        let mut child = Command::new(&self.path)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                AppError::Custom(RBoxError::from_fmt(&format_args!(
+                    "Could not start {}: {}",
+                    self.path.display(),
+                    e
+                )))
+            })?;
+
+        let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+            (Some(stdin), Some(stdout)) => (stdin, stdout),
+            _ => unreachable!("stdin and stdout are piped"),
+        };
+        let connection = Connection::new(BufReader::new(stdout), stdin).map_err(|e| {
+            let _ = child.kill();
+            let _ = child.wait();
+            AppError::Custom(RBoxError::new(e))
+        })?;
+        let mut plugin = ProcessPlugin {
+            plugin_id,
+            sender,
+            child,
+            connection,
+            timeout: self.timeout,
+        };
+        let plugin_id = plugin.plugin_id.to_string();
+        plugin.call(|id| HostMessage::New { id, plugin_id }, None)?;
+        let answered = plugin.call(|id| HostMessage::PluginId { id }, None)?;
+        if answered.as_str() != Some(plugin.plugin_id.as_str()) {
+            return Err(AppError::Custom(RBoxError::from_fmt(&format_args!(
+                "{} answered with the plugin id {}, instead of {:?}",
+                self.path.display(),
+                answered,
+                plugin.plugin_id
+            ))));
+        }
+        Ok(plugin)
+    }
+}
+
+/// One end of the protocol in `common::ipc`.
+struct Connection<W> {
+    /// The lines that the other end sent,
+    /// read on a thread of their own so that waiting for them can time out.
+    lines: Receiver<io::Result<String>>,
+    writer: W,
+    next_id: u64,
+}
+
+impl<W: Write> Connection<W> {
+    fn new<R: BufRead + Send + 'static>(reader: R, writer: W) -> io::Result<Self> {
+        let (sender, lines) = crossbeam_channel::unbounded();
+        thread::Builder::new()
+            .name("plugin-process-reader".into())
+            .spawn(move || {
+                for line in reader.lines() {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Self {
+            lines,
+            writer,
+            next_id: 0,
+        })
+    }
+
+    /// Sends a message and waits at most `timeout` for the answer to it,
+    /// passing the other messages received in the meantime to `notify`.
+    ///
+    /// Returns an io error if the other end went away, doesn't follow the protocol,
+    /// or doesn't answer in time, and the error it answered with otherwise.
+    fn call(
+        &mut self,
+        message: impl FnOnce(u64) -> HostMessage,
+        timeout: Duration,
+        mut notify: impl FnMut(PluginMessage),
+    ) -> io::Result<Result<Value, String>> {
+        self.next_id += 1;
+        let id = self.next_id;
+
+        let mut line = serde_json::to_string(&message(id))?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.flush()?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let line = match self.lines.recv_deadline(deadline) {
+                Ok(line) => line?,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("it did not answer within {:?}", timeout),
+                    ))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::ErrorKind::UnexpectedEof.into())
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<PluginMessage>(&line)? {
+                PluginMessage::Ok {
+                    id: answered,
+                    value,
+                } if answered == id => return Ok(Ok(value)),
+                PluginMessage::Err {
+                    id: answered,
+                    error,
+                } if answered == id => return Ok(Err(error)),
+                // The answer to a message that was given up on.
+                PluginMessage::Ok { .. } | PluginMessage::Err { .. } => {}
+                notification => notify(notification),
+            }
+        }
+    }
+}
+
+/// A plugin running as a separate process.
+///
+/// The process is killed once it answered `HostMessage::Close` or didn't in time,
+/// or when it stops following the protocol or doesn't answer a message in time,
+/// which is reported as a panic so that the plugin is restarted.
+pub struct ProcessPlugin {
+    plugin_id: PluginId,
+    sender: RSender<PluginCommand>,
+    child: Child,
+    connection: Connection<ChildStdin>,
+    timeout: Duration,
+}
+
+impl ProcessPlugin {
+    /// Sends a message to the process and waits for the answer,
+    /// forwarding what the process sends in the meantime to `app`.
+    fn call(
+        &mut self,
+        message: impl FnOnce(u64) -> HostMessage,
+        mut app: Option<ApplicationMut<'_>>,
+    ) -> Result<Value, AppError> {
+        let ProcessPlugin {
+            plugin_id,
+            sender,
+            connection,
+            timeout,
+            ..
+        } = self;
+
+        let res = connection.call(message, *timeout, |notification| match notification {
+            PluginMessage::ReportStatus { request_id, reply } => {
+                if let Some(app) = &mut app {
+                    app.report_status(request_id.as_str().into(), reply.into());
+                }
+            }
+            PluginMessage::SendCommandToPlugin { to, command } => {
+                let command = PluginCommand {
+                    from: plugin_id.clone(),
+                    to: to.into(),
+                    command: command.into(),
+                };
+                match &mut app {
+                    Some(app) => app.send_command_to_plugin(RArc::new(command)),
+                    None => {
+                        let _ = sender.send(command);
+                    }
+                }
+            }
+            PluginMessage::Ok { .. } | PluginMessage::Err { .. } => {}
+        });
+
+        match res {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(error)) => Err(AppError::Custom(RBoxError::from_fmt(&error))),
+            Err(e) => Err(panics::panicked(
+                plugin_id,
+                &format!("its process stopped responding: {}", e),
+            )),
+        }
+    }
+
+    fn call_command(
+        &mut self,
+        command: RStr<'_>,
+        app: ApplicationMut<'_>,
+    ) -> Result<CommandReply, AppError> {
+        let command = command.to_string();
+        let value = self.call(|id| HostMessage::SendCommand { id, command }, Some(app))?;
+        serde_json::from_value::<CommandResult>(value)
+            .map(CommandReply::from)
+            .map_err(|e| AppError::Deserialize(RBoxError::new(e), OpenC2MsgType::Response))
+    }
+}
+
+impl Plugin for ProcessPlugin {
+    fn send_command(
+        &mut self,
+        command: RStr<'_>,
+        app: ApplicationMut<'_>,
+    ) -> RResult<RString, AppError> {
+        CommandView::parse(command.as_str())
+            .and_then(|view| {
+                self.call_command(command, app)?
+                    .into_response_json(&view.request_id, &self.plugin_id)
+            })
+            .into()
+    }
+
+    fn handle_response(
+        &mut self,
+        response: RArc<PluginResponse>,
+        app: ApplicationMut<'_>,
+    ) -> RResult<ROption<RArc<PluginResponse>>, AppError> {
+        let response = PluginResponseMessage {
+            from: response.from.to_string(),
+            to: response.to.to_string(),
+            response: response.response.to_string(),
+        };
+        let value = match self.call(|id| HostMessage::HandleResponse { id, response }, Some(app)) {
+            Ok(x) => x,
+            Err(e) => return RErr(e),
+        };
+        if value.is_null() {
+            return ROk(RNone);
+        }
+        match serde_json::from_value::<PluginResponseMessage>(value) {
+            Ok(response) => ROk(RSome(RArc::new(PluginResponse {
+                from: response.from.into(),
+                to: response.to.into(),
+                response: response.response.into(),
+            }))),
+            Err(e) => RErr(AppError::Deserialize(
+                RBoxError::new(e),
+                OpenC2MsgType::Response,
+            )),
+        }
+    }
+
+    fn plugin_id(&self) -> &PluginId {
+        &self.plugin_id
+    }
+
+    fn close(mut self, app: ApplicationMut<'_>) {
+        let _ = self.call(|id| HostMessage::Close { id }, Some(app));
+    }
+
+    fn handle_command(
+        &mut self,
+        command: &CommandView,
+        app: ApplicationMut<'_>,
+    ) -> RResult<CommandReply, AppError> {
+        self.call_command(command.raw.as_rstr(), app).into()
+    }
+}
+
+impl Drop for ProcessPlugin {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use abi_stable::sabi_trait::TD_Opaque;
+    use common::{Application, Application_TO};
+    use serde_json::json;
+    use std::{io::Cursor, os::unix::net::UnixStream, path::Path};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct NoApplication;
+
+    impl Application for NoApplication {
+        fn send_command_to_plugin(&mut self, _command: RArc<PluginCommand>) {}
+
+        fn sender(&self) -> RSender<PluginCommand> {
+            abi_stable::external_types::crossbeam_channel::unbounded().0
+        }
+    }
+
+    /// A process that answers `HostMessage::New` and `HostMessage::PluginId` with `plugin_id`,
+    /// then runs `rest`.
+    fn script(plugin_id: &str, rest: &str, timeout: Duration) -> ProcessSpec {
+        ProcessSpec {
+            path: "/bin/sh".into(),
+            args: vec![
+                "-c".into(),
+                format!(
+                    r#"read line; echo '{{"op": "ok", "id": 1, "value": null}}'; read line; echo '{{"op": "ok", "id": 2, "value": "{}"}}'; {}"#,
+                    plugin_id, rest
+                ),
+            ],
+            timeout,
+        }
+    }
+
+    /// A process that answers the messages that start it and nothing after them.
+    fn hanging(timeout: Duration) -> ProcessSpec {
+        script("hanging", "exec sleep 10", timeout)
+    }
+
+    fn is_running(pid: u32) -> bool {
+        Path::new(&format!("/proc/{}", pid)).exists()
+    }
+
+    #[test]
+    fn test_connection_waits_for_its_answer() {
+        let lines = [
+            json!({"op": "ok", "id": 7, "value": "stale"}),
+            json!({"op": "send_command_to_plugin", "to": "plugin_fw", "command": "{}"}),
+            json!({"op": "ok", "id": 1, "value": "done"}),
+            json!({"op": "err", "id": 2, "error": "unknown command"}),
+        ]
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect::<String>();
+
+        let mut written = Vec::<u8>::new();
+        let mut notifications = Vec::<PluginMessage>::new();
+        let mut connection = Connection::new(Cursor::new(lines), &mut written).unwrap();
+
+        let answer = connection.call(
+            |id| HostMessage::SendCommand {
+                id,
+                command: "{}".into(),
+            },
+            TIMEOUT,
+            |notification| notifications.push(notification),
+        );
+        assert_eq!(answer.unwrap(), Ok(json!("done")));
+
+        let answer = connection.call(|id| HostMessage::Close { id }, TIMEOUT, |_| {});
+        assert_eq!(answer.unwrap(), Err("unknown command".to_string()));
+
+        let answer = connection.call(|id| HostMessage::Close { id }, TIMEOUT, |_| {});
+        assert_eq!(answer.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        assert_eq!(
+            notifications,
+            vec![PluginMessage::SendCommandToPlugin {
+                to: "plugin_fw".into(),
+                command: "{}".into()
+            }]
+        );
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.starts_with(r#"{"op":"send_command","id":1,"command":"{}"}"#));
+    }
+
+    #[test]
+    fn test_connection_times_out() {
+        let (_other_end, end) = UnixStream::pair().unwrap();
+        let mut connection = Connection::new(BufReader::new(end), Vec::<u8>::new()).unwrap();
+
+        let started = Instant::now();
+        let answer = connection.call(
+            |id| HostMessage::Close { id },
+            Duration::from_millis(50),
+            |_| {},
+        );
+        assert_eq!(answer.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < TIMEOUT);
+    }
+
+    #[test]
+    fn test_hanging_process_is_killed() {
+        let mut app = NoApplication;
+        let timeout = Duration::from_millis(200);
+        let (sender, _) = abi_stable::external_types::crossbeam_channel::unbounded();
+
+        let mut plugin = hanging(timeout)
+            .spawn(sender.clone(), "hanging".into())
+            .unwrap();
+        let pid = plugin.child.id();
+        let command =
+            CommandView::parse(&testing::command("1", &["sw-01"], 0).to_string()).unwrap();
+        match plugin.handle_command(&command, Application_TO::from_ptr(&mut app, TD_Opaque)) {
+            RErr(e) => assert!(panics::is_panic(&e), "{}", e),
+            ROk(reply) => panic!("expected a timeout, got {:?}", reply),
+        }
+        drop(plugin);
+        assert!(!is_running(pid));
+
+        let plugin = hanging(timeout).spawn(sender, "hanging".into()).unwrap();
+        let pid = plugin.child.id();
+        let started = Instant::now();
+        plugin.close(Application_TO::from_ptr(&mut app, TD_Opaque));
+        assert!(started.elapsed() < TIMEOUT);
+        assert!(!is_running(pid));
+    }
+
+    #[test]
+    fn test_process_answering_another_plugin_id_is_not_started() {
+        let (sender, _) = abi_stable::external_types::crossbeam_channel::unbounded();
+        let e = script("impostor", "exec sleep 10", TIMEOUT)
+            .spawn(sender, "process_id".into())
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("impostor"), "{}", e);
+    }
+
+    #[test]
+    fn test_process_answers_through_the_application() {
+        let config = testing::config(json!({
+            "routes": [{"plugin": "process_switch", "actuator_id": ["sw-01"]}]
+        }));
+        let (mut app, events, _) = testing::application(PathBuf::new(), config, &[]);
+        // Answers the command, then `close`, like `plugin_switch` does,
+        // which has tests of its own that run it.
+        let spec = script(
+            "process_switch",
+            r#"read line; echo '{"op": "ok", "id": 3, "value": {"status": 200, "status_text": "Ok", "results": [{"mac": "00:1a:2b:3c:4d:5e"}]}}'; read line; echo '{"op": "ok", "id": 4, "value": null}'"#,
+            TIMEOUT,
+        );
+        let staged = crate::plugin::stage(
+            "process_switch".into(),
+            spec.path.clone(),
+            crate::plugin::PluginSource::Process(spec),
+            &app.state,
+        )
+        .unwrap();
+        crate::plugin::add(&mut app.plugins, &mut app.state, staged, TIMEOUT);
+
+        let mut command = testing::command("sw-1", &["sw-01"], 0);
+        command["command"]["target"] =
+            json!({"artifact": {"mime_type": "device/mac", "payload": {}}});
+        let responses = testing::send(&mut app, command);
+        testing::run(&mut app, &events);
+
+        let responses = responses.try_iter().collect::<Vec<_>>();
+        assert_eq!(responses.len(), 1, "{:?}", responses);
+        assert_eq!(responses[0].get_status(), 200);
+        assert_eq!(responses[0].get_sender(), "process_switch");
+        assert_eq!(responses[0].get_results()[0]["mac"], "00:1a:2b:3c:4d:5e");
+    }
+}
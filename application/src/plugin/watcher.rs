@@ -0,0 +1,111 @@
+use common::PluginId;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Polls the libraries plugins were loaded from, to reload the ones that were rebuilt.
+///
+/// A library is only reported once its modification time stays the same for a whole poll,
+/// so that a library is not loaded while it's still being written.
+pub struct LibraryWatcher {
+    interval: Duration,
+    last_poll: Instant,
+    /// When each library was last modified, as of the last time it was loaded.
+    loaded: HashMap<PluginId, SystemTime>,
+    /// Libraries that were modified, with their modification time in the previous poll.
+    pending: HashMap<PluginId, SystemTime>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl LibraryWatcher {
+    pub fn new(interval: Duration, library_paths: &HashMap<PluginId, PathBuf>) -> Self {
+        let loaded = library_paths
+            .iter()
+            .filter_map(|(plugin_id, path)| Some((plugin_id.clone(), modified(path)?)))
+            .collect();
+
+        Self {
+            interval,
+            last_poll: Instant::now(),
+            loaded,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Returns how long until the libraries should be polled again.
+    pub fn until_next_poll(&self) -> Duration {
+        self.interval.saturating_sub(self.last_poll.elapsed())
+    }
+
+    /// Returns the plugins whose library changed and is done changing,
+    /// at most once every `interval`.
+    pub fn poll(&mut self, library_paths: &HashMap<PluginId, PathBuf>) -> Vec<PluginId> {
+        if self.last_poll.elapsed() < self.interval {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+
+        let mut changed = Vec::new();
+        for (plugin_id, path) in library_paths {
+            // The library can be missing for a moment while it's replaced.
+            let modified = match modified(path) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            match self.loaded.get(plugin_id) {
+                Some(loaded) if *loaded == modified => {
+                    self.pending.remove(plugin_id);
+                }
+                Some(_) => {
+                    if self.pending.get(plugin_id) == Some(&modified) {
+                        self.pending.remove(plugin_id);
+                        self.loaded.insert(plugin_id.clone(), modified);
+                        changed.push(plugin_id.clone());
+                    } else {
+                        self.pending.insert(plugin_id.clone(), modified);
+                    }
+                }
+                None => {
+                    self.loaded.insert(plugin_id.clone(), modified);
+                }
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, time::UNIX_EPOCH};
+
+    #[test]
+    fn test_poll_waits_for_library_to_settle() {
+        let path = std::env::temp_dir().join(format!("watcher_test_{}.so", std::process::id()));
+        let file = File::create(&path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(1_000))
+            .unwrap();
+
+        let plugin_id = PluginId::from("plugin_fw");
+        let mut library_paths = HashMap::new();
+        library_paths.insert(plugin_id.clone(), path.clone());
+
+        let mut watcher = LibraryWatcher::new(Duration::from_secs(0), &library_paths);
+        assert!(watcher.poll(&library_paths).is_empty());
+
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(2_000))
+            .unwrap();
+        assert!(watcher.poll(&library_paths).is_empty());
+        assert_eq!(watcher.poll(&library_paths), vec![plugin_id]);
+        assert!(watcher.poll(&library_paths).is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
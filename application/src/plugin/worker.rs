@@ -0,0 +1,452 @@
+use abi_stable::{
+    external_types::crossbeam_channel::RSender,
+    sabi_trait::TD_Opaque,
+    std_types::{RArc, RBoxError, ROption, RStr},
+};
+use common::{
+    openc2::{response::CommandReply, view::CommandView},
+    panics, Application, Application_TO, Error as AppError, PluginCommand, PluginId,
+    PluginResponse, PluginType,
+};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use std::{
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::PluginSource;
+use crate::runtime::Event;
+
+/// Identifies a command that was handed to a plugin, to match its reply.
+pub type JobId = u64;
+
+/// Something for a plugin to do on its worker thread.
+pub enum Job {
+    Command {
+        job: JobId,
+        command: CommandView,
+    },
+    Response(RArc<PluginResponse>),
+    /// Closes the plugin, then signals that it's closed.
+    Close(mpsc::Sender<()>),
+}
+
+/// What a plugin did on its worker thread, sent to the event loop.
+pub enum WorkerEvent {
+    /// A plugin reported the progress of a command.
+    Status { job: JobId, reply: CommandReply },
+    /// A plugin finished handling a command.
+    Replied {
+        job: JobId,
+        reply: Result<CommandReply, AppError>,
+    },
+    /// A plugin handled the response to a command that it sent to another plugin.
+    HandledResponse {
+        plugin_id: PluginId,
+        response: Result<ROption<RArc<PluginResponse>>, AppError>,
+    },
+    /// A plugin panicked or failed to construct, it's constructed again after `retry_in`.
+    Failed {
+        plugin_id: PluginId,
+        reason: String,
+        retry_in: Duration,
+    },
+    /// A plugin that failed was constructed again.
+    Restarted { plugin_id: PluginId },
+    /// A plugin was closed, see `PluginWorker::close_later`.
+    Closed { plugin_id: PluginId },
+}
+
+/// How long to wait before constructing a plugin again after it failed,
+/// doubling after every failure in a row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Returns how long to wait after `failures` failures in a row.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// A plugin running on its own thread, handling its jobs in the order they were sent.
+pub struct PluginWorker {
+    plugin_id: PluginId,
+    jobs: Sender<Job>,
+    /// The jobs that the plugin didn't start yet, to hand them to the plugin replacing it.
+    queued: Receiver<Job>,
+    thread: JoinHandle<()>,
+}
+
+/// A plugin that is being closed on its worker thread, without waiting for it.
+pub struct Closing {
+    plugin_id: PluginId,
+    timeout: Duration,
+    deadline: Instant,
+    closed: mpsc::Receiver<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Closing {
+    pub fn plugin_id(&self) -> &PluginId {
+        &self.plugin_id
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Returns the plugin back if it's still closing and its deadline didn't pass,
+    /// reporting it if the deadline passed.
+    ///
+    /// A plugin that doesn't close in time can't be interrupted, its thread is left running.
+    pub fn poll(self) -> Option<Self> {
+        match self.closed.try_recv() {
+            Ok(()) | Err(mpsc::TryRecvError::Disconnected) => {
+                let _ = self.thread.join();
+                None
+            }
+            Err(mpsc::TryRecvError::Empty) if Instant::now() < self.deadline => Some(self),
+            Err(mpsc::TryRecvError::Empty) => {
+                eprintln!(
+                    "Plugin {:?} did not close within {:?}",
+                    self.plugin_id, self.timeout
+                );
+                None
+            }
+        }
+    }
+
+    /// Waits until the plugin closed or its deadline passed, returning false in the latter case.
+    pub fn wait(self) -> bool {
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        match self.closed.recv_timeout(timeout) {
+            Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let _ = self.thread.join();
+                true
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => false,
+        }
+    }
+}
+
+/// The `Application` that plugins get while handling jobs on their worker thread.
+struct WorkerApp {
+    events: Sender<Event>,
+    sender: RSender<PluginCommand>,
+    /// The command that the plugin is handling.
+    job: Option<JobId>,
+}
+
+impl Application for WorkerApp {
+    fn send_command_to_plugin(&mut self, command: RArc<PluginCommand>) {
+        let _ = self.events.send(Event::Command((*command).clone()));
+    }
+
+    fn sender(&self) -> RSender<PluginCommand> {
+        self.sender.clone()
+    }
+
+    fn report_status(&mut self, _request_id: RStr<'_>, reply: CommandReply) {
+        if let Some(job) = self.job {
+            let _ = self
+                .events
+                .send(Event::Worker(WorkerEvent::Status { job, reply }));
+        }
+    }
+}
+
+/// The state of a worker thread.
+struct Worker {
+    source: PluginSource,
+    plugin_id: PluginId,
+    app: WorkerApp,
+    backoff: Backoff,
+    /// The plugin, `None` while it's failed.
+    plugin: Option<PluginType>,
+    /// How many times in a row the plugin failed.
+    failures: u32,
+    /// When to construct the plugin again, while it's failed.
+    retry_at: Instant,
+}
+
+impl Worker {
+    fn construct(&mut self) -> Result<(), AppError> {
+        let plugin = self
+            .source
+            .construct(self.app.sender.clone(), self.plugin_id.clone())?;
+        self.plugin = Some(plugin);
+        self.failures = 0;
+        Ok(())
+    }
+
+    /// Drops the plugin, to construct it again once the backoff elapsed.
+    fn fail(&mut self, reason: String) {
+        if let Some(plugin) = self.plugin.take() {
+            plugin.close(Application_TO::from_ptr(&mut self.app, TD_Opaque));
+        }
+        self.failures += 1;
+        let retry_in = self.backoff.delay(self.failures);
+        self.retry_at = Instant::now() + retry_in;
+        let _ = self.app.events.send(Event::Worker(WorkerEvent::Failed {
+            plugin_id: self.plugin_id.clone(),
+            reason,
+            retry_in,
+        }));
+    }
+
+    fn failed_error(&self) -> AppError {
+        AppError::Custom(RBoxError::from_fmt(&format_args!(
+            "Plugin {:?} failed and is being restarted",
+            self.plugin_id
+        )))
+    }
+
+    fn run(mut self, jobs: Receiver<Job>) {
+        loop {
+            let job = if self.plugin.is_some() {
+                match jobs.recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                }
+            } else {
+                match jobs.recv_deadline(self.retry_at) {
+                    Ok(job) => job,
+                    Err(RecvTimeoutError::Timeout) => {
+                        match self.construct() {
+                            Ok(()) => {
+                                let _ =
+                                    self.app.events.send(Event::Worker(WorkerEvent::Restarted {
+                                        plugin_id: self.plugin_id.clone(),
+                                    }));
+                            }
+                            Err(e) => self.fail(e.to_string()),
+                        }
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            };
+
+            match job {
+                Job::Command { job, command } => {
+                    let reply = match &mut self.plugin {
+                        Some(plugin) => {
+                            self.app.job = Some(job);
+                            let state = Application_TO::from_ptr(&mut self.app, TD_Opaque);
+                            let reply = plugin.handle_command(&command, state).into_result();
+                            self.app.job = None;
+                            reply
+                        }
+                        None => Err(self.failed_error()),
+                    };
+                    let panicked = match &reply {
+                        Err(e) if panics::is_panic(e) => Some(e.to_string()),
+                        _ => None,
+                    };
+                    let _ = self
+                        .app
+                        .events
+                        .send(Event::Worker(WorkerEvent::Replied { job, reply }));
+                    if let Some(reason) = panicked {
+                        self.fail(reason);
+                    }
+                }
+                Job::Response(response) => {
+                    let response = match &mut self.plugin {
+                        Some(plugin) => {
+                            let state = Application_TO::from_ptr(&mut self.app, TD_Opaque);
+                            plugin.handle_response(response, state).into_result()
+                        }
+                        None => Err(self.failed_error()),
+                    };
+                    let panicked = match &response {
+                        Err(e) if panics::is_panic(e) => Some(e.to_string()),
+                        _ => None,
+                    };
+                    let _ = self
+                        .app
+                        .events
+                        .send(Event::Worker(WorkerEvent::HandledResponse {
+                            plugin_id: self.plugin_id.clone(),
+                            response,
+                        }));
+                    if let Some(reason) = panicked {
+                        self.fail(reason);
+                    }
+                }
+                Job::Close(closed) => {
+                    if let Some(plugin) = self.plugin.take() {
+                        plugin.close(Application_TO::from_ptr(&mut self.app, TD_Opaque));
+                    }
+                    let _ = closed.send(());
+                    let _ = self.app.events.send(Event::Worker(WorkerEvent::Closed {
+                        plugin_id: self.plugin_id.clone(),
+                    }));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl PluginWorker {
+    /// Constructs a plugin on a new thread,
+    /// since plugins can't be sent between threads once constructed.
+    ///
+    /// Once constructed, the plugin is constructed again with `backoff`
+    /// every time that it panics.
+    pub fn spawn(
+        source: PluginSource,
+        plugin_id: PluginId,
+        sender: RSender<PluginCommand>,
+        events: Sender<Event>,
+        backoff: Backoff,
+    ) -> Result<Self, AppError> {
+        Self::start(source, plugin_id, sender, events, backoff, true)
+    }
+
+    /// Starts the worker of a plugin that failed to construct,
+    /// which constructs it again with `backoff` until it succeeds.
+    pub fn spawn_failed(
+        source: PluginSource,
+        plugin_id: PluginId,
+        sender: RSender<PluginCommand>,
+        events: Sender<Event>,
+        backoff: Backoff,
+    ) -> Result<Self, AppError> {
+        Self::start(source, plugin_id, sender, events, backoff, false)
+    }
+
+    fn start(
+        source: PluginSource,
+        plugin_id: PluginId,
+        sender: RSender<PluginCommand>,
+        events: Sender<Event>,
+        backoff: Backoff,
+        construct: bool,
+    ) -> Result<Self, AppError> {
+        let (jobs, job_receiver) = unbounded::<Job>();
+        let (constructed_tx, constructed_rx) = mpsc::channel::<Result<(), AppError>>();
+
+        let worker_plugin_id = plugin_id.clone();
+        let queued = job_receiver.clone();
+        let thread = thread::Builder::new()
+            .name(format!("plugin-{}", plugin_id))
+            .spawn(move || {
+                let mut worker = Worker {
+                    source,
+                    plugin_id: worker_plugin_id,
+                    app: WorkerApp {
+                        events,
+                        sender,
+                        job: None,
+                    },
+                    backoff,
+                    plugin: None,
+                    failures: 0,
+                    retry_at: Instant::now(),
+                };
+                if construct {
+                    if let Err(e) = worker.construct() {
+                        let _ = constructed_tx.send(Err(e));
+                        return;
+                    }
+                } else {
+                    worker.failures = 1;
+                    worker.retry_at = Instant::now() + backoff.delay(1);
+                }
+                let _ = constructed_tx.send(Ok(()));
+                worker.run(job_receiver);
+            })
+            .map_err(|e| AppError::Custom(RBoxError::new(e)))?;
+
+        match constructed_rx.recv() {
+            Ok(Ok(())) => Ok(Self {
+                plugin_id,
+                jobs,
+                queued,
+                thread,
+            }),
+            Ok(Err(e)) => {
+                let _ = thread.join();
+                Err(e)
+            }
+            Err(_) => {
+                let _ = thread.join();
+                Err(AppError::invalid_plugin_id(plugin_id))
+            }
+        }
+    }
+    pub fn plugin_id(&self) -> &PluginId {
+        &self.plugin_id
+    }
+
+    /// Queues a job for the plugin, failing if its worker thread is gone.
+    pub fn send(&self, job: Job) -> Result<(), AppError> {
+        // `queued` keeps the channel open after the thread is gone.
+        if self.thread.is_finished() {
+            return Err(AppError::invalid_plugin_id(self.plugin_id.clone()));
+        }
+        self.jobs
+            .send(job)
+            .map_err(|_| AppError::invalid_plugin_id(self.plugin_id.clone()))
+    }
+
+    /// Hands the jobs that the plugin didn't start yet to `to`, in the order they were sent.
+    ///
+    /// The plugin may still start one of them in the meantime, which it then handles itself.
+    pub fn hand_over(&self, to: &PluginWorker) {
+        for job in self.queued.try_iter() {
+            if let Err(e) = to.send(job) {
+                eprintln!("Could not hand a job over to {:?}: {}", to.plugin_id, e);
+            }
+        }
+    }
+
+    /// Closes the plugin once it's done with the jobs queued before,
+    /// returning false if that takes longer than `timeout`.
+    ///
+    /// A plugin that doesn't close in time can't be interrupted, its thread is left running.
+    pub fn close(self, timeout: Duration) -> bool {
+        self.close_later(timeout).wait()
+    }
+
+    /// Closes the plugin once it's done with the jobs queued before, without waiting for it,
+    /// its worker thread sends `WorkerEvent::Closed` once it closed.
+    pub fn close_later(self, timeout: Duration) -> Closing {
+        let (closed_tx, closed) = mpsc::channel::<()>();
+        // A worker that is gone drops the sender, which counts as closed.
+        let _ = self.send(Job::Close(closed_tx));
+        Closing {
+            plugin_id: self.plugin_id,
+            timeout,
+            deadline: Instant::now() + timeout,
+            closed,
+            thread: self.thread,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(3),
+        };
+        assert_eq!(backoff.delay(1), Duration::from_millis(500));
+        assert_eq!(backoff.delay(2), Duration::from_millis(1000));
+        assert_eq!(backoff.delay(3), Duration::from_millis(2000));
+        assert_eq!(backoff.delay(4), Duration::from_secs(3));
+        assert_eq!(backoff.delay(40), Duration::from_secs(3));
+    }
+}
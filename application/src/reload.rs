@@ -0,0 +1,505 @@
+//! Loads the config file again while running, when it changes if `watch.config` is set,
+//! on SIGHUP, or when `host` gets `{"op": "reload_config"}`.
+//!
+//! The new config is checked like with `--validate`, and the plugins it adds or changes are
+//! constructed before anything else changes, so a config that fails to load, to validate,
+//! or whose plugins fail to construct leaves the running config as it was.
+
+use abi_stable::std_types::RArc;
+use common::{Error as AppError, PluginCommand, PluginId};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::app::TheApplication;
+use crate::error::{RError, RErrorKind, RResult};
+use crate::plugin::{
+    self, watcher::LibraryWatcher, worker::Backoff, PluginSource, PluginToLoad, Staged,
+};
+use crate::router::Router;
+use crate::utils::config::{self, Config};
+use crate::validate::{self, Capabilities};
+use crate::{info, warn};
+
+/// The config that's running, and where to load it again from.
+pub struct LoadedConfig {
+    pub path: PathBuf,
+    /// The plugin directories passed on the command line.
+    pub cli_plugin_dirs: Vec<PathBuf>,
+    pub current: Config,
+    pub watcher: Option<ConfigWatcher>,
+}
+
+impl LoadedConfig {
+    pub fn new(path: PathBuf, cli_plugin_dirs: Vec<PathBuf>, current: Config) -> Self {
+        let watcher = current.watch.config.then(|| {
+            ConfigWatcher::new(Duration::from_millis(current.watch.poll_interval_ms), &path)
+        });
+        Self {
+            path,
+            cli_plugin_dirs,
+            current,
+            watcher,
+        }
+    }
+}
+
+/// Polls the config file, with the same rule as `LibraryWatcher`:
+/// a change is only reported once the modification time stays the same for a whole poll.
+pub struct ConfigWatcher {
+    interval: Duration,
+    last_poll: Instant,
+    /// When the file was last modified, as of the last time it was reported.
+    loaded: Option<SystemTime>,
+    /// The modification time in the previous poll, if it changed.
+    pending: Option<SystemTime>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl ConfigWatcher {
+    pub fn new(interval: Duration, path: &Path) -> Self {
+        Self {
+            interval,
+            last_poll: Instant::now(),
+            loaded: modified(path),
+            pending: None,
+        }
+    }
+
+    /// Returns how long until the file should be polled again.
+    pub fn until_next_poll(&self) -> Duration {
+        self.interval.saturating_sub(self.last_poll.elapsed())
+    }
+
+    /// Returns whether the file changed and is done changing, at most once every `interval`.
+    pub fn poll(&mut self, path: &Path) -> bool {
+        if self.last_poll.elapsed() < self.interval {
+            return false;
+        }
+        self.last_poll = Instant::now();
+
+        // The file can be missing for a moment while an editor replaces it.
+        let modified = match modified(path) {
+            Some(x) => x,
+            None => return false,
+        };
+        if self.loaded == Some(modified) {
+            self.pending = None;
+            false
+        } else if self.pending == Some(modified) {
+            self.pending = None;
+            self.loaded = Some(modified);
+            true
+        } else {
+            self.pending = Some(modified);
+            false
+        }
+    }
+}
+
+/// How the plugins of the new config differ from the running ones, by the id they're loaded as.
+#[derive(Debug, Default)]
+pub struct PluginChanges {
+    pub added: Vec<PluginToLoad>,
+    pub removed: Vec<PluginId>,
+    /// Plugins loaded from the same library or executable under another id, with their old id.
+    pub renamed: Vec<(PluginId, PluginToLoad)>,
+    /// Plugins loaded as the same id, from another library or with other arguments.
+    pub changed: Vec<PluginToLoad>,
+}
+
+impl PluginChanges {
+    pub fn between(old: &[PluginToLoad], new: &[PluginToLoad]) -> Self {
+        let old_ids = old
+            .iter()
+            .map(|plug| (plug.plugin_id(), plug))
+            .collect::<HashMap<PluginId, &PluginToLoad>>();
+        let new_ids = new
+            .iter()
+            .map(PluginToLoad::plugin_id)
+            .collect::<Vec<PluginId>>();
+
+        let mut changes = PluginChanges::default();
+        let mut removed = old
+            .iter()
+            .filter(|plug| !new_ids.contains(&plug.plugin_id()))
+            .collect::<Vec<&PluginToLoad>>();
+        for plug in new {
+            match old_ids.get(&plug.plugin_id()) {
+                Some(old) if old.same_source(plug) => {}
+                Some(_) => changes.changed.push(plug.clone()),
+                None => match removed.iter().position(|old| old.same_source(plug)) {
+                    Some(i) => {
+                        let old = removed.remove(i);
+                        changes.renamed.push((old.plugin_id(), plug.clone()));
+                    }
+                    None => changes.added.push(plug.clone()),
+                },
+            }
+        }
+        changes.removed = removed.iter().map(|plug| plug.plugin_id()).collect();
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.renamed.is_empty()
+            && self.changed.is_empty()
+    }
+
+    /// The plugins that have to be constructed.
+    fn to_load(&self) -> impl Iterator<Item = &PluginToLoad> {
+        self.added
+            .iter()
+            .chain(self.renamed.iter().map(|(_, plug)| plug))
+            .chain(&self.changed)
+    }
+
+    /// The ids of the running plugins that have to be closed.
+    fn to_close(&self) -> impl Iterator<Item = PluginId> + '_ {
+        self.removed
+            .iter()
+            .cloned()
+            .chain(self.renamed.iter().map(|(old_id, _)| old_id.clone()))
+            .chain(self.changed.iter().map(PluginToLoad::plugin_id))
+    }
+}
+
+impl TheApplication {
+    /// Loads the config file again if it changed, reporting what was applied.
+    pub(super) fn reload_changed_config(&mut self) {
+        let changed = match &mut self.config.watcher {
+            Some(watcher) => watcher.poll(&self.config.path),
+            None => return,
+        };
+        if changed {
+            self.reload_config_logged();
+        }
+    }
+
+    pub(super) fn reload_config_logged(&mut self) {
+        match self.reload_config() {
+            Ok(applied) => info!("{}", applied),
+            Err(e) => eprintln!(
+                "Could not reload the config, the running one is kept, because of this error: {}",
+                e
+            ),
+        }
+    }
+
+    /// Loads the config file again and applies how it differs from the running config,
+    /// returning what was applied.
+    ///
+    /// Plugins are loaded, closed, or closed and loaded again as their new id or from their new
+    /// library, and the routes and the settings used while running are replaced.
+    /// The commands of the config are not run again.
+    /// The config is checked with the commands that the unchanged plugins declared when they were
+    /// loaded, only the libraries of the plugins that are constructed again are loaded.
+    /// Commands queued for a renamed plugin are handed to it under its new id,
+    /// the ones queued for a removed plugin fail like after `{"op": "unload"}`.
+    pub fn reload_config(&mut self) -> RResult<String> {
+        let path = self.config.path.clone();
+        let config = config::load(&path)?;
+        let dirs = plugin::search_dirs(&self.config.cli_plugin_dirs, &config.plugin_dirs);
+        let changes = PluginChanges::between(&self.config.current.plugins, &config.plugins);
+        let could_not_load = |plugin_id: PluginId, e: AppError| {
+            RError::new(
+                RErrorKind::FileInvalid,
+                format!(
+                    "{}: could not load plugin {:?}: {}",
+                    path.display(),
+                    plugin_id,
+                    e
+                ),
+            )
+        };
+
+        // Only the plugins that are constructed again are looked up,
+        // the others keep what they were running with.
+        let mut resolved = Vec::<(PluginId, PathBuf, PluginSource)>::new();
+        for plug in changes.to_load() {
+            let (library_path, source) =
+                plugin::resolve(plug, &dirs, &mut self.state.library_version)
+                    .map_err(|e| could_not_load(plug.plugin_id(), e))?;
+            resolved.push((plug.plugin_id(), library_path, source));
+        }
+        let mut capabilities = Capabilities::new();
+        for plugin_id in config.plugins.iter().map(PluginToLoad::plugin_id) {
+            let supported = match resolved.iter().find(|(id, ..)| *id == plugin_id) {
+                Some((.., source)) => source.supported_commands(),
+                None => self.state.supported_commands.get(&plugin_id).cloned(),
+            };
+            if let Some(supported) = supported {
+                capabilities.insert(plugin_id, supported);
+            }
+        }
+        let errors = validate::check(&config, &capabilities)
+            .into_iter()
+            .filter(|problem| !problem.warning)
+            .map(|problem| problem.to_string())
+            .collect::<Vec<String>>();
+        if !errors.is_empty() {
+            return Err(RError::new(
+                RErrorKind::FileInvalid,
+                format!("{}: {}", path.display(), errors.join("; ")),
+            ));
+        }
+
+        let mut staged = Vec::<Staged>::new();
+        for (plugin_id, library_path, source) in resolved {
+            match plugin::stage(plugin_id.clone(), library_path, source, &self.state) {
+                Ok(x) => staged.push(x),
+                Err(e) => {
+                    for staged in staged {
+                        self.state.close_later(staged.worker, self.close_timeout);
+                    }
+                    return Err(could_not_load(plugin_id, e));
+                }
+            }
+        }
+
+        // Nothing can fail from here on.
+        for plugin_id in changes.to_close() {
+            if self.plugins.contains_key(&plugin_id) {
+                let _ = plugin::unload(
+                    &mut self.plugins,
+                    &mut self.state,
+                    &plugin_id,
+                    self.close_timeout,
+                );
+            }
+        }
+        for staged in staged {
+            let plugin_id = staged.plugin_id.clone();
+            info!("resolved {:?} from {}", plugin_id, staged.path.display());
+            plugin::add(
+                &mut self.plugins,
+                &mut self.state,
+                staged,
+                self.close_timeout,
+            );
+            info!("load {:?} success", plugin_id);
+        }
+        for (old_id, plug) in &changes.renamed {
+            self.retarget(old_id, &plug.plugin_id());
+        }
+        self.apply_settings(&config);
+
+        let applied = format!(
+            "reload config {} success: {} added, {} removed, {} renamed, {} changed",
+            path.display(),
+            changes.added.len(),
+            changes.removed.len(),
+            changes.renamed.len(),
+            changes.changed.len()
+        );
+        self.config.current = config;
+        Ok(applied)
+    }
+
+    /// Hands the commands queued or deferred for a plugin to its new id.
+    fn retarget(&mut self, old_id: &PluginId, new_id: &PluginId) {
+        for command in self.state.commands.iter_mut() {
+            if command.to == *old_id {
+                *command = RArc::new(PluginCommand {
+                    to: new_id.clone(),
+                    ..(**command).clone()
+                });
+            }
+        }
+        for deferred in self.state.deferred.iter_mut() {
+            if deferred.plugin_id == *old_id {
+                deferred.plugin_id = new_id.clone();
+            }
+        }
+    }
+
+    /// Replaces the settings used while running, and warns about the ones that are only read
+    /// when the application starts.
+    fn apply_settings(&mut self, config: &Config) {
+        self.router = Router::new(config.routes.clone());
+        self.batch_concurrency = config.batch.max_concurrency;
+        self.close_timeout = Duration::from_millis(config.shutdown.close_timeout_ms);
+        self.state.restart_backoff = Backoff {
+            initial: Duration::from_millis(config.restart.initial_backoff_ms),
+            max: Duration::from_millis(config.restart.max_backoff_ms),
+        };
+        // Watching again from now, a library whose path changed was just loaded.
+        self.watcher = config.watch.enabled.then(|| {
+            LibraryWatcher::new(
+                Duration::from_millis(config.watch.poll_interval_ms),
+                &self.state.library_paths,
+            )
+        });
+        let current = &self.config.current;
+        if config.watch.config != current.watch.config
+            || config.watch.poll_interval_ms != current.watch.poll_interval_ms
+        {
+            self.config.watcher = config.watch.config.then(|| {
+                ConfigWatcher::new(
+                    Duration::from_millis(config.watch.poll_interval_ms),
+                    &self.config.path,
+                )
+            });
+        }
+
+        let restart = [
+            ("correlation", config.correlation != current.correlation),
+            ("journal", config.journal != current.journal),
+            ("dedup", config.dedup != current.dedup),
+            ("http", config.http != current.http),
+            ("unix_socket", config.unix_socket != current.unix_socket),
+            ("mqtt", config.mqtt != current.mqtt),
+        ];
+        for (section, changed) in restart {
+            if changed {
+                warn!(
+                    "the {:?} section of the config changed, it's applied after a restart",
+                    section
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{openc2::command::OpenC2Action, CommandDescription};
+
+    fn plugins(json: &str) -> Vec<PluginToLoad> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_plugin_changes_between() {
+        let old = plugins(
+            r#"[
+                "plugin_fw",
+                {"name": "plugin_server"},
+                {"process": "plugin_switch", "args": ["-v"]},
+                {"name": "plugin_log"}
+            ]"#,
+        );
+        let new = plugins(
+            r#"[
+                {"name": "plugin_fw", "rename": "plugin_fw"},
+                {"name": "plugin_server", "rename": "server"},
+                {"process": "plugin_switch", "args": ["-q"]},
+                {"process": "plugin_router"}
+            ]"#,
+        );
+
+        let changes = PluginChanges::between(&old, &new);
+        let ids = |plugs: &[PluginToLoad]| {
+            plugs
+                .iter()
+                .map(PluginToLoad::plugin_id)
+                .collect::<Vec<PluginId>>()
+        };
+        assert_eq!(ids(&changes.added), vec!["plugin_router"]);
+        assert_eq!(changes.removed, vec!["plugin_log"]);
+        assert_eq!(changes.renamed.len(), 1);
+        assert_eq!(changes.renamed[0].0, "plugin_server");
+        assert_eq!(changes.renamed[0].1.plugin_id(), "server");
+        assert_eq!(ids(&changes.changed), vec!["plugin_switch"]);
+
+        assert!(PluginChanges::between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_reload_config_keeps_everything_if_a_plugin_fails_to_load() {
+        let path = std::env::temp_dir().join(format!("reload-test-{}.json", std::process::id()));
+        let old = serde_json::json!({
+            "plugins": [],
+            "commands": {},
+            "routes": [{"plugin": "rollback_kept", "actuator_id": ["kept-01"]}]
+        });
+        std::fs::write(&path, old.to_string()).unwrap();
+        let (mut app, _events, _) = crate::testing::application(
+            path.clone(),
+            crate::testing::config(old),
+            &["rollback_kept"],
+        );
+        let router = app.router.clone();
+
+        // `false` exits before answering, so the plugin fails to stage.
+        let new = serde_json::json!({
+            "plugins": [{"process": "/bin/false", "rename": "rollback_broken", "timeout_ms": 1000}],
+            "commands": {},
+            "routes": [{"plugin": "rollback_broken", "actuator_id": ["broken-01"]}]
+        });
+        std::fs::write(&path, new.to_string()).unwrap();
+        let res = app.reload_config();
+        std::fs::remove_file(&path).unwrap();
+
+        let e = res.unwrap_err().to_string();
+        assert!(e.contains("could not load plugin"), "{}", e);
+        assert_eq!(
+            app.plugins.keys().collect::<Vec<&PluginId>>(),
+            [&PluginId::from("rollback_kept")]
+        );
+        assert!(!app.state.id_map.contains_key("rollback_broken"));
+        assert_eq!(app.router, router);
+        assert!(app.config.current.plugins.is_empty());
+        assert_eq!(Router::new(app.config.current.routes.clone()), router);
+    }
+
+    #[test]
+    fn test_reload_config_checks_running_plugins_with_their_capabilities() {
+        let path = std::env::temp_dir().join(format!("reload-caps-{}.json", std::process::id()));
+        let config = |action: &str| {
+            serde_json::json!({
+                "plugins": ["reuse_kept"],
+                // Nothing in here, the running plugin must not be looked up again.
+                "plugin_dirs": ["/nonexistent/plugin/dir"],
+                "commands": {
+                    "reuse_kept": {
+                        "header": {"request_id": "1", "msg_type": "request", "version": "1.0", "created": 0, "sender": "gateway"},
+                        "command": {"action": action, "target": {"features": ["versions"]}}
+                    }
+                }
+            })
+        };
+        let old = config("query");
+        std::fs::write(&path, old.to_string()).unwrap();
+        let (mut app, _events, _) =
+            crate::testing::application(path.clone(), crate::testing::config(old), &["reuse_kept"]);
+        app.state.set_supported_commands(
+            &PluginId::from("reuse_kept"),
+            Some(
+                vec![CommandDescription::new(
+                    OpenC2Action::Query,
+                    "features.versions",
+                    "",
+                )]
+                .into(),
+            ),
+        );
+
+        std::fs::write(&path, config("deny").to_string()).unwrap();
+        let denied = app.reload_config();
+        std::fs::write(&path, config("query").to_string()).unwrap();
+        let queried = app.reload_config();
+        std::fs::remove_file(&path).unwrap();
+
+        let e = denied.unwrap_err().to_string();
+        assert!(
+            e.contains(r#""reuse_kept" did not declare "deny features.versions""#),
+            "{}",
+            e
+        );
+        assert!(queried.is_ok(), "{:?}", queried.err());
+        assert_eq!(
+            app.plugins.keys().collect::<Vec<&PluginId>>(),
+            [&PluginId::from("reuse_kept")]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use common::{
+    openc2::{
+        command::{ActuatorType, OpenC2Action, OpenC2Command},
+        target::Target,
+    },
+    PluginId,
+};
+use serde::Deserialize;
+
+/// Registers a plugin for the commands matching every criteria that is set,
+/// a route with no criteria matches every command.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Route {
+    pub plugin: PluginId,
+    /// Matches commands whose actuator is of this type.
+    #[serde(default)]
+    pub actuator_type: Option<ActuatorType>,
+    /// Matches commands addressed to any of these actuator ids.
+    #[serde(default)]
+    pub actuator_id: Vec<String>,
+    /// Matches commands whose target is a device of any of these kinds.
+    #[serde(default)]
+    pub device_kind: Vec<String>,
+    /// Matches commands with this action.
+    #[serde(default)]
+    pub action: Option<OpenC2Action>,
+    /// Matches commands whose target has this identity, like `artifact.cmd`.
+    #[serde(default)]
+    pub target: Option<String>,
+}
+
+impl Route {
+    pub fn matches(&self, command: &OpenC2Command) -> bool {
+        let actuator = command.get_actuator().as_ref();
+
+        if let Some(actuator_type) = &self.actuator_type {
+            if actuator.map(|a| a.get_actuator_type()) != Some(actuator_type) {
+                return false;
+            }
+        }
+
+        if !self.actuator_id.is_empty() {
+            let ids = actuator.map_or(&[][..], |a| a.get_actuator_id());
+            if !ids.iter().any(|id| self.actuator_id.contains(id)) {
+                return false;
+            }
+        }
+
+        if !self.device_kind.is_empty() {
+            match command.get_target() {
+                Target::Device(device) if self.device_kind.contains(&device.kind) => {}
+                _ => return false,
+            }
+        }
+
+        let (action, target) = command.get_command_identity();
+        if self.action.as_ref().is_some_and(|a| *a != action) {
+            return false;
+        }
+        if self.target.as_ref().is_some_and(|t| *t != target) {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Finds the plugins that handle an OpenC2 command, from its actuator and action/target.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Self { routes }
+    }
+
+    /// Returns the plugins registered for the command, in the order of their first matching route.
+    pub fn route(&self, command: &OpenC2Command) -> Vec<PluginId> {
+        let mut plugins = Vec::<PluginId>::new();
+        for route in &self.routes {
+            if route.matches(command) && !plugins.contains(&route.plugin) {
+                plugins.push(route.plugin.clone());
+            }
+        }
+        plugins
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn command(action: &str, target: serde_json::Value, actuator_id: &str) -> OpenC2Command {
+        serde_json::from_value(json!({
+            "header": {
+                "request_id": "1",
+                "msg_type": "request",
+                "version": "1.0",
+                "created": 0,
+                "sender": "gateway"
+            },
+            "command": {
+                "action": action,
+                "target": target,
+                "actuator": {
+                    "actuator_type": "device",
+                    "actuator_id": [actuator_id]
+                },
+                "args": null
+            }
+        }))
+        .unwrap()
+    }
+
+    fn route(value: serde_json::Value) -> Route {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_route_by_actuator() {
+        let router = Router::new(vec![
+            route(json!({"plugin": "plugin_fw", "actuator_id": ["fw-01", "fw-02"]})),
+            route(json!({"plugin": "plugin_server", "actuator_type": "platform"})),
+            route(json!({"plugin": "plugin_audit"})),
+        ]);
+        let artifact = json!({"artifact": {"mime_type": "cmd", "payload": {}}});
+
+        assert_eq!(
+            router.route(&command("set", artifact.clone(), "fw-02")),
+            vec![PluginId::from("plugin_fw"), PluginId::from("plugin_audit")]
+        );
+        assert_eq!(
+            router.route(&command("set", artifact, "server-01")),
+            vec![PluginId::from("plugin_audit")]
+        );
+    }
+
+    #[test]
+    fn test_route_by_device_kind_and_identity() {
+        let router = Router::new(vec![
+            route(json!({"plugin": "plugin_fw", "device_kind": ["fw"]})),
+            route(json!({"plugin": "plugin_server", "action": "query", "target": "artifact.cmd"})),
+        ]);
+        let device = json!({"device": {"id": "1", "kind": "fw"}});
+        let artifact = json!({"artifact": {"mime_type": "cmd", "payload": {}}});
+
+        assert_eq!(
+            router.route(&command("set", device, "dev-01")),
+            vec![PluginId::from("plugin_fw")]
+        );
+        assert_eq!(
+            router.route(&command("query", artifact.clone(), "dev-01")),
+            vec![PluginId::from("plugin_server")]
+        );
+        assert!(router.route(&command("set", artifact, "dev-01")).is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+use abi_stable::{external_types::crossbeam_channel::RReceiver, std_types::RArc};
+use common::PluginCommand;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use signal_hook::{
+    consts::{SIGHUP, TERM_SIGNALS},
+    iterator::Signals,
+};
+use std::{io, process, thread, time::Duration};
+
+use crate::app::TheApplication;
+use crate::plugin::worker::WorkerEvent;
+use crate::script::Script;
+use crate::transport::RemoteCommand;
+use crate::{debug, info};
+
+/// Something that wakes up the event loop.
+pub enum Event {
+    /// A command sent by a plugin through its `RSender<PluginCommand>`.
+    Command(PluginCommand),
+    /// A plugin did something on its worker thread.
+    Worker(WorkerEvent),
+    /// An OpenC2 command came in over a transport.
+    Remote(RemoteCommand),
+    /// The process was asked to terminate.
+    Terminate,
+    /// The process was asked to load the config file again, with SIGHUP.
+    ReloadConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunMode {
+    /// Runs until the process is asked to terminate.
+    Service,
+    /// Exits once there was nothing to do for `idle_timeout`,
+    /// used to run the commands from the config file and exit.
+    OneShot { idle_timeout: Duration },
+}
+
+/// Forwards the commands that plugins send through their `RSender<PluginCommand>`
+/// to the event loop.
+pub fn forward_plugin_commands(
+    receiver: RReceiver<PluginCommand>,
+    events: Sender<Event>,
+) -> io::Result<()> {
+    thread::Builder::new()
+        .name("plugin-commands".into())
+        .spawn(move || {
+            for command in receiver.iter() {
+                if events.send(Event::Command(command)).is_err() {
+                    break;
+                }
+            }
+        })
+        .map(drop)
+}
+
+/// Sends `Event::Terminate` to the event loop on SIGINT/SIGTERM.
+///
+/// A second signal exits the process right away, in case shutting down gets stuck.
+pub fn forward_term_signals(events: Sender<Event>) -> io::Result<()> {
+    let mut signals = Signals::new(TERM_SIGNALS)?;
+    thread::Builder::new()
+        .name("signals".into())
+        .spawn(move || {
+            let mut signals = signals.forever();
+            if signals.next().is_some() {
+                let _ = events.send(Event::Terminate);
+            }
+            if signals.next().is_some() {
+                process::exit(1);
+            }
+        })
+        .map(drop)
+}
+
+/// Sends `Event::ReloadConfig` to the event loop on SIGHUP.
+pub fn forward_reload_signals(events: Sender<Event>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::Builder::new()
+        .name("reload-signals".into())
+        .spawn(move || {
+            for _ in signals.forever() {
+                if events.send(Event::ReloadConfig).is_err() {
+                    break;
+                }
+            }
+        })
+        .map(drop)
+}
+
+impl TheApplication {
+    /// Handles events until the process is asked to terminate,
+    /// or until there's nothing left to do in `RunMode::OneShot`.
+    ///
+    /// This sleeps while waiting for events,
+    /// waking up only to poll the plugin libraries and the config file when they're watched,
+    /// to run deferred commands once their `start_time` comes,
+    /// to answer commands that plugins didn't reply to in time,
+    /// to send the steps of the script once their delay passed,
+    /// and to forget the commands that got no response for too long.
+    pub fn run(&mut self, events: &Receiver<Event>, mode: RunMode) {
+        loop {
+            self.run_deferred();
+            self.run_queued();
+            self.advance_batches();
+            self.advance_script();
+
+            let mut timeout = [
+                self.watcher.as_ref().map(|w| w.until_next_poll()),
+                self.config.watcher.as_ref().map(|w| w.until_next_poll()),
+                self.state.until_next_deferred(),
+                self.state.until_next_deadline(),
+                self.state.until_next_close_deadline(),
+                self.state.correlations.until_next_expiry(),
+                self.state
+                    .script
+                    .as_ref()
+                    .and_then(Script::until_next_start),
+            ]
+            .into_iter()
+            .fold(None, min_timeout);
+            if let RunMode::OneShot { idle_timeout } = mode {
+                let idle_for = self.state.last_run_at.elapsed();
+                if idle_for >= idle_timeout
+                    && self.state.deferred.is_empty()
+                    && self.state.in_flight.is_empty()
+                    && self.state.batches.is_empty()
+                    && self.state.closing.is_empty()
+                    && self.state.script.as_ref().is_none_or(Script::is_done)
+                {
+                    debug!("timeout waiting for events");
+                    return;
+                }
+                let until_idle = idle_timeout.saturating_sub(idle_for);
+                timeout = min_timeout(timeout, Some(until_idle));
+            }
+
+            let event = match timeout {
+                Some(timeout) => events.recv_timeout(timeout),
+                None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match event {
+                Ok(Event::Command(command)) => {
+                    self.state.commands.push_back(RArc::new(command));
+                }
+                Ok(Event::Worker(event)) => self.handle_worker_event(event),
+                Ok(Event::Remote(command)) => self.handle_remote_command(command),
+                Ok(Event::Terminate) => {
+                    info!("received termination signal, shutting down");
+                    return;
+                }
+                Ok(Event::ReloadConfig) => self.reload_config_logged(),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            self.expire_in_flight();
+            self.state.finish_closing();
+            self.state.expire_correlations();
+            self.reload_changed_libraries();
+            self.reload_changed_config();
+        }
+    }
+}
+
+fn min_timeout(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
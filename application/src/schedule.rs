@@ -0,0 +1,86 @@
+use common::openc2::{command::OpenC2Command, target::Target};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// When a command may run and how long its plugin gets to handle it,
+/// from its `start_time`/`stop_time` args and the connection timeout of its target device.
+///
+/// Times are in milliseconds since the unix epoch, like in OpenC2 commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Schedule {
+    pub start_time: Option<u64>,
+    pub stop_time: Option<u64>,
+    /// The connection timeout of the device that the command targets, in milliseconds.
+    pub device_timeout: Option<u64>,
+}
+
+/// Returns the current time in milliseconds since the unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl Schedule {
+    pub fn of(command: &OpenC2Command) -> Self {
+        let args = command.get_args();
+        let device_timeout = match command.get_target() {
+            Target::Device(device) => device.get_timeout(),
+            _ => None,
+        };
+        Schedule {
+            start_time: args.and_then(|args| args.get_start_time()),
+            stop_time: args.and_then(|args| args.get_stop_time()),
+            device_timeout,
+        }
+    }
+
+    /// Returns how long until the command may run, if its `start_time` is still to come.
+    pub fn until_start(&self, now: u64) -> Option<Duration> {
+        self.start_time
+            .filter(|&start_time| start_time > now)
+            .map(|start_time| Duration::from_millis(start_time - now))
+    }
+
+    /// Whether the command can't run anymore, because its `stop_time` passed.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.stop_time.is_some_and(|stop_time| stop_time <= now)
+    }
+
+    /// Returns how long the plugin gets to handle the command,
+    /// the shortest of the time left until `stop_time` and the device connection timeout.
+    pub fn timeout(&self, now: u64) -> Option<Duration> {
+        let until_stop = self
+            .stop_time
+            .map(|stop_time| stop_time.saturating_sub(now));
+        match (until_stop, self.device_timeout) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+        .map(Duration::from_millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule {
+            start_time: Some(2_000),
+            stop_time: Some(10_000),
+            device_timeout: Some(3_000),
+        };
+
+        assert_eq!(
+            schedule.until_start(1_500),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(schedule.until_start(2_000), None);
+        assert!(!schedule.is_expired(9_999));
+        assert!(schedule.is_expired(10_000));
+        assert_eq!(schedule.timeout(2_000), Some(Duration::from_millis(3_000)));
+        assert_eq!(schedule.timeout(8_000), Some(Duration::from_millis(2_000)));
+        assert_eq!(Schedule::default().timeout(8_000), None);
+    }
+}
@@ -0,0 +1,213 @@
+//! Runs the `script` of the config: commands sent one after the other,
+//! each once the previous one got its final responses and its delay passed,
+//! checking the status of the responses to them, so the config can describe a test scenario.
+
+use common::{
+    openc2::response::{OpenC2RespStatus, OpenC2Response},
+    PluginId,
+};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::time::{Duration, Instant};
+
+/// A command of the script.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Step {
+    /// The plugin the command is sent to, it's dispatched by `routes` if there's none.
+    #[serde(default)]
+    pub plugin: Option<PluginId>,
+    pub command: Box<RawValue>,
+    /// How long to wait before sending the command, after the previous step.
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// What the final responses to the command have to be for the step to pass.
+    #[serde(default)]
+    pub expect: Option<Expect>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Expect {
+    /// The status of every final response, there has to be at least one.
+    pub status: u16,
+}
+
+/// How a step went.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The step expected nothing.
+    Done,
+    Passed,
+    Failed(String),
+}
+
+pub struct Script {
+    steps: Vec<Step>,
+    /// The step that's running, or the next one to run.
+    next: usize,
+    running: bool,
+    /// When the next step is sent.
+    start_at: Instant,
+    /// The final responses to the step that's running.
+    responses: Vec<OpenC2Response>,
+    failed: usize,
+}
+
+impl Script {
+    pub fn new(steps: Vec<Step>, now: Instant) -> Self {
+        let delay = steps.first().map_or(0, |step| step.delay_ms);
+        Self {
+            steps,
+            next: 0,
+            running: false,
+            start_at: now + Duration::from_millis(delay),
+            responses: Vec::new(),
+            failed: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Whether every step ran.
+    pub fn is_done(&self) -> bool {
+        self.next >= self.steps.len()
+    }
+
+    /// Returns the index of the step that's running, or of the next one to run.
+    pub fn next(&self) -> usize {
+        self.next
+    }
+
+    /// Returns the index of the step that's running.
+    pub fn running(&self) -> Option<usize> {
+        self.running.then_some(self.next)
+    }
+
+    /// How many steps failed so far.
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+
+    /// Returns the next step and marks it as running, once its delay passed.
+    pub fn start_next(&mut self, now: Instant) -> Option<(usize, &Step)> {
+        if self.running || self.is_done() || now < self.start_at {
+            return None;
+        }
+        self.running = true;
+        self.responses.clear();
+        Some((self.next, &self.steps[self.next]))
+    }
+
+    /// Returns how long until the next step is sent, if it's waiting for its delay.
+    pub fn until_next_start(&self) -> Option<Duration> {
+        if self.running || self.is_done() {
+            return None;
+        }
+        Some(self.start_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Adds a response to the command of a step, if it's the one that's running.
+    pub fn record(&mut self, step: usize, response: OpenC2Response) {
+        let (processing, _) = OpenC2RespStatus::Processing.into();
+        if self.running() == Some(step) && response.get_status() != processing {
+            self.responses.push(response);
+        }
+    }
+
+    /// Checks the responses to the step that's running, and waits for the delay of the next one.
+    pub fn finish(&mut self, now: Instant) -> Outcome {
+        let outcome = match &self.steps[self.next].expect {
+            None => Outcome::Done,
+            Some(expect) => check(expect, &self.responses),
+        };
+        if let Outcome::Failed(_) = outcome {
+            self.failed += 1;
+        }
+        self.running = false;
+        self.next += 1;
+        let delay = self.steps.get(self.next).map_or(0, |step| step.delay_ms);
+        self.start_at = now + Duration::from_millis(delay);
+        outcome
+    }
+}
+
+fn check(expect: &Expect, responses: &[OpenC2Response]) -> Outcome {
+    if responses.is_empty() {
+        return Outcome::Failed(format!("expected {}, got no response", expect.status));
+    }
+    let unexpected = responses
+        .iter()
+        .filter(|response| response.get_status() != expect.status)
+        .map(|response| match response.get_desc().as_str() {
+            "" => format!("{} from {:?}", response.get_status(), response.get_sender()),
+            desc => format!(
+                "{} from {:?} ({})",
+                response.get_status(),
+                response.get_sender(),
+                desc
+            ),
+        })
+        .collect::<Vec<String>>();
+    if unexpected.is_empty() {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(format!(
+            "expected {}, got {}",
+            expect.status,
+            unexpected.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: OpenC2RespStatus) -> OpenC2Response {
+        OpenC2Response::new_status(vec![], "42", "plugin_fw", status, "")
+    }
+
+    #[test]
+    fn test_steps_run_in_order() {
+        let steps = serde_json::from_str::<Vec<Step>>(
+            r#"[
+                {"plugin": "plugin_fw", "command": {}, "expect": {"status": 200}},
+                {"command": {}, "delay_ms": 100, "expect": {"status": 200}},
+                {"plugin": "plugin_fw", "command": {}}
+            ]"#,
+        )
+        .unwrap();
+        let now = Instant::now();
+        let mut script = Script::new(steps, now);
+
+        let (step, _) = script.start_next(now).unwrap();
+        assert_eq!(step, 0);
+        assert!(script.start_next(now).is_none());
+        script.record(0, response(OpenC2RespStatus::Processing));
+        script.record(0, response(OpenC2RespStatus::OK));
+        assert_eq!(script.finish(now), Outcome::Passed);
+
+        // The second step waits for its delay.
+        assert!(script.start_next(now).is_none());
+        let later = now + Duration::from_millis(100);
+        assert_eq!(script.start_next(later).unwrap().0, 1);
+        script.record(1, response(OpenC2RespStatus::OK));
+        script.record(1, response(OpenC2RespStatus::NotFound));
+        match script.finish(later) {
+            Outcome::Failed(reason) => {
+                assert!(reason.starts_with("expected 200, got 404"), "{}", reason)
+            }
+            outcome => panic!("{:?}", outcome),
+        }
+
+        assert_eq!(script.start_next(later).unwrap().0, 2);
+        assert_eq!(script.finish(later), Outcome::Done);
+        assert!(script.is_done());
+        assert_eq!(script.failed(), 1);
+    }
+}
@@ -0,0 +1,271 @@
+//! Runs a `TheApplication` with plugins that are built into the test binary,
+//! for the tests that go through the whole application.
+
+use abi_stable::{
+    external_types::crossbeam_channel::RSender,
+    prefix_type::PrefixTypeTrait,
+    sabi_trait::TD_Opaque,
+    std_types::{ROk, RResult, RStr, RString, RVec},
+};
+use common::{
+    openc2::{response::CommandReply, response::OpenC2Response, view::CommandView},
+    panics::CatchPanics,
+    ApplicationMut, CommandDescription, Error as AppError, Plugin, PluginCommand, PluginFactory,
+    PluginId, PluginType, Plugin_TO,
+};
+use crossbeam_channel::{Receiver, Sender};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::app::{ApplicationState, TheApplication};
+use crate::correlation::Correlations;
+use crate::dedup::RecentCommands;
+use crate::plugin::{self, worker::Backoff, PluginSource};
+use crate::reload::LoadedConfig;
+use crate::router::Router;
+use crate::runtime::{Event, RunMode};
+use crate::transport::RemoteCommand;
+use crate::utils::config::Config;
+
+/// A command that a `TestPlugin` handled.
+#[derive(Debug, Clone)]
+pub struct Handled {
+    pub plugin_id: PluginId,
+    /// How many times the plugin was constructed before the instance that handled it.
+    pub instance: usize,
+    pub request_id: String,
+    pub actuator_id: Vec<String>,
+    pub started: Instant,
+    pub finished: Instant,
+}
+
+impl Handled {
+    /// Whether the plugins handled both commands at the same time for a moment.
+    pub fn overlaps(&self, other: &Handled) -> bool {
+        self.started < other.finished && other.started < self.finished
+    }
+}
+
+static HANDLED: Mutex<Vec<Handled>> = Mutex::new(Vec::new());
+
+/// When each `TestPlugin` was constructed.
+static CONSTRUCTED: Mutex<Vec<(PluginId, Instant)>> = Mutex::new(Vec::new());
+
+/// Returns the commands that the `TestPlugin` with `plugin_id` handled, in the order it did.
+///
+/// Tests run at the same time, each one loads its plugins with ids of its own.
+pub fn handled(plugin_id: &str) -> Vec<Handled> {
+    HANDLED
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|handled| handled.plugin_id == plugin_id)
+        .cloned()
+        .collect()
+}
+
+/// Returns when the `TestPlugin` with `plugin_id` was constructed, every time it was.
+pub fn constructed(plugin_id: &str) -> Vec<Instant> {
+    CONSTRUCTED
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(id, _)| *id == plugin_id)
+        .map(|(_, at)| *at)
+        .collect()
+}
+
+/// Replies to every command with its `plugin_id` and actuator ids in the results,
+/// after sleeping for the `delay_ms` in the payload of the artifact it targets, if it has one,
+/// and panics if the payload has `"panic": true`.
+///
+/// It's wrapped in `CatchPanics`, like the plugins of this repository.
+pub struct TestPlugin {
+    plugin_id: PluginId,
+    instance: usize,
+}
+
+impl Plugin for TestPlugin {
+    fn send_command(
+        &mut self,
+        _command: RStr<'_>,
+        _app: ApplicationMut<'_>,
+    ) -> RResult<RString, AppError> {
+        unreachable!("the application calls handle_command")
+    }
+
+    fn handle_command(
+        &mut self,
+        command: &CommandView,
+        _app: ApplicationMut<'_>,
+    ) -> RResult<CommandReply, AppError> {
+        let started = Instant::now();
+        let raw = serde_json::from_str::<Value>(&command.raw).unwrap_or_default();
+        if let Some(delay_ms) = raw["command"]["target"]["artifact"]["payload"]["delay_ms"].as_u64()
+        {
+            thread::sleep(Duration::from_millis(delay_ms));
+        }
+        if raw["command"]["target"]["artifact"]["payload"]["panic"] == true {
+            panic!("asked to panic by {}", command.request_id);
+        }
+        let actuator_id = command.actuator.as_ref().map_or_else(Vec::new, |actuator| {
+            actuator
+                .actuator_id
+                .iter()
+                .map(|id| id.to_string())
+                .collect()
+        });
+
+        HANDLED.lock().unwrap().push(Handled {
+            plugin_id: self.plugin_id.clone(),
+            instance: self.instance,
+            request_id: command.request_id.to_string(),
+            actuator_id: actuator_id.clone(),
+            started,
+            finished: Instant::now(),
+        });
+        ROk(CommandReply::new(vec![json!({
+            "plugin": self.plugin_id.as_str(),
+            "actuator_id": actuator_id,
+        })]))
+    }
+
+    fn plugin_id(&self) -> &PluginId {
+        &self.plugin_id
+    }
+
+    fn close(self, _app: ApplicationMut<'_>) {}
+}
+
+extern "C" fn new(
+    _sender: RSender<PluginCommand>,
+    plugin_id: PluginId,
+) -> RResult<PluginType, AppError> {
+    let mut constructed = CONSTRUCTED.lock().unwrap();
+    let instance = constructed
+        .iter()
+        .filter(|(id, _)| *id == plugin_id)
+        .count();
+    constructed.push((plugin_id.clone(), Instant::now()));
+    ROk(Plugin_TO::from_value(
+        CatchPanics(TestPlugin {
+            plugin_id,
+            instance,
+        }),
+        TD_Opaque,
+    ))
+}
+
+extern "C" fn supported_commands() -> RVec<CommandDescription> {
+    RVec::new()
+}
+
+/// Where `TestPlugin`s are constructed from.
+pub fn source() -> PluginSource {
+    PluginSource::Library(
+        PluginFactory {
+            new,
+            supported_commands,
+        }
+        .leak_into_prefix(),
+    )
+}
+
+/// Parses a config, filling in the sections that every config has.
+pub fn config(mut config: Value) -> Config {
+    for (key, value) in [("plugins", json!([])), ("commands", json!({}))] {
+        config.as_object_mut().unwrap().entry(key).or_insert(value);
+    }
+    serde_json::from_value(config).unwrap()
+}
+
+/// Builds the application for `config` at `path` with a `TestPlugin` for each of `plugin_ids`,
+/// returning it with the events it handles.
+pub fn application(
+    path: PathBuf,
+    config: Config,
+    plugin_ids: &[&str],
+) -> (TheApplication, Receiver<Event>, Sender<Event>) {
+    let (event_sender, events) = crossbeam_channel::unbounded();
+    let state = ApplicationState::new(
+        event_sender.clone(),
+        Backoff {
+            initial: Duration::from_millis(config.restart.initial_backoff_ms),
+            max: Duration::from_millis(config.restart.max_backoff_ms),
+        },
+        Correlations::new(Duration::from_millis(config.correlation.stale_after_ms)),
+        None,
+        RecentCommands::new(Duration::from_millis(config.dedup.window_ms)),
+        None,
+    );
+    let mut app = TheApplication {
+        plugins: HashMap::new(),
+        state,
+        watcher: None,
+        router: Router::new(config.routes.clone()),
+        close_timeout: Duration::from_millis(config.shutdown.close_timeout_ms),
+        batch_concurrency: config.batch.max_concurrency,
+        config: LoadedConfig::new(path, Vec::new(), config),
+    };
+    for plugin_id in plugin_ids {
+        let plugin_id = PluginId::from(*plugin_id);
+        let staged = plugin::stage(
+            plugin_id.clone(),
+            PathBuf::from(plugin_id.as_str()),
+            source(),
+            &app.state,
+        )
+        .unwrap();
+        plugin::add(&mut app.plugins, &mut app.state, staged, app.close_timeout);
+        app.state.set_supported_commands(&plugin_id, None);
+    }
+    (app, events, event_sender)
+}
+
+/// Sends a command to the application like a transport does,
+/// returning where its responses are sent.
+pub fn send(app: &mut TheApplication, command: Value) -> Receiver<OpenC2Response> {
+    let (responses, receiver) = crossbeam_channel::unbounded();
+    app.handle_remote_command(RemoteCommand {
+        transport: "test",
+        command: command.to_string(),
+        responses,
+    });
+    receiver
+}
+
+/// Handles events until there was nothing to do for a moment.
+pub fn run(app: &mut TheApplication, events: &Receiver<Event>) {
+    app.run(
+        events,
+        RunMode::OneShot {
+            idle_timeout: Duration::from_millis(100),
+        },
+    );
+}
+
+/// An OpenC2 command with `request_id` to the actuators with `actuator_id`,
+/// which `TestPlugin`s handle after `delay_ms`.
+pub fn command(request_id: &str, actuator_id: &[&str], delay_ms: u64) -> Value {
+    json!({
+        "header": {
+            "request_id": request_id,
+            "msg_type": "request",
+            "version": "1.0",
+            "created": 0,
+            "sender": "gateway"
+        },
+        "command": {
+            "action": "query",
+            "target": {"artifact": {"mime_type": "test", "payload": {"delay_ms": delay_ms}}},
+            "actuator": {"actuator_type": "device", "actuator_id": actuator_id},
+            "args": {"response_requested": "Complete"}
+        }
+    })
+}
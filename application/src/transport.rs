@@ -0,0 +1,20 @@
+use common::openc2::response::OpenC2Response;
+use crossbeam_channel::Sender;
+
+pub mod http;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(unix)]
+pub mod unix;
+
+/// An OpenC2 command that came in over a transport,
+/// dispatched to plugins like the commands in the `dispatch` section of the config file.
+pub struct RemoteCommand {
+    /// The name of the transport, like `http`.
+    pub transport: &'static str,
+    /// The JSON encoded `OpenC2Command`.
+    pub command: String,
+    /// Where the responses to the command are sent,
+    /// it's disconnected once there won't be any more.
+    pub responses: Sender<OpenC2Response>,
+}
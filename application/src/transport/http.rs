@@ -0,0 +1,300 @@
+//! The HTTP API, answering `POST /openc2` requests whose body is an `OpenC2Command`
+//! with the `OpenC2Response` to it.
+
+use common::openc2::response::{OpenC2RespStatus, OpenC2Response};
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use std::{
+    io,
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use super::RemoteCommand;
+use crate::runtime::Event;
+
+/// The path that OpenC2 commands are posted to.
+pub const OPENC2_PATH: &str = "/openc2";
+
+/// The media type of OpenC2 messages, from the OpenC2 HTTPS transfer specification.
+const OPENC2_CONTENT_TYPE: &str = "application/openc2+json;version=1.0";
+
+/// Starts serving the HTTP API on `addr`, returning the address it listens on.
+///
+/// Every request waits for the responses to its command on its own thread,
+/// for at most `response_timeout`.
+/// At most `max_requests` are handled at the same time, the others are answered with 503.
+pub fn serve(
+    addr: &str,
+    events: Sender<Event>,
+    response_timeout: Duration,
+    max_requests: usize,
+) -> io::Result<SocketAddr> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+    let local_addr = server
+        .server_addr()
+        .to_ip()
+        .ok_or_else(|| io::Error::other("the HTTP API is not listening on an ip address"))?;
+
+    // Holds a message for every request that is being handled.
+    let (permits_tx, permits_rx) = bounded::<()>(max_requests.max(1));
+    thread::Builder::new()
+        .name("http".into())
+        .spawn(move || {
+            for request in server.incoming_requests() {
+                if permits_tx.try_send(()).is_err() {
+                    let response = Response::from_string("too many requests are being handled")
+                        .with_status_code(503);
+                    if let Err(e) = request.respond(response) {
+                        eprintln!(
+                            "Could not answer an HTTP request, because of this error: {}",
+                            e
+                        );
+                    }
+                    continue;
+                }
+                let events = events.clone();
+                let permits = permits_rx.clone();
+                let res = thread::Builder::new()
+                    .name("http-request".into())
+                    .spawn(move || handle(request, &events, response_timeout, &permits));
+                if let Err(e) = res {
+                    let _ = permits_rx.try_recv();
+                    eprintln!(
+                        "Could not handle an HTTP request, because of this error: {}",
+                        e
+                    );
+                }
+            }
+        })
+        .map(drop)?;
+
+    Ok(local_addr)
+}
+
+/// Answers a request, then frees its permit.
+fn handle(
+    mut request: Request,
+    events: &Sender<Event>,
+    response_timeout: Duration,
+    permits: &Receiver<()>,
+) {
+    let path = request.url().split('?').next().unwrap_or_default();
+    let response = if path != OPENC2_PATH {
+        Response::from_string(format!("only {} is served", OPENC2_PATH)).with_status_code(404)
+    } else if *request.method() != Method::Post {
+        Response::from_string(format!("{} only accepts POST", OPENC2_PATH))
+            .with_status_code(405)
+            .with_header(header("Allow", "POST"))
+    } else {
+        let mut command = String::new();
+        match request.as_reader().read_to_string(&mut command) {
+            Ok(_) => run(command, events, response_timeout),
+            Err(e) => Response::from_string(format!("Could not read the request: {}", e))
+                .with_status_code(400),
+        }
+    };
+
+    if let Err(e) = request.respond(response) {
+        eprintln!(
+            "Could not answer an HTTP request, because of this error: {}",
+            e
+        );
+    }
+    let _ = permits.try_recv();
+}
+
+/// Dispatches a command and waits for the responses to it.
+fn run(
+    command: String,
+    events: &Sender<Event>,
+    response_timeout: Duration,
+) -> Response<io::Cursor<Vec<u8>>> {
+    let (responses_tx, responses_rx) = unbounded::<OpenC2Response>();
+    let remote = RemoteCommand {
+        transport: "http",
+        command,
+        responses: responses_tx,
+    };
+    if events.send(Event::Remote(remote)).is_err() {
+        return Response::from_string("the application is shutting down").with_status_code(503);
+    }
+
+    let deadline = Instant::now() + response_timeout;
+    let mut responses = Vec::<OpenC2Response>::new();
+    let mut timed_out = false;
+    loop {
+        match responses_rx.recv_deadline(deadline) {
+            Ok(response) => responses.push(response),
+            Err(RecvTimeoutError::Timeout) => {
+                timed_out = true;
+                break;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    let responses = pick_responses(responses);
+    let body = match responses.len() {
+        0 if timed_out => {
+            return Response::from_string(format!("no response within {:?}", response_timeout))
+                .with_status_code(504)
+        }
+        0 => return Response::from_string("").with_status_code(204),
+        1 => serde_json::to_string(&responses[0]),
+        _ => serde_json::to_string(&responses),
+    };
+    match body {
+        Ok(body) => Response::from_string(body)
+            .with_status_code(200)
+            .with_header(header("Content-Type", OPENC2_CONTENT_TYPE)),
+        Err(e) => Response::from_string(format!("Could not encode the response: {}", e))
+            .with_status_code(500),
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("the header is valid")
+}
+
+fn is_processing(response: &OpenC2Response) -> bool {
+    let (processing, _) = OpenC2RespStatus::Processing.into();
+    response.get_status() == processing
+}
+
+/// Picks what a request is answered with from the responses to its command:
+/// the final responses of the plugins that handled it,
+/// or the last `102 Processing` response if none of them finished.
+fn pick_responses(mut responses: Vec<OpenC2Response>) -> Vec<OpenC2Response> {
+    if responses.iter().all(is_processing) {
+        return responses.pop().into_iter().collect();
+    }
+    responses.retain(|response| !is_processing(response));
+    responses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    fn response(status: OpenC2RespStatus, sender: &str) -> OpenC2Response {
+        OpenC2Response::new_status(vec![], "42", sender, status, "")
+    }
+
+    #[test]
+    fn test_pick_responses() {
+        let picked = pick_responses(vec![
+            response(OpenC2RespStatus::Processing, "plugin_fw"),
+            response(OpenC2RespStatus::OK, "plugin_fw"),
+            response(OpenC2RespStatus::Processing, "plugin_server"),
+        ]);
+        assert_eq!(picked, vec![response(OpenC2RespStatus::OK, "plugin_fw")]);
+
+        let picked = pick_responses(vec![response(OpenC2RespStatus::Processing, "plugin_fw")]);
+        assert_eq!(picked.len(), 1);
+        assert!(pick_responses(vec![]).is_empty());
+    }
+
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).unwrap();
+        answer
+    }
+
+    #[test]
+    fn test_post_openc2() {
+        let (events_tx, events_rx) = unbounded::<Event>();
+        let addr = serve("127.0.0.1:0", events_tx, Duration::from_secs(5), 4).unwrap();
+
+        // Stands in for the event loop, answering every command like a plugin would.
+        thread::spawn(move || {
+            for event in events_rx.iter() {
+                if let Event::Remote(remote) = event {
+                    let command = serde_json::from_str::<serde_json::Value>(&remote.command);
+                    assert!(command.is_ok());
+                    let _ = remote
+                        .responses
+                        .send(response(OpenC2RespStatus::Processing, "plugin_fw"));
+                    let _ = remote.responses.send(OpenC2Response::new(
+                        vec![json!({"ip": "192.168.1.1"})],
+                        "42",
+                        "plugin_fw",
+                    ));
+                }
+            }
+        });
+
+        let answer = request(addr, "POST", OPENC2_PATH, r#"{"header": {}}"#);
+        assert!(answer.starts_with("HTTP/1.1 200"), "{}", answer);
+        assert!(answer.contains(OPENC2_CONTENT_TYPE), "{}", answer);
+        assert!(
+            answer.contains(r#""results":[{"ip":"192.168.1.1"}]"#),
+            "{}",
+            answer
+        );
+        assert!(!answer.contains(r#""status":102"#), "{}", answer);
+
+        let answer = request(addr, "GET", OPENC2_PATH, "");
+        assert!(answer.starts_with("HTTP/1.1 405"), "{}", answer);
+        let answer = request(addr, "POST", "/commands", "{}");
+        assert!(answer.starts_with("HTTP/1.1 404"), "{}", answer);
+    }
+
+    #[test]
+    fn test_requests_over_the_limit_are_answered_with_503() {
+        let (events_tx, events_rx) = unbounded::<Event>();
+        let addr = serve("127.0.0.1:0", events_tx, Duration::from_secs(5), 1).unwrap();
+
+        // Holds the first command until the second request was answered.
+        let first = thread::spawn(move || request(addr, "POST", OPENC2_PATH, "{}"));
+        let remote = match events_rx.recv().unwrap() {
+            Event::Remote(remote) => remote,
+            _ => panic!("expected a command"),
+        };
+        let answer = request(addr, "POST", OPENC2_PATH, "{}");
+        assert!(answer.starts_with("HTTP/1.1 503"), "{}", answer);
+
+        let _ = remote
+            .responses
+            .send(response(OpenC2RespStatus::OK, "plugin_fw"));
+        drop(remote);
+        let answer = first.join().unwrap();
+        assert!(answer.starts_with("HTTP/1.1 200"), "{}", answer);
+
+        // The permit of the first request is freed right after it was answered.
+        thread::spawn(move || {
+            for event in events_rx.iter() {
+                if let Event::Remote(remote) = event {
+                    let _ = remote
+                        .responses
+                        .send(response(OpenC2RespStatus::OK, "plugin_fw"));
+                }
+            }
+        });
+        let started = Instant::now();
+        let answer = loop {
+            let answer = request(addr, "POST", OPENC2_PATH, "{}");
+            if !answer.starts_with("HTTP/1.1 503") || started.elapsed() > Duration::from_secs(5) {
+                break answer;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(answer.starts_with("HTTP/1.1 200"), "{}", answer);
+    }
+}
@@ -0,0 +1,290 @@
+//! The MQTT transport, subscribing to the topics OpenC2 commands are published on
+//! and publishing the `OpenC2Response`s to them, as they come,
+//! to a topic named after the `sender` of the command.
+
+use common::openc2::response::OpenC2Response;
+use crossbeam_channel::{unbounded, Sender};
+use rumqttc::{Client, Event as MqttEvent, MqttOptions, Packet, QoS};
+use std::{io, thread, time::Duration};
+
+use super::RemoteCommand;
+use crate::{runtime::Event, utils::config::MqttConfig};
+
+/// How long to wait before connecting to the broker again after losing the connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Starts connecting to the broker, subscribing to the command topics every time it connects.
+pub fn serve(config: &MqttConfig, events: Sender<Event>) -> io::Result<()> {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    let (client, mut connection) = Client::new(options, 64);
+    let command_topics = config.command_topics.clone();
+    let response_topic = config.response_topic.clone();
+
+    thread::Builder::new()
+        .name("mqtt".into())
+        .spawn(move || {
+            for notification in connection.iter() {
+                match notification {
+                    Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                        for topic in &command_topics {
+                            if let Err(e) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                                eprintln!(
+                                    "Could not subscribe to {:?}, because of this error: {}",
+                                    topic, e
+                                );
+                            }
+                        }
+                    }
+                    Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+                        let command = String::from_utf8_lossy(&publish.payload).into_owned();
+                        let topic = response_topic_for(&response_topic, &command);
+                        if !run(command, topic, &client, &events) {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("Lost the connection to the MQTT broker: {}", e);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        })
+        .map(drop)
+}
+
+/// Dispatches a command, publishing the responses to it on their own thread.
+///
+/// Returns false if the application is shutting down.
+fn run(command: String, topic: String, client: &Client, events: &Sender<Event>) -> bool {
+    let (responses_tx, responses_rx) = unbounded::<OpenC2Response>();
+    let remote = RemoteCommand {
+        transport: "mqtt",
+        command,
+        responses: responses_tx,
+    };
+    if events.send(Event::Remote(remote)).is_err() {
+        return false;
+    }
+
+    let client = client.clone();
+    let res = thread::Builder::new()
+        .name("mqtt-responses".into())
+        .spawn(move || {
+            for response in responses_rx.iter() {
+                let published = serde_json::to_vec(&response)
+                    .map_err(|e| e.to_string())
+                    .and_then(|payload| {
+                        client
+                            .publish(&topic, QoS::AtLeastOnce, false, payload)
+                            .map_err(|e| e.to_string())
+                    });
+                if let Err(e) = published {
+                    eprintln!(
+                        "Could not publish a response to {:?}, because of this error: {}",
+                        topic, e
+                    );
+                }
+            }
+        });
+    if let Err(e) = res {
+        eprintln!(
+            "Could not publish the responses to a command, because of this error: {}",
+            e
+        );
+    }
+    true
+}
+
+/// Fills in the `sender` of a command in the response topic,
+/// leaving out its level when the command has none,
+/// or one that isn't a single topic level: with a `/`, a wildcard or a NUL.
+fn response_topic_for(response_topic: &str, command: &str) -> String {
+    let sender = serde_json::from_str::<serde_json::Value>(command)
+        .ok()
+        .and_then(|command| command["header"]["sender"].as_str().map(String::from))
+        .filter(|sender| !sender.is_empty() && !sender.contains(['/', '+', '#', '\0']));
+    match sender {
+        Some(sender) => response_topic.replace("{sender}", &sender),
+        None => response_topic
+            .replace("/{sender}", "")
+            .replace("{sender}", ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use common::openc2::response::OpenC2RespStatus;
+    use rumqttc::{
+        mqttbytes::{self, v4},
+        ConnAck, ConnectReturnCode, PingResp, PubAck, Publish, SubAck, SubscribeReasonCode,
+    };
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    type Subscriptions = Arc<Mutex<Vec<(String, Arc<Mutex<TcpStream>>)>>>;
+
+    /// Stands in for a broker, forwarding every publish to the matching subscriptions.
+    fn broker() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let subscriptions = Subscriptions::default();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let subscriptions = subscriptions.clone();
+                thread::spawn(move || broker_connection(stream.unwrap(), subscriptions));
+            }
+        });
+        addr
+    }
+
+    fn broker_connection(mut stream: TcpStream, subscriptions: Subscriptions) {
+        let writer = Arc::new(Mutex::new(stream.try_clone().unwrap()));
+        let send = |writer: &Mutex<TcpStream>, packet: BytesMut| {
+            let _ = writer.lock().unwrap().write_all(&packet);
+        };
+        let mut incoming = BytesMut::new();
+        let mut chunk = [0; 4096];
+        loop {
+            let packet = match v4::read(&mut incoming, 1 << 20) {
+                Ok(x) => x,
+                Err(mqttbytes::Error::InsufficientBytes(_)) => match stream.read(&mut chunk) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => {
+                        incoming.extend_from_slice(&chunk[..n]);
+                        continue;
+                    }
+                },
+                Err(e) => panic!("invalid packet: {:?}", e),
+            };
+
+            let mut out = BytesMut::new();
+            match packet {
+                v4::Packet::Connect(_) => {
+                    ConnAck::new(ConnectReturnCode::Success, false)
+                        .write(&mut out)
+                        .unwrap();
+                }
+                v4::Packet::Subscribe(subscribe) => {
+                    let mut codes = vec![];
+                    for filter in subscribe.filters {
+                        codes.push(SubscribeReasonCode::Success(filter.qos));
+                        subscriptions
+                            .lock()
+                            .unwrap()
+                            .push((filter.path, writer.clone()));
+                    }
+                    SubAck::new(subscribe.pkid, codes).write(&mut out).unwrap();
+                }
+                v4::Packet::Publish(publish) => {
+                    if publish.qos != QoS::AtMostOnce {
+                        PubAck::new(publish.pkid).write(&mut out).unwrap();
+                    }
+                    let forwarded = Publish::new(
+                        publish.topic.clone(),
+                        QoS::AtMostOnce,
+                        publish.payload.to_vec(),
+                    );
+                    for (filter, subscriber) in subscriptions.lock().unwrap().iter() {
+                        if rumqttc::matches(&publish.topic, filter) {
+                            let mut packet = BytesMut::new();
+                            forwarded.write(&mut packet).unwrap();
+                            send(subscriber, packet);
+                        }
+                    }
+                }
+                v4::Packet::PingReq => {
+                    PingResp.write(&mut out).unwrap();
+                }
+                v4::Packet::Disconnect => return,
+                _ => {}
+            }
+            if !out.is_empty() {
+                send(&writer, out);
+            }
+        }
+    }
+
+    #[test]
+    fn test_response_topic_for() {
+        let command = r#"{"header": {"sender": "gateway"}}"#;
+        assert_eq!(
+            response_topic_for("oc2/rsp/{sender}", command),
+            "oc2/rsp/gateway"
+        );
+        assert_eq!(response_topic_for("oc2/rsp/{sender}", "{}"), "oc2/rsp");
+        assert_eq!(response_topic_for("oc2/rsp", command), "oc2/rsp");
+        for sender in ["../cmd", "a/b", "+", "#", "gate\\u0000way"] {
+            let command = format!(r#"{{"header": {{"sender": "{}"}}}}"#, sender);
+            assert_eq!(
+                response_topic_for("oc2/rsp/{sender}", &command),
+                "oc2/rsp",
+                "{}",
+                sender
+            );
+        }
+    }
+
+    #[test]
+    fn test_commands_are_answered_on_the_response_topic() {
+        let addr = broker();
+        let config = MqttConfig {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            ..MqttConfig::default()
+        };
+        let (events_tx, events_rx) = unbounded::<Event>();
+        serve(&config, events_tx).unwrap();
+
+        // Stands in for the event loop, answering every command like a plugin would.
+        thread::spawn(move || {
+            for event in events_rx.iter() {
+                if let Event::Remote(remote) = event {
+                    let _ = remote.responses.send(OpenC2Response::new_status(
+                        vec![],
+                        "42",
+                        "plugin_fw",
+                        OpenC2RespStatus::OK,
+                        "",
+                    ));
+                }
+            }
+        });
+
+        let mut options = MqttOptions::new("test-producer", addr.ip().to_string(), addr.port());
+        options.set_keep_alive(Duration::from_secs(30));
+        let (client, mut connection) = Client::new(options, 16);
+        client.subscribe("oc2/rsp/#", QoS::AtMostOnce).unwrap();
+
+        // The command is published until the transport has subscribed and answers it.
+        let command = r#"{"header": {"request_id": "42", "sender": "gateway"}}"#;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut last_publish = None::<Instant>;
+        let response = loop {
+            assert!(Instant::now() < deadline, "no response to the command");
+            if last_publish.is_none_or(|at| at.elapsed() > Duration::from_millis(200)) {
+                client
+                    .publish("oc2/cmd/all", QoS::AtLeastOnce, false, command)
+                    .unwrap();
+                last_publish = Some(Instant::now());
+            }
+            match connection.recv_timeout(Duration::from_millis(50)) {
+                Ok(Ok(MqttEvent::Incoming(Packet::Publish(publish)))) => break publish,
+                Ok(Err(e)) => panic!("lost the connection to the broker: {}", e),
+                _ => {}
+            }
+        };
+
+        assert_eq!(response.topic, "oc2/rsp/gateway");
+        let response = String::from_utf8(response.payload.to_vec()).unwrap();
+        assert!(response.contains(r#""request_id":"42""#), "{}", response);
+        assert!(response.contains(r#""status":200"#), "{}", response);
+    }
+}
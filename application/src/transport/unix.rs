@@ -0,0 +1,212 @@
+//! A Unix socket where every connection streams `OpenC2Command`s, one JSON value per line,
+//! and gets the `OpenC2Response`s to them back the same way, as they come.
+//!
+//! Responses carry the `request_id` of their command,
+//! which is how clients match them when several commands are running.
+
+use common::openc2::response::OpenC2Response;
+use crossbeam_channel::{bounded, unbounded, Receiver, Select, Sender};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    thread,
+};
+
+use super::RemoteCommand;
+use crate::runtime::Event;
+
+/// Starts listening on a Unix socket at `path`,
+/// replacing a socket file left over by a process that's gone.
+///
+/// A connection stops being read from while `max_in_flight` of its commands are running.
+pub fn serve(path: &Path, events: Sender<Event>, max_in_flight: usize) -> io::Result<()> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another process", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let max_in_flight = max_in_flight.max(1);
+
+    thread::Builder::new()
+        .name("unix-socket".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(x) => x,
+                    Err(e) => {
+                        eprintln!(
+                            "Could not accept a connection, because of this error: {}",
+                            e
+                        );
+                        continue;
+                    }
+                };
+                let events = events.clone();
+                let res = thread::Builder::new()
+                    .name("unix-connection".into())
+                    .spawn(move || handle_connection(stream, events, max_in_flight));
+                if let Err(e) = res {
+                    eprintln!(
+                        "Could not handle a connection, because of this error: {}",
+                        e
+                    );
+                }
+            }
+        })
+        .map(drop)
+}
+
+/// Reads the commands of a connection, while another thread writes the responses to them.
+fn handle_connection(stream: UnixStream, events: Sender<Event>, max_in_flight: usize) {
+    // Holds a message for every running command, so sending blocks once there are too many.
+    let (permits_tx, permits_rx) = bounded::<()>(max_in_flight);
+    let (commands_tx, commands_rx) = unbounded::<Receiver<OpenC2Response>>();
+
+    let writer = match stream.try_clone().and_then(|writer| {
+        thread::Builder::new()
+            .name("unix-responses".into())
+            .spawn(move || write_responses(writer, commands_rx, permits_rx))
+    }) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!(
+                "Could not handle a connection, because of this error: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let command = match line {
+            Ok(x) => x,
+            Err(_) => break,
+        };
+        if command.trim().is_empty() {
+            continue;
+        }
+        if permits_tx.send(()).is_err() {
+            break;
+        }
+        let (responses_tx, responses_rx) = unbounded::<OpenC2Response>();
+        if commands_tx.send(responses_rx).is_err() {
+            break;
+        }
+        let remote = RemoteCommand {
+            transport: "unix",
+            command,
+            responses: responses_tx,
+        };
+        if events.send(Event::Remote(remote)).is_err() {
+            break;
+        }
+    }
+
+    // The responses to the commands that are still running are written before closing.
+    drop(commands_tx);
+    let _ = writer.join();
+}
+
+/// Writes the responses to the commands of a connection as they come,
+/// freeing a permit every time a command is done.
+fn write_responses(
+    mut stream: UnixStream,
+    commands: Receiver<Receiver<OpenC2Response>>,
+    permits: Receiver<()>,
+) {
+    let mut running = Vec::<Receiver<OpenC2Response>>::new();
+    let mut reading = true;
+
+    while reading || !running.is_empty() {
+        let mut select = Select::new();
+        let new_command = reading.then(|| select.recv(&commands));
+        let first_running = usize::from(reading);
+        for responses in &running {
+            select.recv(responses);
+        }
+
+        let op = select.select();
+        let index = op.index();
+        if Some(index) == new_command {
+            match op.recv(&commands) {
+                Ok(responses) => running.push(responses),
+                Err(_) => reading = false,
+            }
+            continue;
+        }
+
+        let i = index - first_running;
+        match op.recv(&running[i]) {
+            Ok(response) => {
+                let written = serde_json::to_vec(&response)
+                    .map_err(io::Error::from)
+                    .and_then(|mut line| {
+                        line.push(b'\n');
+                        stream.write_all(&line)
+                    });
+                if written.is_err() {
+                    return;
+                }
+            }
+            Err(_) => {
+                running.swap_remove(i);
+                let _ = permits.recv();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_connection_streams_responses_with_backpressure() {
+        let path = std::env::temp_dir().join(format!("openc2-test-{}.sock", std::process::id()));
+        let (events_tx, events_rx) = unbounded::<Event>();
+        serve(&path, events_tx, 1).unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"{\"n\": 1}\n\n{\"n\": 2}\n").unwrap();
+
+        let first = match events_rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Event::Remote(remote)) => remote,
+            _ => panic!("expected the first command"),
+        };
+        assert_eq!(first.command, r#"{"n": 1}"#);
+        first
+            .responses
+            .send(OpenC2Response::new(vec![], "1", "plugin_fw"))
+            .unwrap();
+        // The second command waits for the first one, since only one may run at a time.
+        assert!(events_rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(first);
+
+        let second = match events_rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Event::Remote(remote)) => remote,
+            _ => panic!("expected the second command"),
+        };
+        second
+            .responses
+            .send(OpenC2Response::new(vec![], "2", "plugin_fw"))
+            .unwrap();
+        drop(second);
+
+        let mut lines = BufReader::new(client).lines();
+        let first = lines.next().unwrap().unwrap();
+        let second = lines.next().unwrap().unwrap();
+        assert!(first.contains(r#""request_id":"1""#), "{}", first);
+        assert!(second.contains(r#""request_id":"2""#), "{}", second);
+
+        assert!(serve(&path, unbounded().0, 1).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
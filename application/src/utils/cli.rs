@@ -0,0 +1,53 @@
+use crate::utils::log::Level;
+use clap::Parser;
+use std::path::PathBuf;
+
+/// Environment variable holding extra plugin search directories,
+/// separated like `PATH` (`:` on unix).
+pub const PLUGIN_PATH_ENV: &str = "PLUGIN_PATH";
+
+#[derive(Debug, Clone, Parser)]
+#[clap(
+    version,
+    about = "Loads the configured plugins and sends them OpenC2 commands"
+)]
+pub struct Opts {
+    /// The config file, in JSON, TOML or YAML depending on its extension.
+    #[clap(
+        short,
+        long,
+        value_name = "FILE",
+        default_value = "./data/app_config.json"
+    )]
+    pub config: PathBuf,
+
+    /// Directory to search for plugin libraries, can be repeated.
+    /// Searched before the directories from `PLUGIN_PATH` and the config file.
+    #[clap(long = "plugin-dir", value_name = "DIR")]
+    pub plugin_dirs: Vec<PathBuf>,
+
+    /// Runs the commands from the config file and exits once the plugins are idle,
+    /// instead of running as a service until SIGINT/SIGTERM.
+    #[clap(long)]
+    pub oneshot: bool,
+
+    /// Checks the config file and the commands the plugins declare, then exits,
+    /// with an error if there's any problem.
+    /// Problems are printed with their path, and with their line and column for JSON files only.
+    #[clap(long, conflicts_with = "oneshot")]
+    pub validate: bool,
+
+    /// The least important messages that are printed, errors are always printed.
+    #[clap(long, arg_enum, value_name = "LEVEL", default_value = "info")]
+    pub log_level: Level,
+}
+
+/// Returns the directories listed in the `PLUGIN_PATH` environment variable.
+pub fn env_plugin_dirs() -> Vec<PathBuf> {
+    match std::env::var_os(PLUGIN_PATH_ENV) {
+        Some(paths) => std::env::split_paths(&paths)
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect(),
+        None => Vec::new(),
+    }
+}
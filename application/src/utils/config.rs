@@ -1,26 +1,558 @@
-use crate::error::RResult;
+use crate::error::{RError, RErrorKind, RResult};
 use crate::plugin::PluginToLoad;
+use crate::router::Route;
+use crate::script::Step;
 use crate::utils::vec_from_map::VecFromMap;
 
 use abi_stable::std_types::RVec;
 use common::PluginId;
-use lazy_static::lazy_static;
 use serde::Deserialize;
 use serde_json::value::RawValue;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+use template::Variables;
 
-lazy_static! {
-    pub static ref CONFIG: Config = load().unwrap();
-}
+pub mod template;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
+    /// Directories searched for plugin libraries,
+    /// after the ones passed on the command line and in `PLUGIN_PATH`.
+    #[serde(default)]
+    pub plugin_dirs: Vec<PathBuf>,
     pub plugins: RVec<PluginToLoad>,
+    #[serde(default)]
+    pub watch: WatchConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub restart: RestartConfig,
+    #[serde(default)]
+    pub correlation: CorrelationConfig,
+    #[serde(default)]
+    pub batch: BatchConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub journal: JournalConfig,
+    #[serde(default)]
+    pub dedup: DedupConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub unix_socket: UnixSocketConfig,
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    /// The values of the `{{name}}` placeholders in the commands, see `template`.
+    #[serde(default)]
+    pub variables: BTreeMap<String, serde_json::Value>,
     pub commands: VecFromMap<PluginId, Box<RawValue>>,
+    /// Registers plugins for the commands addressed to some actuators.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// OpenC2 commands sent to the plugins registered for their actuator in `routes`.
+    #[serde(default)]
+    pub dispatch: Vec<Box<RawValue>>,
+    /// Commands sent one after the other, once the ones above were sent, see `script`.
+    #[serde(default)]
+    pub script: Vec<Step>,
+}
+
+impl Config {
+    /// Fills in the placeholders of the commands, the dispatched commands
+    /// and the commands of the script, see `template`.
+    ///
+    /// Fails with every placeholder that has no value, so no command is sent with one.
+    pub fn render_commands(&mut self) -> RResult<()> {
+        let mut missing = Vec::<String>::new();
+        let mut render = |command: &mut Box<RawValue>, path: String| -> RResult<()> {
+            let mut unresolved = BTreeSet::new();
+            *command =
+                template::render(command, &Variables::new(&self.variables), &mut unresolved)?;
+            if !unresolved.is_empty() {
+                let names = unresolved
+                    .iter()
+                    .map(|name| format!("{{{{{}}}}}", name))
+                    .collect::<Vec<String>>();
+                missing.push(format!("{} in {}", names.join(", "), path));
+            }
+            Ok(())
+        };
+        for (plugin_id, command) in &mut self.commands.vec {
+            render(command, format!("commands.{}", plugin_id))?;
+        }
+        for (i, command) in self.dispatch.iter_mut().enumerate() {
+            render(command, format!("dispatch[{}]", i))?;
+        }
+        for (i, step) in self.script.iter_mut().enumerate() {
+            render(&mut step.command, format!("script[{}].command", i))?;
+        }
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(RError::new(
+                RErrorKind::FileInvalid,
+                format!("placeholders without a value: {}", missing.join("; ")),
+            ))
+        }
+    }
+}
+
+/// Settings for reloading plugins when their library changes on disk,
+/// and the config when the config file changes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatchConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Whether the config file is loaded again when it changes, see `reload`.
+    #[serde(default)]
+    pub config: bool,
+    /// How often the libraries and the config file are checked for changes.
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+}
+
+fn default_poll_interval_ms() -> u64 {
+    1000
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            config: false,
+            poll_interval_ms: default_poll_interval_ms(),
+        }
+    }
+}
+
+/// Settings for stopping the application and unloading plugins.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShutdownConfig {
+    /// How long the queued commands and responses are handled for before closing the plugins.
+    #[serde(default = "default_drain_timeout_ms")]
+    pub drain_timeout_ms: u64,
+    /// How long each plugin gets to close.
+    #[serde(default = "default_close_timeout_ms")]
+    pub close_timeout_ms: u64,
+}
+
+fn default_drain_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_close_timeout_ms() -> u64 {
+    2000
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_ms: default_drain_timeout_ms(),
+            close_timeout_ms: default_close_timeout_ms(),
+        }
+    }
+}
+
+/// Settings for constructing plugins again after they panic or fail to construct.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RestartConfig {
+    /// How long to wait before the first attempt, doubled after every failed attempt.
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// The longest to wait between two attempts.
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_backoff_ms() -> u64 {
+    30_000
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+        }
+    }
+}
+
+/// Settings for matching responses to the commands that caused them, see `correlation`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CorrelationConfig {
+    /// How long a command is tracked without any response, past its deadline.
+    #[serde(default = "default_stale_after_ms")]
+    pub stale_after_ms: u64,
+}
+
+fn default_stale_after_ms() -> u64 {
+    300_000
+}
+
+impl Default for CorrelationConfig {
+    fn default() -> Self {
+        Self {
+            stale_after_ms: default_stale_after_ms(),
+        }
+    }
+}
+
+/// Settings for the commands addressed to several actuators, see `batch`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchConfig {
+    /// How many actuators of a command are handled at the same time,
+    /// by different plugins.
+    #[serde(default = "default_max_concurrency")]
+    pub max_concurrency: usize,
+}
+
+fn default_max_concurrency() -> usize {
+    8
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: default_max_concurrency(),
+        }
+    }
+}
+
+/// Settings for the commands addressed to the host, see `admin`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AdminConfig {
+    /// The library plugins that may send admin commands to the host,
+    /// process plugins never may.
+    #[serde(default)]
+    pub allowed_plugins: Vec<PluginId>,
+}
+
+/// Settings for recording the commands handed to plugins, see `journal`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct JournalConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_journal_path")]
+    pub path: PathBuf,
+}
+
+fn default_journal_path() -> PathBuf {
+    PathBuf::from("./data/journal.log")
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_journal_path(),
+        }
+    }
+}
+
+/// Settings for answering the commands that transports send again, see `dedup`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DedupConfig {
+    /// How long a command is remembered, 0 to run every command that's sent again.
+    #[serde(default = "default_dedup_window_ms")]
+    pub window_ms: u64,
+}
+
+fn default_dedup_window_ms() -> u64 {
+    60_000
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            window_ms: default_dedup_window_ms(),
+        }
+    }
+}
+
+/// Settings for the HTTP API, see `transport::http`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HttpConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The address to listen on.
+    #[serde(default = "default_http_listen")]
+    pub listen: String,
+    /// How long a request waits for the responses to its command.
+    #[serde(default = "default_response_timeout_ms")]
+    pub response_timeout_ms: u64,
+    /// How many requests are handled at the same time, the others are answered with 503.
+    #[serde(default = "default_max_requests")]
+    pub max_requests: usize,
+}
+
+fn default_http_listen() -> String {
+    "127.0.0.1:8080".into()
+}
+
+fn default_response_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_max_requests() -> usize {
+    32
 }
 
-pub fn load() -> RResult<Config> {
-    let path = { "./data/app_config.json".to_string() };
-    let file_contents = std::fs::read_to_string(&path)?;
-    let config = serde_json::from_str(&file_contents)?;
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: default_http_listen(),
+            response_timeout_ms: default_response_timeout_ms(),
+            max_requests: default_max_requests(),
+        }
+    }
+}
+
+/// Settings for the Unix socket command channel, see `transport::unix`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UnixSocketConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_socket_path")]
+    pub path: PathBuf,
+    /// How many commands of a connection may run at the same time.
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+}
+
+fn default_socket_path() -> PathBuf {
+    PathBuf::from("./data/openc2.sock")
+}
+
+fn default_max_in_flight() -> usize {
+    16
+}
+
+impl Default for UnixSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_socket_path(),
+            max_in_flight: default_max_in_flight(),
+        }
+    }
+}
+
+/// Settings for the MQTT transport, see `transport::mqtt`,
+/// which is only built with the `mqtt` feature.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MqttConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The address of the broker.
+    #[serde(default = "default_mqtt_host")]
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    /// The topics commands are received on, they may contain wildcards.
+    #[serde(default = "default_command_topics")]
+    pub command_topics: Vec<String>,
+    /// The topic responses are published to,
+    /// where `{sender}` is replaced with the `sender` in the header of the command.
+    #[serde(default = "default_response_topic")]
+    pub response_topic: String,
+}
+
+fn default_mqtt_host() -> String {
+    "127.0.0.1".into()
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "openc2-host".into()
+}
+
+fn default_command_topics() -> Vec<String> {
+    vec!["oc2/cmd/all".into()]
+}
+
+fn default_response_topic() -> String {
+    "oc2/rsp/{sender}".into()
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: default_mqtt_host(),
+            port: default_mqtt_port(),
+            client_id: default_mqtt_client_id(),
+            command_topics: default_command_topics(),
+            response_topic: default_response_topic(),
+        }
+    }
+}
+
+/// The formats a config file can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Returns the format of a file from its extension.
+    pub fn of(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the config file at `path`, in the format given by its extension.
+pub fn load(path: &Path) -> RResult<Config> {
+    let format = Format::of(path).ok_or_else(|| {
+        RError::new(
+            RErrorKind::FileInvalid,
+            format!(
+                "{}: unknown config format, expected a .json, .toml, .yaml or .yml file",
+                path.display()
+            ),
+        )
+    })?;
+    load_file(path, format).map_err(|e| e.context(&path.display().to_string()))
+}
+
+fn load_file(path: &Path, format: Format) -> RResult<Config> {
+    let file_contents = std::fs::read_to_string(path)?;
+    let mut config = parse(&file_contents, format)?;
+    config.render_commands()?;
     Ok(config)
 }
+
+/// Parses a config written in `format`.
+///
+/// TOML and YAML configs are converted to JSON first,
+/// since the commands are kept as JSON until they're sent to a plugin.
+pub fn parse(file_contents: &str, format: Format) -> RResult<Config> {
+    let json = match format {
+        Format::Json => return Ok(serde_json::from_str(file_contents)?),
+        Format::Toml => serde_json::to_string(&toml::from_str::<toml::Value>(file_contents)?)?,
+        Format::Yaml => {
+            serde_json::to_string(&serde_yaml::from_str::<serde_yaml::Value>(file_contents)?)?
+        }
+    };
+    Ok(serde_json::from_str(&json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_formats_give_the_same_config() {
+        let json = parse(
+            r#"{
+                "plugins": [{"name": "plugin_fw"}],
+                "http": {"enabled": true},
+                "commands": {
+                    "plugin_fw": {"header": {"request_id": "1"}},
+                    "plugin_server": {"header": {"request_id": "2"}}
+                }
+            }"#,
+            Format::Json,
+        )
+        .unwrap();
+        let toml = parse(
+            r#"
+                plugins = [{name = "plugin_fw"}]
+                [http]
+                enabled = true
+                [commands.plugin_fw.header]
+                request_id = "1"
+                [commands.plugin_server.header]
+                request_id = "2"
+            "#,
+            Format::Toml,
+        )
+        .unwrap();
+        let yaml = parse(
+            "
+plugins:
+  - name: plugin_fw
+http:
+  enabled: true
+commands:
+  plugin_fw:
+    header: {request_id: '1'}
+  plugin_server:
+    header: {request_id: '2'}
+",
+            Format::Yaml,
+        )
+        .unwrap();
+
+        for config in [&json, &toml, &yaml] {
+            assert!(config.http.enabled);
+            assert_eq!(config.plugins.len(), 1);
+            let commands = config
+                .commands
+                .vec
+                .iter()
+                .map(|(plugin_id, command)| {
+                    let command = serde_json::from_str::<serde_json::Value>(command.get());
+                    (plugin_id.as_str(), command.unwrap())
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                commands,
+                vec![
+                    ("plugin_fw", json!({"header": {"request_id": "1"}})),
+                    ("plugin_server", json!({"header": {"request_id": "2"}})),
+                ]
+            );
+        }
+
+        assert_eq!(
+            Format::of(Path::new("./data/app_config.yml")),
+            Some(Format::Yaml)
+        );
+        assert!(parse("plugins = [", Format::Toml).is_err());
+    }
+
+    #[test]
+    fn test_render_commands_lists_missing_placeholders() {
+        let mut config = parse(
+            r#"{
+                "plugins": [],
+                "variables": {"device_id": "fw-01"},
+                "commands": {"plugin_fw": {"header": {"request_id": "{{device_id}}"}}},
+                "dispatch": [{"header": {"request_id": "{{missing}}-{{device_id}}"}}]
+            }"#,
+            Format::Json,
+        )
+        .unwrap();
+        let e = config.render_commands().unwrap_err();
+        assert_eq!(
+            e.error_message,
+            "placeholders without a value: {{missing}} in dispatch[0]"
+        );
+        assert_eq!(
+            config.commands.vec[0].1.get(),
+            r#"{"header":{"request_id":"fw-01"}}"#
+        );
+    }
+}
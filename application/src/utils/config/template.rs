@@ -0,0 +1,175 @@
+//! Fills in the `{{name}}` placeholders in the strings of the configured commands.
+//!
+//! A placeholder gets its value from, by decreasing priority:
+//! the `variables` of the config, the environment variable of the same name,
+//! or one of the built-ins: `now_millis`, `uuid` and `hostname`.
+//!
+//! A string that is a single placeholder is replaced by its value, keeping its JSON type,
+//! like the number of `now_millis` in `"created": "{{now_millis}}"`.
+
+use crate::schedule::now_millis;
+use serde_json::{value::RawValue, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+/// Looks up the values of the placeholders of a command.
+pub struct Variables<'a> {
+    variables: &'a BTreeMap<String, Value>,
+    /// The same for every placeholder of a command, so its `request_id` can be repeated in it.
+    uuid: String,
+}
+
+impl<'a> Variables<'a> {
+    pub fn new(variables: &'a BTreeMap<String, Value>) -> Self {
+        Self {
+            variables,
+            uuid: Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        if let Some(value) = self.variables.get(name) {
+            return Some(value.clone());
+        }
+        if let Ok(value) = std::env::var(name) {
+            return Some(Value::String(value));
+        }
+        match name {
+            "now_millis" => Some(now_millis().into()),
+            "uuid" => Some(self.uuid.clone().into()),
+            "hostname" => gethostname::gethostname()
+                .into_string()
+                .ok()
+                .map(Value::from),
+            _ => None,
+        }
+    }
+}
+
+/// Fills in the placeholders of a command,
+/// adding the names of the ones that have no value to `unresolved`.
+pub fn render(
+    command: &RawValue,
+    variables: &Variables,
+    unresolved: &mut BTreeSet<String>,
+) -> serde_json::Result<Box<RawValue>> {
+    let mut command = serde_json::from_str::<Value>(command.get())?;
+    render_value(&mut command, variables, unresolved);
+    serde_json::value::to_raw_value(&command)
+}
+
+fn render_value(value: &mut Value, variables: &Variables, unresolved: &mut BTreeSet<String>) {
+    match value {
+        Value::String(text) if text.contains("{{") => {
+            let whole = placeholder(text).and_then(|name| variables.get(name));
+            match whole {
+                Some(whole) => *value = whole,
+                None => *text = render_str(text, variables, unresolved),
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                render_value(value, variables, unresolved);
+            }
+        }
+        Value::Object(values) => {
+            for value in values.values_mut() {
+                render_value(value, variables, unresolved);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns the name of the placeholder if it's the whole string.
+fn placeholder(text: &str) -> Option<&str> {
+    let name = text.strip_prefix("{{")?.strip_suffix("}}")?;
+    (!name.contains("{{") && !name.contains("}}")).then(|| name.trim())
+}
+
+/// Fills in the placeholders of a string, the ones that have no value are left as they are.
+///
+/// Strings are inserted without their quotes, other values as JSON.
+fn render_str(text: &str, variables: &Variables, unresolved: &mut BTreeSet<String>) -> String {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start + 2..].find("}}") {
+            Some(len) => start + 2 + len,
+            None => break,
+        };
+        rendered.push_str(&rest[..start]);
+        let name = rest[start + 2..end].trim();
+        match variables.get(name) {
+            Some(Value::String(value)) => rendered.push_str(&value),
+            Some(value) => rendered.push_str(&value.to_string()),
+            None => {
+                unresolved.insert(name.to_string());
+                rendered.push_str(&rest[start..end + 2]);
+            }
+        }
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let variables = BTreeMap::from([("device_id".to_string(), serde_json::json!("fw-01"))]);
+        let variables = Variables::new(&variables);
+        let command = serde_json::value::to_raw_value(&serde_json::json!({
+            "header": {"request_id": "{{device_id}}-{{ uuid }}", "created": 0},
+            "command": {
+                "actuator": {"actuator_id": ["{{device_id}}", "{{unknown}}"]},
+                "args": {"comment": "{{uuid}} {{ unclosed"}
+            }
+        }))
+        .unwrap();
+
+        let mut unresolved = BTreeSet::new();
+        let rendered = render(&command, &variables, &mut unresolved).unwrap();
+        let rendered = serde_json::from_str::<Value>(rendered.get()).unwrap();
+        let request_id = rendered["header"]["request_id"].as_str().unwrap();
+        assert_eq!(request_id, format!("fw-01-{}", variables.uuid));
+        assert_eq!(rendered["header"]["created"], 0);
+        assert_eq!(
+            rendered["command"]["actuator"]["actuator_id"],
+            serde_json::json!(["fw-01", "{{unknown}}"])
+        );
+        assert_eq!(
+            rendered["command"]["args"]["comment"],
+            format!("{} {{{{ unclosed", variables.uuid)
+        );
+        assert_eq!(unresolved, BTreeSet::from(["unknown".to_string()]));
+    }
+
+    #[test]
+    fn test_render_keeps_the_type_of_whole_placeholders() {
+        let variables = BTreeMap::from([
+            ("created".to_string(), serde_json::json!(1700000000000u64)),
+            ("device_id".to_string(), serde_json::json!("fw-01")),
+        ]);
+        let variables = Variables::new(&variables);
+        let command = serde_json::value::to_raw_value(&serde_json::json!({
+            "header": {"request_id": "{{device_id}}-{{created}}", "created": "{{ created }}"},
+            "command": {"actuator": {"actuator_id": ["{{device_id}}"]}}
+        }))
+        .unwrap();
+
+        let mut unresolved = BTreeSet::new();
+        let rendered = render(&command, &variables, &mut unresolved).unwrap();
+        let rendered = serde_json::from_str::<Value>(rendered.get()).unwrap();
+        assert_eq!(rendered["header"]["created"], 1700000000000u64);
+        assert_eq!(rendered["header"]["request_id"], "fw-01-1700000000000");
+        assert_eq!(
+            rendered["command"]["actuator"]["actuator_id"],
+            serde_json::json!(["fw-01"])
+        );
+        assert!(unresolved.is_empty());
+    }
+}
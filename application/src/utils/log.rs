@@ -0,0 +1,56 @@
+//! Filters the messages printed by the application by their level, see `--log-level`.
+//!
+//! Errors are printed to stderr with `eprintln!`, the other messages with the macros of this module.
+
+use clap::ArgEnum;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
+#[repr(u8)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether the messages of `level` are printed.
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Prints a warning to stderr, unless `--log-level` is `error`.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        if $crate::utils::log::enabled($crate::utils::log::Level::Warn) {
+            eprintln!($($arg)*);
+        }
+    }};
+}
+
+/// Prints a message about what the application does, like loading a plugin.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        if $crate::utils::log::enabled($crate::utils::log::Level::Info) {
+            println!($($arg)*);
+        }
+    }};
+}
+
+/// Prints a message that is only useful when looking into a problem.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        if $crate::utils::log::enabled($crate::utils::log::Level::Debug) {
+            println!($($arg)*);
+        }
+    }};
+}
@@ -1,2 +1,4 @@
+pub mod cli;
 pub mod config;
+pub mod log;
 pub mod vec_from_map;
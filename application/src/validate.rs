@@ -0,0 +1,415 @@
+//! Checks a config file without running anything, see `--validate`.
+//!
+//! Every configured command has to parse, be addressed to a plugin of `plugins`,
+//! and be declared by its plugin if the plugin declares the commands it supports.
+
+use abi_stable::std_types::RVec;
+use common::{
+    openc2::{
+        command::{OpenC2Action, OpenC2Command},
+        target::Target,
+    },
+    CommandDescription, PluginId,
+};
+use serde::de::DeserializeOwned;
+use serde_json::value::RawValue;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use self::locate::{fmt_path, locate, Segment};
+use crate::admin::{AdminCommand, HOST_ID};
+use crate::error::{RError, RErrorKind, RResult};
+use crate::plugin::{self, PluginToLoad};
+use crate::router::Router;
+use crate::utils::config::{Config, Format};
+
+pub mod locate;
+
+/// The commands that plugins declared to support,
+/// the plugins that are not in it support every command.
+pub type Capabilities = HashMap<PluginId, RVec<CommandDescription>>;
+
+/// Something wrong with the value at `path` in the config.
+#[derive(Debug)]
+pub struct Problem {
+    pub path: Vec<Segment>,
+    /// The line and column of the value, for JSON config files.
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+    /// Whether the config still works as intended, like with a command that's answered with 404.
+    pub warning: bool,
+}
+
+impl Problem {
+    fn new(path: Vec<Segment>, message: String) -> Self {
+        Self {
+            path,
+            position: None,
+            message,
+            warning: false,
+        }
+    }
+
+    fn warning(path: Vec<Segment>, message: String) -> Self {
+        Self {
+            warning: true,
+            ..Self::new(path, message)
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((line, column)) = self.position {
+            write!(f, "{}:{}: ", line, column)?;
+        }
+        if self.warning {
+            f.write_str("warning: ")?;
+        }
+        fmt_path(&self.path, f)?;
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Checks the config loaded from `path`, with the plugins found in `dirs`,
+/// printing every problem.
+///
+/// Only the problems of JSON files get a line and column,
+/// the TOML and YAML parsers don't tell where the values are, so they only get their path.
+pub fn run(path: &Path, config: &Config, dirs: &[PathBuf]) -> RResult<()> {
+    let mut problems = problems(config, dirs);
+
+    if Format::of(path) == Some(Format::Json) {
+        let text = std::fs::read_to_string(path)?;
+        for problem in &mut problems {
+            problem.position = Some(locate(&text, &problem.path));
+        }
+    }
+    for problem in &problems {
+        println!("{}:{}", path.display(), problem);
+    }
+
+    let errors = problems.iter().filter(|problem| !problem.warning).count();
+    if errors == 0 {
+        println!("{} is valid", path.display());
+        Ok(())
+    } else {
+        Err(RError::new(
+            RErrorKind::FileInvalid,
+            format!("{}: found {} problems", path.display(), errors),
+        ))
+    }
+}
+
+/// Returns every problem of the config, with the plugins found in `dirs`.
+pub fn problems(config: &Config, dirs: &[PathBuf]) -> Vec<Problem> {
+    let (capabilities, mut problems) = find_plugins(config, dirs);
+    problems.extend(check(config, &capabilities));
+    problems
+}
+
+/// Looks for the plugins of the config in `dirs`,
+/// returning the commands they declare and the ones that could not be found.
+fn find_plugins(config: &Config, dirs: &[PathBuf]) -> (Capabilities, Vec<Problem>) {
+    let discovery = plugin::discover(dirs);
+    let mut capabilities = Capabilities::new();
+    let mut problems = Vec::<Problem>::new();
+    for (i, plug) in config.plugins.iter().enumerate() {
+        let path = vec![Segment::Key("plugins".into()), Segment::Index(i)];
+        let named = match plug {
+            PluginToLoad::Named(named) | PluginToLoad::WithRename { named, .. } => named,
+            PluginToLoad::Process { process, .. } => {
+                if plugin::find_executable(process, dirs).is_none() {
+                    let message = format!(
+                        "could not find executable {}, searched in {:?}",
+                        process.display(),
+                        dirs
+                    );
+                    problems.push(Problem::new(path, message));
+                }
+                continue;
+            }
+        };
+        match discovery.libraries.get(named) {
+            Some(library) => {
+                if let Some(supported) = plugin::supported_commands(library.root_module) {
+                    capabilities.insert(plug.plugin_id(), supported);
+                }
+            }
+            None => {
+                let message = match discovery.library_errs.iter().find(|(x, ..)| x == named) {
+                    Some((_, library, e)) => {
+                        format!("could not load library {}: {}", library.display(), e)
+                    }
+                    None => format!("could not find library {}, searched in {:?}", named, dirs),
+                };
+                problems.push(Problem::new(path, message));
+            }
+        }
+    }
+    (capabilities, problems)
+}
+
+/// Checks the commands, the script and the routes of the config.
+pub fn check(config: &Config, capabilities: &Capabilities) -> Vec<Problem> {
+    let declared = config
+        .plugins
+        .iter()
+        .map(PluginToLoad::plugin_id)
+        .collect::<Vec<PluginId>>();
+    let router = Router::new(config.routes.clone());
+    let mut problems = Vec::<Problem>::new();
+
+    for (plugin_id, command) in &config.commands.vec {
+        let path = vec![
+            Segment::Key("commands".into()),
+            Segment::Key(plugin_id.to_string()),
+        ];
+        if plugin_id != HOST_ID && !declared.contains(plugin_id) {
+            let message = format!("{:?} is not one of the plugins", plugin_id);
+            problems.push(Problem::new(path.clone(), message));
+        }
+        check_command(plugin_id, command, &path, capabilities, &mut problems);
+    }
+
+    for (i, command) in config.dispatch.iter().enumerate() {
+        let path = vec![Segment::Key("dispatch".into()), Segment::Index(i)];
+        check_dispatched(&router, command, &path, capabilities, &mut problems);
+    }
+
+    for (i, step) in config.script.iter().enumerate() {
+        let path = |key: &str| {
+            vec![
+                Segment::Key("script".into()),
+                Segment::Index(i),
+                Segment::Key(key.into()),
+            ]
+        };
+        match &step.plugin {
+            Some(plugin_id) => {
+                if plugin_id != HOST_ID && !declared.contains(plugin_id) {
+                    let message = format!("{:?} is not one of the plugins", plugin_id);
+                    problems.push(Problem::new(path("plugin"), message));
+                }
+                check_command(
+                    plugin_id,
+                    &step.command,
+                    &path("command"),
+                    capabilities,
+                    &mut problems,
+                );
+            }
+            None => check_dispatched(
+                &router,
+                &step.command,
+                &path("command"),
+                capabilities,
+                &mut problems,
+            ),
+        }
+    }
+
+    for (i, route) in config.routes.iter().enumerate() {
+        if !declared.contains(&route.plugin) {
+            let path = vec![
+                Segment::Key("routes".into()),
+                Segment::Index(i),
+                Segment::Key("plugin".into()),
+            ];
+            let message = format!("{:?} is not one of the plugins", route.plugin);
+            problems.push(Problem::new(path, message));
+        }
+    }
+
+    problems
+}
+
+/// Checks a command sent to a plugin.
+fn check_command(
+    plugin_id: &PluginId,
+    command: &RawValue,
+    path: &[Segment],
+    capabilities: &Capabilities,
+    problems: &mut Vec<Problem>,
+) {
+    if plugin_id == HOST_ID {
+        if let Err(problem) = parse::<AdminCommand>(command, path) {
+            problems.push(problem);
+        }
+        return;
+    }
+    match parse::<OpenC2Command>(command, path) {
+        Ok(openc2) => {
+            if let Some(problem) = check_supported(&[plugin_id], &openc2, capabilities, path) {
+                problems.push(problem);
+            }
+        }
+        Err(problem) => problems.push(problem),
+    }
+}
+
+/// Checks a command sent to the plugins registered for it in `routes`.
+fn check_dispatched(
+    router: &Router,
+    command: &RawValue,
+    path: &[Segment],
+    capabilities: &Capabilities,
+    problems: &mut Vec<Problem>,
+) {
+    let openc2 = match parse::<OpenC2Command>(command, path) {
+        Ok(x) => x,
+        Err(problem) => {
+            problems.push(problem);
+            return;
+        }
+    };
+    let plugin_ids = router.route(&openc2);
+    if plugin_ids.is_empty() {
+        let message = "no route matches this command, it will be answered with 404".into();
+        problems.push(Problem::warning(path.to_vec(), message));
+        return;
+    }
+    // The host answers these for the plugins.
+    if let (OpenC2Action::Query, Target::Features(_)) = (openc2.get_action(), openc2.get_target()) {
+        return;
+    }
+    let plugin_ids = plugin_ids.iter().collect::<Vec<&PluginId>>();
+    if let Some(problem) = check_supported(&plugin_ids, &openc2, capabilities, path) {
+        problems.push(problem);
+    }
+}
+
+/// Parses a command, pointing at the value that could not be parsed.
+fn parse<T: DeserializeOwned>(command: &RawValue, path: &[Segment]) -> Result<T, Problem> {
+    let value = serde_json::from_str::<serde_json::Value>(command.get())
+        .map_err(|e| Problem::new(path.to_vec(), e.to_string()))?;
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let mut path = path.to_vec();
+        for segment in e.path().iter() {
+            match segment {
+                serde_path_to_error::Segment::Seq { index } => path.push(Segment::Index(*index)),
+                serde_path_to_error::Segment::Map { key } => path.push(Segment::Key(key.clone())),
+                serde_path_to_error::Segment::Enum { .. }
+                | serde_path_to_error::Segment::Unknown => {}
+            }
+        }
+        Problem::new(path, e.into_inner().to_string())
+    })
+}
+
+/// Returns a problem if none of the plugins declared the command.
+fn check_supported(
+    plugin_ids: &[&PluginId],
+    command: &OpenC2Command,
+    capabilities: &Capabilities,
+    path: &[Segment],
+) -> Option<Problem> {
+    let (action, target) = command.get_command_identity();
+    let mut declared = Vec::<String>::new();
+    for plugin_id in plugin_ids {
+        match capabilities.get(*plugin_id) {
+            Some(supported) if !supported.iter().any(|desc| desc.matches(&action, &target)) => {
+                declared.extend(supported.iter().map(CommandDescription::name));
+            }
+            _ => return None,
+        }
+    }
+
+    let mut path = path.to_vec();
+    path.extend([
+        Segment::Key("command".into()),
+        Segment::Key("action".into()),
+    ]);
+    let plugin_ids = plugin_ids
+        .iter()
+        .map(|plugin_id| format!("{:?}", plugin_id))
+        .collect::<Vec<String>>();
+    let message = format!(
+        "{} did not declare \"{} {}\", only: {}",
+        plugin_ids.join(", "),
+        action.as_ref(),
+        target,
+        declared.join(", ")
+    );
+    Some(Problem::new(path, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::config;
+
+    #[test]
+    fn test_check() {
+        let config = config::parse(
+            r#"{
+                "plugins": ["plugin_fw", {"process": "bin/plugin_switch"}],
+                "routes": [
+                    {"plugin": "plugin_fw", "actuator_id": ["fw-01"]},
+                    {"plugin": "plugin_server", "actuator_id": ["srv-01"]}
+                ],
+                "commands": {
+                    "plugin_fw": {
+                        "header": {"request_id": "1", "msg_type": "request", "version": "1.0", "created": 0, "sender": "gateway"},
+                        "command": {"action": "deny", "target": {"features": ["versions"]}}
+                    },
+                    "plugin_switch": {
+                        "header": {"request_id": "2", "msg_type": "request", "version": "1.0", "created": 0, "sender": "gateway"},
+                        "command": {"action": "query", "target": {"features": ["versions"]}, "args": {"start_time": "soon", "response_requested": "Complete"}}
+                    },
+                    "plugin_typo": {
+                        "header": {"request_id": "3", "msg_type": "request", "version": "1.0", "created": 0, "sender": "gateway"},
+                        "command": {"action": "query", "target": {"features": ["versions"]}}
+                    },
+                    "host": {"op": "restart", "plugin": "plugin_fw"}
+                },
+                "dispatch": [
+                    {
+                        "header": {"request_id": "4", "msg_type": "request", "version": "1.0", "created": 0, "sender": "gateway"},
+                        "command": {"action": "query", "target": {"features": ["versions"]}, "actuator": {"actuator_type": "device", "actuator_id": ["fw-02"]}}
+                    }
+                ],
+                "script": [
+                    {"plugin": "plugin_fw", "command": {"header": {}}, "expect": {"status": 200}}
+                ]
+            }"#,
+            Format::Json,
+        )
+        .unwrap();
+        let capabilities = Capabilities::from([(
+            PluginId::from("plugin_fw"),
+            RVec::from(vec![CommandDescription::new(
+                OpenC2Action::Query,
+                "features.versions",
+                "",
+            )]),
+        )]);
+
+        let problems = check(&config, &capabilities)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>();
+        assert_eq!(problems.len(), 7, "{:#?}", problems);
+        assert!(problems[0].starts_with(
+            r#"commands.plugin_fw.command.action: "plugin_fw" did not declare "deny features.versions", only: query features.versions"#
+        ));
+        assert!(problems[1].starts_with("commands.plugin_switch.command.args.start_time: "));
+        assert_eq!(
+            problems[2],
+            r#"commands.plugin_typo: "plugin_typo" is not one of the plugins"#
+        );
+        assert!(problems[3].starts_with("commands.host.op: unknown variant `restart`"));
+        assert_eq!(
+            problems[4],
+            "warning: dispatch[0]: no route matches this command, it will be answered with 404"
+        );
+        assert!(problems[5].starts_with("script[0].command.header: missing field"));
+        assert_eq!(
+            problems[6],
+            r#"routes[1].plugin: "plugin_server" is not one of the plugins"#
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! Finds where a value is in the text of a JSON document, to point at the problems in a config file.
+
+use std::fmt::{self, Write};
+
+/// A step of the path to a value, like `commands`, `plugin_fw` and `[0]` in `commands.plugin_fw[0]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// Writes a path like `dispatch[0].header.request_id`.
+pub fn fmt_path(path: &[Segment], f: &mut impl Write) -> fmt::Result {
+    for (i, segment) in path.iter().enumerate() {
+        match segment {
+            Segment::Key(key) if i == 0 => f.write_str(key)?,
+            Segment::Key(key) => write!(f, ".{}", key)?,
+            Segment::Index(index) => write!(f, "[{}]", index)?,
+        }
+    }
+    Ok(())
+}
+
+/// Returns the line and column, starting at 1, where the value at `path` starts,
+/// or where the deepest value on the path that's in the document starts.
+pub fn locate(text: &str, path: &[Segment]) -> (usize, usize) {
+    let mut pos = skip_whitespace(text, 0);
+    for segment in path {
+        match descend(text, pos, segment) {
+            Some(x) => pos = x,
+            None => break,
+        }
+    }
+    let before = &text[..pos];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .map_or(0, |line| line.chars().count())
+        + 1;
+    (line, column)
+}
+
+/// Returns where the value of a member of the object, or an item of the array, at `pos` starts.
+fn descend(text: &str, pos: usize, segment: &Segment) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let open = *bytes.get(pos)?;
+    let mut pos = skip_whitespace(text, pos + 1);
+    match (open, segment) {
+        (b'{', Segment::Key(key)) => loop {
+            let end = skip_string(text, pos)?;
+            let name = serde_json::from_str::<String>(&text[pos..end]).ok()?;
+            pos = skip_whitespace(text, end);
+            if bytes.get(pos) != Some(&b':') {
+                return None;
+            }
+            pos = skip_whitespace(text, pos + 1);
+            if name == *key {
+                return Some(pos);
+            }
+            pos = skip_whitespace(text, skip_value(text, pos)?);
+            if bytes.get(pos) != Some(&b',') {
+                return None;
+            }
+            pos = skip_whitespace(text, pos + 1);
+        },
+        (b'[', Segment::Index(index)) => {
+            for i in 0.. {
+                if bytes.get(pos) == Some(&b']') {
+                    return None;
+                }
+                if i == *index {
+                    return Some(pos);
+                }
+                pos = skip_whitespace(text, skip_value(text, pos)?);
+                if bytes.get(pos) != Some(&b',') {
+                    return None;
+                }
+                pos = skip_whitespace(text, pos + 1);
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+fn skip_whitespace(text: &str, pos: usize) -> usize {
+    let rest = text.get(pos..).unwrap_or_default();
+    pos + (rest.len() - rest.trim_start().len())
+}
+
+/// Returns where the string at `pos` ends, after its closing quote.
+fn skip_string(text: &str, pos: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    if bytes.get(pos) != Some(&b'"') {
+        return None;
+    }
+    let mut i = pos + 1;
+    loop {
+        match bytes.get(i)? {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+}
+
+/// Returns where the value at `pos` ends.
+fn skip_value(text: &str, pos: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    match bytes.get(pos)? {
+        b'"' => skip_string(text, pos),
+        b'{' | b'[' => {
+            let mut depth = 0;
+            let mut i = pos;
+            loop {
+                match bytes.get(i)? {
+                    b'"' => {
+                        i = skip_string(text, i)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(i + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+        }
+        _ => {
+            let len = text[pos..]
+                .find(|c: char| c == ',' || c == '}' || c == ']' || c.is_whitespace())
+                .unwrap_or(text.len() - pos);
+            Some(pos + len)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let text = r#"{
+	"plugins": [{"name": "plugin_fw"}, "plugin_server"],
+	"commands": {
+		"plugin_\"fw\"": {"header": {"request_id": "1", "tags": ["a]", {"b": 2}]}},
+		"plugin_fw": {"header": {"created": 13, "request_id": "42"}}
+	}
+}"#;
+        let key = |key: &str| Segment::Key(key.into());
+        assert_eq!(locate(text, &[key("plugins"), Segment::Index(1)]), (2, 37));
+        assert_eq!(
+            locate(text, &[key("commands"), key("plugin_fw"), key("header")]),
+            (5, 27)
+        );
+        assert_eq!(
+            locate(
+                text,
+                &[
+                    key("commands"),
+                    key("plugin_fw"),
+                    key("header"),
+                    key("request_id")
+                ]
+            ),
+            (5, 57)
+        );
+        // The deepest value on the path that's in the document.
+        assert_eq!(
+            locate(text, &[key("commands"), key("plugin_fw"), key("command")]),
+            (5, 16)
+        );
+
+        let mut path = String::new();
+        fmt_path(
+            &[key("dispatch"), Segment::Index(0), key("header")],
+            &mut path,
+        )
+        .unwrap();
+        assert_eq!(path, "dispatch[0].header");
+    }
+}
@@ -0,0 +1,197 @@
+//! The plugin interface as of the first compatible version of the library.
+//!
+//! abi_stable only loads a library whose types have at least the fields and methods
+//! of the types it's checked against,
+//! so checking the plugins against the current interface would keep the plugins built
+//! before `PluginFactory::supported_commands` or `Plugin::handle_command` were added
+//! from loading.
+//! `init_root_module` checks them against the types in here instead,
+//! which must never change,
+//! and the fields and methods added since are accessed through the optional accessors
+//! that abi_stable generates for them.
+
+use abi_stable::{
+    external_types::crossbeam_channel::RSender,
+    library::{LibHeader, LibraryError, RootModule},
+    sabi_trait,
+    sabi_types::RMut,
+    std_types::{RArc, RBox, RBoxError, ROk, ROption, RResult, RSome, RStr, RString, RVec},
+    StableAbi,
+};
+
+pub type PluginId = RString;
+
+#[repr(u8)]
+#[derive(StableAbi)]
+pub enum OpenC2MsgType {
+    Request,
+    Response,
+}
+
+#[repr(u8)]
+#[derive(StableAbi)]
+pub enum Error {
+    Serialize(RBoxError, OpenC2MsgType),
+    Deserialize(RBoxError, OpenC2MsgType),
+    UnsupportedCommand(RBox<Unsupported>),
+    UnsupportedReturnValue(RBox<Unsupported>),
+    InvalidPlugin(PluginId),
+    Custom(RBoxError),
+    Many(RVec<Error>),
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct Unsupported {
+    pub plugin_name: RString,
+    pub command_name: RString,
+    pub error: RBoxError,
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct PluginCommand {
+    pub from: PluginId,
+    pub to: PluginId,
+    pub command: RString,
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+pub struct PluginResponse {
+    pub from: PluginId,
+    pub to: PluginId,
+    pub response: RString,
+}
+
+pub type PluginType = Plugin_TO<'static, RBox<()>>;
+
+#[sabi_trait]
+pub trait Plugin {
+    fn send_command(
+        &mut self,
+        command: RStr<'_>,
+        app: ApplicationMut<'_>,
+    ) -> RResult<RString, Error>;
+
+    fn handle_response(
+        &mut self,
+        response: RArc<PluginResponse>,
+        _app: ApplicationMut<'_>,
+    ) -> RResult<ROption<RArc<PluginResponse>>, Error> {
+        ROk(RSome(response))
+    }
+
+    fn plugin_id(&self) -> &PluginId;
+
+    #[sabi(last_prefix_field)]
+    fn close(self, app: ApplicationMut<'_>);
+}
+
+#[repr(C)]
+#[derive(StableAbi)]
+#[sabi(kind(Prefix(prefix_ref = "PluginFactory_Ref")))]
+#[sabi(missing_field(panic))]
+pub struct PluginFactory {
+    #[sabi(last_prefix_field)]
+    pub new: extern "C" fn(RSender<PluginCommand>, PluginId) -> RResult<PluginType, Error>,
+}
+
+impl RootModule for PluginFactory_Ref {
+    abi_stable::declare_root_module_statics! {PluginFactory_Ref}
+    const BASE_NAME: &'static str = crate::PluginFactory_Ref::BASE_NAME;
+    const NAME: &'static str = crate::PluginFactory_Ref::NAME;
+    const VERSION_STRINGS: abi_stable::sabi_types::VersionStrings =
+        crate::PluginFactory_Ref::VERSION_STRINGS;
+}
+
+pub type ApplicationMut<'a> = Application_TO<'a, RMut<'a, ()>>;
+
+#[sabi_trait]
+pub trait Application {
+    fn send_command_to_plugin(&mut self, command: RArc<PluginCommand>);
+
+    fn sender(&self) -> RSender<PluginCommand>;
+}
+
+/// Initializes the root module of the plugin library with `header`,
+/// checking its layout against the first compatible version of the interface.
+pub fn init_root_module(header: &LibHeader) -> Result<crate::PluginFactory_Ref, LibraryError> {
+    header.ensure_layout::<PluginFactory_Ref>()?;
+    // Safety: the current interface only added fields and methods after the ones checked above,
+    // which are only accessed through the accessors that check if the library has them.
+    unsafe { header.init_root_module_with_unchecked_layout::<crate::PluginFactory_Ref>() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::openc2::{
+        response::{CommandReply, OpenC2RespStatus},
+        view::CommandView,
+    };
+    use abi_stable::{
+        abi_stability::abi_checking::check_layout_compatibility, external_types::crossbeam_channel,
+        sabi_trait::TD_Opaque,
+    };
+    use std::mem;
+
+    /// A plugin built before `Plugin::handle_command` was added.
+    struct Legacy(PluginId);
+
+    impl Plugin for Legacy {
+        fn send_command(
+            &mut self,
+            command: RStr<'_>,
+            _app: ApplicationMut<'_>,
+        ) -> RResult<RString, Error> {
+            ROk(command.into())
+        }
+
+        fn plugin_id(&self) -> &PluginId {
+            &self.0
+        }
+
+        fn close(self, _app: ApplicationMut<'_>) {}
+    }
+
+    struct NoApplication;
+
+    impl crate::Application for NoApplication {
+        fn send_command_to_plugin(&mut self, _command: RArc<crate::PluginCommand>) {}
+
+        fn sender(&self) -> RSender<crate::PluginCommand> {
+            crossbeam_channel::unbounded().0
+        }
+    }
+
+    #[test]
+    fn test_plugins_load_with_either_layout() {
+        let baseline = <PluginFactory_Ref as StableAbi>::LAYOUT;
+        check_layout_compatibility(baseline, baseline).unwrap();
+        check_layout_compatibility(baseline, <crate::PluginFactory_Ref as StableAbi>::LAYOUT)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_baseline_plugins_handle_commands_with_send_command() {
+        let plugin = Plugin_TO::from_value(Legacy("plugin_fw".into()), TD_Opaque);
+        // What `init_root_module` does to the plugins that the root module constructs.
+        let mut plugin: crate::PluginType = unsafe { mem::transmute(plugin) };
+
+        let raw = r#"{
+            "header": {"request_id": "fw-01-14", "msg_type": "request", "version": "1.0", "created": 0, "sender": "gateway"},
+            "command": {"action": "query", "target": {"artifact": {"mime_type": "device/arp", "payload": {}}}}
+        }"#;
+        let command = CommandView::parse(raw).unwrap();
+        let mut app = NoApplication;
+        let reply = plugin
+            .handle_command(
+                &command,
+                crate::Application_TO::from_ptr(&mut app, TD_Opaque),
+            )
+            .unwrap();
+        assert_eq!(reply, CommandReply::new_status(OpenC2RespStatus::OK, raw));
+    }
+}
@@ -1,13 +1,14 @@
-use crate::{openc2::OpenC2MsgType, PluginId};
+use crate::{openc2::OpenC2MsgType, CommandDescription, PluginId};
 
 use abi_stable::{
     std_types::{RBox, RBoxError, RString, RVec},
     StableAbi,
 };
+use core_extensions::StringExt;
 
 use std::{
     error::Error as ErrorTrait,
-    fmt::{self, Display},
+    fmt::{self, Display, Write},
 };
 
 #[repr(u8)]
@@ -41,9 +42,29 @@ pub struct Unsupported {
     pub command_name: RString,
     /// A custom error.
     pub error: RBoxError,
-    //Todo: plugin support interface
-    //A list of the commands that the plugin supports
-    //pub supported_commands: RVec<CommandDescription>,
+}
+
+impl Unsupported {
+    /// Adds the commands that the plugin supports to `error`, so they're listed with it.
+    ///
+    /// They're not a field of `Unsupported`,
+    /// which would keep the plugins built before they were listed from loading.
+    pub fn list_supported_commands(&mut self, supported_commands: &[CommandDescription]) {
+        let mut error = format!("{}\nSupported commands:", self.error);
+        for supported in supported_commands {
+            let _ = write!(
+                error,
+                "{}",
+                format!(
+                    "\nName:\n{}\nDescription:\n{}\n\n",
+                    supported.name().left_padder(4),
+                    supported.description.left_padder(4),
+                )
+                .left_padder(4)
+            );
+        }
+        self.error = RBoxError::from_fmt(&error);
+    }
 }
 
 impl Error {
@@ -81,32 +102,14 @@ impl Display for Error {
                 };
                 writeln!(f, "Error happened while deserializing {}:\n{}\n", which, e)
             }
-            Error::UnsupportedCommand(v) => {
-                writeln!(
-                    f,
-                    "Plugin '{}' ooes not support this command:\n\
-                     \t'{}'\n\
-                     Because of this error:\n{}\n\
-                     Supported commands:\
-                    ",
-                    v.plugin_name, v.command_name, v.error,
-                )?;
-
-                //                for supported in &v.supported_commands {
-                //                    write!(
-                //                        f,
-                //                        "{}",
-                //                        format!(
-                //                            "\nName:\n{}\nDescription:\n{}\n\n",
-                //                            supported.name.left_padder(4),
-                //                            supported.description.left_padder(4),
-                //                        )
-                //                        .left_padder(4)
-                //                    )?;
-                //                }
-
-                Ok(())
-            }
+            Error::UnsupportedCommand(v) => writeln!(
+                f,
+                "Plugin '{}' does not support this command:\n\
+                 \t'{}'\n\
+                 Because of this error:\n{}\n\
+                ",
+                v.plugin_name, v.command_name, v.error,
+            ),
             Error::UnsupportedReturnValue(v) => writeln!(
                 f,
                 "Unrecognized return value from '{}',named:\n\
@@ -132,3 +135,28 @@ impl Display for Error {
 }
 
 impl ErrorTrait for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openc2::command::OpenC2Action;
+
+    #[test]
+    fn test_unsupported_command_lists_supported_commands() {
+        let mut unsupported = Unsupported {
+            plugin_name: "plugin_fw".into(),
+            command_name: "delete artifact.cmd".into(),
+            error: RBoxError::from_fmt(&"unknown command"),
+        };
+        unsupported.list_supported_commands(&[CommandDescription::new(
+            OpenC2Action::Set,
+            "artifact.cmd",
+            "Runs commands on the firewall",
+        )]);
+        let error = Error::unsupported_command(unsupported);
+        let message = error.to_string();
+        assert!(message.contains("Supported commands:"));
+        assert!(message.contains("set artifact.cmd"));
+        assert!(message.contains("Runs commands on the firewall"));
+    }
+}
@@ -0,0 +1,145 @@
+//! The protocol between the application and plugins that run as separate processes.
+//!
+//! The application writes a `HostMessage` per line to the stdin of the plugin process,
+//! which answers every message with a `PluginMessage::Ok` or `PluginMessage::Err` line
+//! with the same `id` on its stdout.
+//! While handling a message,
+//! the plugin can also write `ReportStatus` and `SendCommandToPlugin` lines,
+//! mirroring the methods of `Application`.
+
+use abi_stable::std_types::RString;
+use serde_json::Value;
+
+use crate::openc2::response::{CommandReply, OpenC2RespStatus};
+
+/// A message from the application, mirroring the methods of `Plugin`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum HostMessage {
+    /// Sent once after the process starts, like `PluginFactory::new`.
+    New { id: u64, plugin_id: String },
+    /// Sent once after `New`, answered with the id of the plugin,
+    /// which has to be the `plugin_id` it got in `New`.
+    PluginId { id: u64 },
+    /// Answered with a `CommandResult`.
+    SendCommand { id: u64, command: String },
+    /// Answered with `null`, or with the response to hand to the application.
+    HandleResponse {
+        id: u64,
+        response: PluginResponseMessage,
+    },
+    /// The process exits after answering it.
+    Close { id: u64 },
+}
+
+/// A message from the plugin process.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum PluginMessage {
+    Ok {
+        id: u64,
+        #[serde(default)]
+        value: Value,
+    },
+    Err {
+        id: u64,
+        error: String,
+    },
+    /// Like `Application::report_status`.
+    ReportStatus {
+        request_id: String,
+        reply: ReplyMessage,
+    },
+    /// Like `Application::send_command_to_plugin`.
+    SendCommandToPlugin {
+        to: String,
+        command: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PluginResponseMessage {
+    pub from: String,
+    pub to: String,
+    pub response: String,
+}
+
+/// The reply of a plugin process to a command, like `CommandReply`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplyMessage {
+    pub status: u16,
+    pub status_text: String,
+    #[serde(default)]
+    pub desc: String,
+    #[serde(default)]
+    pub results: Vec<Value>,
+}
+
+/// What a plugin process answers `SendCommand` with,
+/// either a description of an `Ok` reply, like `Plugin::send_command` returns,
+/// or the whole reply.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CommandResult {
+    Desc(String),
+    Reply(ReplyMessage),
+}
+
+impl From<ReplyMessage> for CommandReply {
+    fn from(reply: ReplyMessage) -> Self {
+        CommandReply {
+            status: reply.status,
+            status_text: reply.status_text.into(),
+            desc: reply.desc.into(),
+            results: reply
+                .results
+                .iter()
+                .map(|result| RString::from(result.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl From<CommandResult> for CommandReply {
+    fn from(result: CommandResult) -> Self {
+        match result {
+            CommandResult::Desc(desc) => CommandReply::new_status(OpenC2RespStatus::OK, &desc),
+            CommandResult::Reply(reply) => reply.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_messages_format() {
+        let message = HostMessage::SendCommand {
+            id: 3,
+            command: "{}".into(),
+        };
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            json!({"op": "send_command", "id": 3, "command": "{}"})
+        );
+
+        let message = serde_json::from_value::<PluginMessage>(json!({
+            "op": "ok",
+            "id": 3,
+            "value": {"status": 200, "status_text": "Ok", "results": [{"ip": "10.0.0.1"}]}
+        }))
+        .unwrap();
+        let value = match message {
+            PluginMessage::Ok { id: 3, value } => value,
+            other => panic!("unexpected message {:?}", other),
+        };
+        let reply = CommandReply::from(serde_json::from_value::<CommandResult>(value).unwrap());
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.results, vec![RString::from(r#"{"ip":"10.0.0.1"}"#)]);
+
+        let result = serde_json::from_value::<CommandResult>(json!("done")).unwrap();
+        assert_eq!(CommandReply::from(result).desc, "done");
+    }
+}
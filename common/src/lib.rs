@@ -1,8 +1,11 @@
 #[macro_use]
 extern crate serde_derive;
 
+pub mod baseline;
 pub mod error;
+pub mod ipc;
 pub mod openc2;
+pub mod panics;
 pub mod util;
 
 use abi_stable::{
@@ -11,10 +14,15 @@ use abi_stable::{
     library::RootModule,
     package_version_strings, sabi_trait,
     sabi_types::{RMut, VersionStrings},
-    std_types::{RArc, RBox, ROk, ROption, RResult, RSome, RStr, RString},
+    std_types::{RArc, RBox, ROk, ROption, RResult, RSome, RStr, RString, RVec},
     StableAbi,
 };
 pub use error::Error;
+use openc2::{
+    command::OpenC2Action,
+    response::{CommandReply, OpenC2RespStatus, OpenC2Response},
+    view::CommandView,
+};
 
 pub type PluginId = RString;
 
@@ -31,16 +39,56 @@ pub struct PluginCommand {
 pub struct PluginResponse {
     pub from: PluginId,
     pub to: PluginId,
+    /// The JSON encoded `OpenC2Response`.
     pub response: RString,
 }
 
+/// A command that a plugin supports,
+/// identified like `OpenC2Command::get_command_identity` identifies commands.
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq, StableAbi)]
+pub struct CommandDescription {
+    pub action: OpenC2Action,
+    /// The identity of the target, like `artifact.cmd`.
+    pub target: RString,
+    pub description: RString,
+}
+
+impl CommandDescription {
+    pub fn new(action: OpenC2Action, target: &str, description: &str) -> Self {
+        CommandDescription {
+            action,
+            target: target.into(),
+            description: description.into(),
+        }
+    }
+
+    /// Returns the action and target of the command, like `set artifact.cmd`.
+    pub fn name(&self) -> String {
+        format!("{} {}", self.action.as_ref(), self.target)
+    }
+
+    pub fn matches(&self, action: &OpenC2Action, target: &str) -> bool {
+        self.action == *action && self.target == target
+    }
+}
+
 pub type PluginType = Plugin_TO<'static, RBox<()>>;
 
 /// A plugin which is loaded by the application,and provides some functionality.
+///
+/// A panic in a method of a library plugin aborts the whole process,
+/// the application can't catch it on its side of the library boundary.
+/// Library plugins have to wrap themselves in `panics::CatchPanics`
+/// and their constructor in `panics::catch_panics`, see `panics`.
 #[sabi_trait]
 //#[sabi(debug_print)]
 pub trait Plugin {
-    /// Handles a JSON encoded command.
+    /// Handles a JSON encoded command,
+    /// returning the JSON encoded `OpenC2Response` to it.
+    ///
+    /// The application calls `handle_command` instead,
+    /// this is for the plugins and applications built before it was added.
     fn send_command(
         &mut self,
         command: RStr<'_>,
@@ -78,6 +126,26 @@ pub trait Plugin {
     /// at which point it would be moved to the last method at the time.
     #[sabi(last_prefix_field)]
     fn close(self, app: ApplicationMut<'_>);
+
+    /// Handles a command that the application already parsed and validated,
+    /// replying with the status and results of the `OpenC2Response` to it.
+    ///
+    /// This defaults to calling `send_command` with the JSON encoded command,
+    /// replying with the `OpenC2Response` it returns,
+    /// or with the string as the `desc` of an `Ok` reply if it isn't one,
+    /// which is also what happens for plugins built before this method was added.
+    fn handle_command(
+        &mut self,
+        command: &CommandView,
+        app: ApplicationMut<'_>,
+    ) -> RResult<CommandReply, Error> {
+        self.send_command(command.raw.as_rstr(), app).map(|reply| {
+            match serde_json::from_str::<OpenC2Response>(&reply) {
+                Ok(response) => response.into(),
+                Err(_) => CommandReply::new_status(OpenC2RespStatus::OK, &reply),
+            }
+        })
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -105,6 +173,13 @@ pub struct PluginFactory {
     ///
     #[sabi(last_prefix_field)]
     pub new: extern "C" fn(RSender<PluginCommand>, PluginId) -> RResult<PluginType, Error>,
+
+    /// Lists the commands that the plugins constructed by `new` support.
+    ///
+    /// This is `None` for plugins built before this field was added,
+    /// the application then assumes that they support every command.
+    #[sabi(missing_field(option))]
+    pub supported_commands: extern "C" fn() -> RVec<CommandDescription>,
 }
 
 impl RootModule for PluginFactory_Ref {
@@ -120,5 +195,17 @@ pub type ApplicationMut<'a> = Application_TO<'a, RMut<'a, ()>>;
 pub trait Application {
     fn send_command_to_plugin(&mut self, command: RArc<PluginCommand>);
 
+    /// This trait has no `#[sabi(last_prefix_field)]` attribute,
+    /// the first compatible version of the library didn't have it
+    /// and adding it would change the prefix that the plugins built since are checked against.
+    /// Every method of this trait is optional because of that,
+    /// the methods added after this one need a default body for applications that don't have them.
     fn sender(&self) -> RSender<PluginCommand>;
+
+    /// Reports the progress of the command with `request_id` while the plugin handles it,
+    /// which is sent as an intermediate response if the command asked for `status` or `complete`
+    /// responses.
+    ///
+    /// Applications built before this method was added drop the reports.
+    fn report_status(&mut self, _request_id: RStr<'_>, _reply: CommandReply) {}
 }
@@ -1,3 +1,4 @@
+use abi_stable::StableAbi;
 use chrono::Utc;
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -20,6 +21,14 @@ impl OpenC2Args {
         }
     }
 
+    pub fn get_start_time(&self) -> Option<u64> {
+        self.start_time
+    }
+
+    pub fn get_stop_time(&self) -> Option<u64> {
+        self.stop_time
+    }
+
     pub fn get_timeout(&self) -> Option<u64> {
         self.stop_time.map(|time| {
             let now = Utc::now().timestamp_millis();
@@ -32,7 +41,8 @@ impl OpenC2Args {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[repr(u8)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, StableAbi)]
 pub enum ResponseRequested {
     None,
     Ack,
@@ -7,6 +7,7 @@ use crate::openc2::args::{OpenC2Args, ResponseRequested};
 use crate::openc2::target::{Device, Target, TargetIdentity};
 use crate::openc2::{OpenC2MsgType, TraceIdent};
 
+#[repr(u8)]
 #[derive(
     Serialize,
     Deserialize,
@@ -19,6 +20,7 @@ use crate::openc2::{OpenC2MsgType, TraceIdent};
     IntoEnumIterator,
     EnumString,
     ToString,
+    StableAbi,
 )]
 #[serde(rename_all = "lowercase")]
 pub enum OpenC2Action {
@@ -272,6 +274,14 @@ impl OpenC2Actuator {
         }
     }
 
+    pub fn get_actuator_type(&self) -> &ActuatorType {
+        &self.actuator_type
+    }
+
+    pub fn get_actuator_id(&self) -> &[String] {
+        &self.actuator_id
+    }
+
     pub fn actuator_id_to_string(&self) -> String {
         let mut id = "".into();
         if self.actuator_id.is_empty() {
@@ -333,6 +343,10 @@ impl OpenC2Command {
             .and_then(|args| args.get_timeout())
     }
 
+    pub fn get_args(&self) -> Option<&OpenC2Args> {
+        self.command.args.as_ref()
+    }
+
     pub fn get_response_requested(&self) -> Option<&ResponseRequested> {
         self.command
             .args
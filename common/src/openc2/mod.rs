@@ -5,6 +5,7 @@ pub mod args;
 pub mod command;
 pub mod response;
 pub mod target;
+pub mod view;
 
 pub trait Push<T> {
     fn push(&mut self, t: T);
@@ -1,4 +1,9 @@
 use crate::openc2::{OpenC2MsgType, Push, TraceIdent};
+use crate::Error;
+use abi_stable::{
+    std_types::{RBoxError, RString, RVec},
+    StableAbi,
+};
 use chrono::Utc;
 use serde_json::Value;
 
@@ -53,7 +58,7 @@ use serde_json::Value;
 ///        assert_eq!(expect, serde_json::to_string(&response).unwrap());
 ///
 /// ```
-#[derive(Debug, Deserialize, Serialize, PartialEq, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Default)]
 pub struct OpenC2Response {
     #[serde(rename = "results")]
     results: Vec<serde_json::Value>,
@@ -82,13 +87,34 @@ pub struct OpenC2Response {
 
 #[allow(clippy::wrong_self_convention)]
 pub enum OpenC2RespStatus {
+    // 已收到命令, 正在处理
+    Processing,
     OK,
+    // 命令格式错误
+    BadRequest,
+    // 插件无权执行该命令
+    Forbidden,
+    // 没有插件处理该执行器
+    NotFound,
+    // 命令已过期, 或插件处理超时
+    Timeout,
+    // 插件处理命令出错
+    InternalError,
+    // 插件不支持该命令
+    NotImplemented,
 }
 
 impl From<OpenC2RespStatus> for (u16, String) {
     fn from(status: OpenC2RespStatus) -> Self {
         match status {
+            OpenC2RespStatus::Processing => (102, "Processing".into()),
             OpenC2RespStatus::OK => (200, "Ok".into()),
+            OpenC2RespStatus::BadRequest => (400, "Bad Request".into()),
+            OpenC2RespStatus::Forbidden => (403, "Forbidden".into()),
+            OpenC2RespStatus::NotFound => (404, "Not Found".into()),
+            OpenC2RespStatus::Timeout => (408, "Request Timeout".into()),
+            OpenC2RespStatus::InternalError => (500, "Internal Error".into()),
+            OpenC2RespStatus::NotImplemented => (501, "Not Implemented".into()),
         }
     }
 }
@@ -156,9 +182,21 @@ impl OpenC2Response {
         &self.request_id
     }
 
+    pub fn get_sender(&self) -> &String {
+        &self.sender
+    }
+
     pub fn get_status(&self) -> u16 {
         self.status
     }
+
+    pub fn get_status_text(&self) -> &String {
+        &self.status_text
+    }
+
+    pub fn get_desc(&self) -> &String {
+        &self.desc
+    }
 }
 
 impl TraceIdent for OpenC2Response {
@@ -193,6 +231,79 @@ impl Push<&Vec<serde_json::Value>> for OpenC2Response {
     }
 }
 
+/// The reply of a plugin to a command, which the application turns into an `OpenC2Response`
+/// with the `request_id` of the command, the plugin as its `sender`, and when it was `created`.
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq, StableAbi)]
+pub struct CommandReply {
+    pub status: u16,
+    pub status_text: RString,
+    pub desc: RString,
+    /// The JSON encoded results.
+    pub results: RVec<RString>,
+}
+
+impl CommandReply {
+    pub fn new(results: Vec<serde_json::Value>) -> Self {
+        let mut reply = Self::new_status(OpenC2RespStatus::OK, "");
+        reply.results = results.iter().map(|v| v.to_string().into()).collect();
+        reply
+    }
+
+    pub fn new_status(status: OpenC2RespStatus, desc: &str) -> Self {
+        let (status, status_text) = status.into();
+        CommandReply {
+            status,
+            status_text: status_text.into(),
+            desc: desc.into(),
+            results: RVec::new(),
+        }
+    }
+
+    /// Turns the reply into the response to the command with `request_id`.
+    pub fn into_response(self, request_id: &str, sender: &str) -> Result<OpenC2Response, Error> {
+        let results = self
+            .results
+            .iter()
+            .map(|result| serde_json::from_str(result))
+            .collect::<Result<Vec<Value>, _>>()
+            .map_err(|e| Error::Deserialize(RBoxError::new(e), OpenC2MsgType::Response))?;
+        let mut response = OpenC2Response::new(results, request_id, sender);
+        response.set_status(self.status, &self.status_text, &self.desc);
+        Ok(response)
+    }
+
+    /// Turns the reply into the JSON encoded response to the command with `request_id`,
+    /// which is what `Plugin::send_command` returns.
+    pub fn into_response_json(self, request_id: &str, sender: &str) -> Result<RString, Error> {
+        let response = self.into_response(request_id, sender)?;
+        serde_json::to_string(&response)
+            .map(RString::from)
+            .map_err(|e| Error::Serialize(RBoxError::new(e), OpenC2MsgType::Response))
+    }
+}
+
+impl From<OpenC2Response> for CommandReply {
+    fn from(response: OpenC2Response) -> Self {
+        CommandReply {
+            status: response.status,
+            status_text: response.status_text.into(),
+            desc: response.desc.into(),
+            results: response
+                .results
+                .iter()
+                .map(|v| v.to_string().into())
+                .collect(),
+        }
+    }
+}
+
+impl Push<serde_json::Value> for CommandReply {
+    fn push(&mut self, v: Value) {
+        self.results.push(v.to_string().into())
+    }
+}
+
 // 探针批量任务处理报错时，使用此结构与探针请求结果一致
 #[derive(Debug, Deserialize, Serialize, PartialEq, Default)]
 pub struct BatchResultInner {
@@ -258,6 +369,30 @@ mod tests {
         assert_eq!(expect, serde_json::to_string(&response).unwrap());
     }
 
+    #[test]
+    fn test_reply_into_response() {
+        let mut reply = CommandReply::new(vec![serde_json::json!({"ip": "10.0.0.1"})]);
+        reply.push(serde_json::json!("second"));
+
+        let mut response = reply.into_response("fw-01-14", "plugin_fw").unwrap();
+        response.created = 0;
+        let expect = r#"{"results":[{"ip":"10.0.0.1"},"second"],"msg_type":"response","request_id":"fw-01-14","created":0,"sender":"plugin_fw","status":200,"status_text":"Ok","desc":""}"#;
+        assert_eq!(expect, serde_json::to_string(&response).unwrap());
+
+        let reply = CommandReply::new_status(OpenC2RespStatus::NotFound, "no arp table");
+        let response = reply.into_response("fw-01-14", "plugin_fw").unwrap();
+        assert_eq!(response.get_status(), 404);
+
+        let reply = CommandReply::new(vec![serde_json::json!({"ip": "10.0.0.1"})]);
+        let json = reply
+            .clone()
+            .into_response_json("fw-01-14", "plugin_fw")
+            .unwrap();
+        let response = serde_json::from_str::<OpenC2Response>(&json).unwrap();
+        assert_eq!(response.get_request_id(), "fw-01-14");
+        assert_eq!(CommandReply::from(response), reply);
+    }
+
     #[test]
     fn test_response_created() {
         let now = Utc::now().timestamp_millis();
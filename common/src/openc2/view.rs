@@ -0,0 +1,155 @@
+use abi_stable::{
+    std_types::{RBoxError, RNone, ROption, RSome, RString, RVec},
+    StableAbi,
+};
+
+use crate::openc2::args::{OpenC2Args, ResponseRequested};
+use crate::openc2::command::{ActuatorType, OpenC2Action, OpenC2Actuator, OpenC2Command};
+use crate::openc2::target::TargetIdentity;
+use crate::openc2::OpenC2MsgType;
+use crate::Error;
+
+/// An OpenC2 command that the application already parsed and validated,
+/// which plugins receive in `Plugin::handle_command`.
+///
+/// The target is only described by its identity,
+/// `to_openc2` parses the whole command from `raw` for plugins that need the target's payload.
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, StableAbi)]
+pub struct CommandView {
+    pub request_id: RString,
+    pub action: OpenC2Action,
+    /// The identity of the target, like `artifact.cmd`.
+    pub target: RString,
+    pub actuator: ROption<ActuatorView>,
+    pub args: ROption<ArgsView>,
+    /// The JSON encoded command.
+    pub raw: RString,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq, StableAbi)]
+pub struct ActuatorView {
+    pub actuator_type: ActuatorType,
+    pub actuator_id: RVec<RString>,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq, StableAbi)]
+pub struct ArgsView {
+    pub start_time: ROption<u64>,
+    pub stop_time: ROption<u64>,
+    pub response_requested: ResponseRequested,
+}
+
+impl CommandView {
+    /// Parses a JSON encoded OpenC2 command.
+    pub fn parse(raw: &str) -> Result<Self, Error> {
+        let command = serde_json::from_str::<OpenC2Command>(raw)
+            .map_err(|e| Error::Deserialize(RBoxError::new(e), OpenC2MsgType::Request))?;
+        Ok(Self::new(&command, raw))
+    }
+
+    /// Constructs the view of an already parsed command, `raw` being its JSON encoding.
+    pub fn new(command: &OpenC2Command, raw: &str) -> Self {
+        CommandView {
+            request_id: command.get_request_id().as_str().into(),
+            action: command.get_action().clone(),
+            target: command.get_target().identity().into(),
+            actuator: command
+                .get_actuator()
+                .as_ref()
+                .map(ActuatorView::from)
+                .into(),
+            args: command.get_args().map(ArgsView::from).into(),
+            raw: raw.into(),
+        }
+    }
+
+    /// Parses the whole command from its JSON encoding.
+    pub fn to_openc2(&self) -> Result<OpenC2Command, Error> {
+        serde_json::from_str(&self.raw)
+            .map_err(|e| Error::Deserialize(RBoxError::new(e), OpenC2MsgType::Request))
+    }
+
+    /// Returns which responses the command asked for, `Complete` if it has no args.
+    pub fn response_requested(&self) -> ResponseRequested {
+        match &self.args {
+            RSome(args) => args.response_requested.clone(),
+            RNone => ResponseRequested::Complete,
+        }
+    }
+
+    /// Returns the action and target of the command, like `set artifact.cmd`.
+    pub fn name(&self) -> String {
+        format!("{} {}", self.action.as_ref(), self.target)
+    }
+}
+
+impl From<&OpenC2Actuator> for ActuatorView {
+    fn from(actuator: &OpenC2Actuator) -> Self {
+        ActuatorView {
+            actuator_type: actuator.get_actuator_type().clone(),
+            actuator_id: actuator
+                .get_actuator_id()
+                .iter()
+                .map(|id| RString::from(id.as_str()))
+                .collect(),
+        }
+    }
+}
+
+impl From<&OpenC2Args> for ArgsView {
+    fn from(args: &OpenC2Args) -> Self {
+        ArgsView {
+            start_time: args.get_start_time().into(),
+            stop_time: args.get_stop_time().into(),
+            response_requested: args.get_response_requested().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command_view() {
+        let raw = r#"{
+            "header": {
+                "request_id": "fw-01-14",
+                "msg_type": "request",
+                "version": "1.0",
+                "created": 1539355895215,
+                "sender": "gateway"
+            },
+            "command": {
+                "action": "query",
+                "target": {"artifact": {"mime_type": "device/arp", "payload": {}}},
+                "actuator": {"actuator_type": "device", "actuator_id": ["fw-01"]},
+                "args": {"start_time": null, "stop_time": 1934775460000, "response_requested": "Ack"}
+            }
+        }"#;
+
+        let view = CommandView::parse(raw).unwrap();
+        assert_eq!(view.request_id, "fw-01-14");
+        assert_eq!(view.name(), "query artifact.device/arp");
+        assert_eq!(
+            view.actuator.as_ref().unwrap().actuator_id,
+            vec![RString::from("fw-01")]
+        );
+        let args = view.args.as_ref().unwrap();
+        assert_eq!(args.stop_time, RSome(1934775460000));
+        assert_eq!(args.response_requested, ResponseRequested::Ack);
+        assert_eq!(view.response_requested(), ResponseRequested::Ack);
+        assert_eq!(view.to_openc2().unwrap().get_request_id(), "fw-01-14");
+    }
+
+    #[test]
+    fn test_parse_malformed_command() {
+        match CommandView::parse(r#"{"header": {}}"#) {
+            Err(Error::Deserialize(_, OpenC2MsgType::Request)) => {}
+            other => panic!("expected a request deserialization error, got {:?}", other),
+        }
+    }
+}
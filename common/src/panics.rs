@@ -0,0 +1,181 @@
+//! Catching panics in plugins before they reach the application.
+//!
+//! A panic can't unwind across the functions that abi_stable generates for plugins,
+//! it aborts the whole process instead,
+//! so plugins catch their own panics by wrapping themselves in `CatchPanics`
+//! and their constructor in `catch_panics`.
+//! The application can't catch the panics of a library plugin that doesn't,
+//! plugins that can't be trusted to do it are run as a separate process instead.
+//!
+//! Panics are returned as an `Error::Custom` that `is_panic` recognizes,
+//! since adding a variant to `Error` would keep the plugins built before from loading.
+
+use abi_stable::std_types::{RArc, RBoxError, RErr, ROption, RResult, RStr, RString};
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::{
+    openc2::{response::CommandReply, view::CommandView},
+    ApplicationMut, Error, Plugin, PluginId, PluginResponse,
+};
+
+/// Returns the message that `panic!` was called with.
+fn panic_message(payload: &(dyn Any + Send)) -> RString {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).into()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str().into()
+    } else {
+        "panicked with a non-string payload".into()
+    }
+}
+
+/// Starts the message of the errors that panics are returned as, after the plugin id.
+const PANICKED: &str = " panicked: ";
+
+/// Returns the error that a panic of `plugin_id` with `message` is returned as.
+pub fn panicked(plugin_id: &PluginId, message: &str) -> Error {
+    Error::Custom(RBoxError::from_fmt(&format_args!(
+        "plugin {:?}{}{}",
+        plugin_id, PANICKED, message
+    )))
+}
+
+/// Whether an error is a panic returned by `panicked`.
+pub fn is_panic(error: &Error) -> bool {
+    match error {
+        Error::Custom(e) => {
+            let message = e.to_string();
+            message.starts_with("plugin \"") && message.contains(PANICKED)
+        }
+        _ => false,
+    }
+}
+
+/// Runs `f`, turning a panic into the error returned by `panicked` for `plugin_id`.
+pub fn catch_panics<T, F>(plugin_id: &PluginId, f: F) -> RResult<T, Error>
+where
+    F: FnOnce() -> RResult<T, Error>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(x) => x,
+        Err(payload) => RErr(panicked(plugin_id, &panic_message(&*payload))),
+    }
+}
+
+/// A plugin whose panics are returned as errors that `is_panic` recognizes,
+/// after which the application constructs the plugin again.
+pub struct CatchPanics<P>(pub P);
+
+impl<P: Plugin> Plugin for CatchPanics<P> {
+    fn send_command(
+        &mut self,
+        command: RStr<'_>,
+        app: ApplicationMut<'_>,
+    ) -> RResult<RString, Error> {
+        let plugin_id = self.0.plugin_id().clone();
+        catch_panics(&plugin_id, || self.0.send_command(command, app))
+    }
+
+    fn handle_response(
+        &mut self,
+        response: RArc<PluginResponse>,
+        app: ApplicationMut<'_>,
+    ) -> RResult<ROption<RArc<PluginResponse>>, Error> {
+        let plugin_id = self.0.plugin_id().clone();
+        catch_panics(&plugin_id, || self.0.handle_response(response, app))
+    }
+
+    fn plugin_id(&self) -> &PluginId {
+        self.0.plugin_id()
+    }
+
+    fn close(self, app: ApplicationMut<'_>) {
+        let plugin = self.0;
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| plugin.close(app)));
+    }
+
+    fn handle_command(
+        &mut self,
+        command: &CommandView,
+        app: ApplicationMut<'_>,
+    ) -> RResult<CommandReply, Error> {
+        let plugin_id = self.0.plugin_id().clone();
+        catch_panics(&plugin_id, || self.0.handle_command(command, app))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Application, Application_TO, PluginCommand};
+    use abi_stable::{
+        external_types::crossbeam_channel::{self, RSender},
+        sabi_trait::TD_Opaque,
+        std_types::ROk,
+    };
+
+    struct Panicking(PluginId);
+
+    impl Plugin for Panicking {
+        fn send_command(
+            &mut self,
+            command: RStr<'_>,
+            _app: ApplicationMut<'_>,
+        ) -> RResult<RString, Error> {
+            if command.as_str() == "panic" {
+                panic!("cannot handle {}", command);
+            }
+            ROk(command.into())
+        }
+
+        fn plugin_id(&self) -> &PluginId {
+            &self.0
+        }
+
+        fn close(self, _app: ApplicationMut<'_>) {}
+    }
+
+    struct NoApplication;
+
+    impl Application for NoApplication {
+        fn send_command_to_plugin(&mut self, _command: RArc<PluginCommand>) {}
+
+        fn sender(&self) -> RSender<PluginCommand> {
+            crossbeam_channel::unbounded().0
+        }
+    }
+
+    #[test]
+    fn test_catch_panics() {
+        let mut plugin = CatchPanics(Panicking("plugin_fw".into()));
+        let mut app = NoApplication;
+
+        let reply = plugin.send_command("ok".into(), Application_TO::from_ptr(&mut app, TD_Opaque));
+        assert_eq!(reply.unwrap(), "ok");
+
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let reply = plugin.send_command(
+            "panic".into(),
+            Application_TO::from_ptr(&mut app, TD_Opaque),
+        );
+        panic::set_hook(hook);
+
+        match reply {
+            RErr(e) => {
+                assert!(is_panic(&e), "{:?}", e);
+                assert_eq!(
+                    e.to_string(),
+                    r#"plugin "plugin_fw" panicked: cannot handle panic"#
+                );
+            }
+            other => panic!("expected a panic error, got {:?}", other),
+        }
+        assert!(!is_panic(&Error::Custom(RBoxError::from_fmt(
+            &"unknown command"
+        ))));
+    }
+}
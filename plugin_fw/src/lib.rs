@@ -4,12 +4,20 @@ use abi_stable::{
     prefix_type::PrefixTypeTrait,
     sabi_extern_fn,
     sabi_trait::prelude::TD_Opaque,
-    std_types::{ROk, RResult, RStr, RString},
+    std_types::{RErr, ROk, RResult, RStr, RString, RVec},
 };
 
+use serde_json::json;
+
 use common::{
-    ApplicationMut, Error as AppError, Plugin, PluginCommand, PluginFactory, PluginFactory_Ref,
-    PluginId, PluginType, Plugin_TO,
+    openc2::{
+        command::OpenC2Action,
+        response::{CommandReply, OpenC2RespStatus},
+        view::CommandView,
+    },
+    panics::{catch_panics, CatchPanics},
+    ApplicationMut, CommandDescription, Error as AppError, Plugin, PluginCommand, PluginFactory,
+    PluginFactory_Ref, PluginId, PluginType, Plugin_TO,
 };
 
 ///////////////////////////////////////////////////////////////////////////////////
@@ -19,13 +27,36 @@ use common::{
 /// This code isn't run until the layout of the type it returns is checked.
 #[export_root_module]
 fn instantiate_root_module() -> PluginFactory_Ref {
-    PluginFactory { new }.leak_into_prefix()
+    PluginFactory {
+        new,
+        supported_commands,
+    }
+    .leak_into_prefix()
+}
+
+#[sabi_extern_fn]
+pub fn supported_commands() -> RVec<CommandDescription> {
+    vec![
+        CommandDescription::new(
+            OpenC2Action::Set,
+            "artifact.cmd",
+            "Runs cli commands on the firewall",
+        ),
+        CommandDescription::new(
+            OpenC2Action::Query,
+            "artifact.device/arp",
+            "Returns the arp table of the firewall",
+        ),
+    ]
+    .into()
 }
 
 #[sabi_extern_fn]
 pub fn new(_sender: RSender<PluginCommand>, plugin_id: PluginId) -> RResult<PluginType, AppError> {
-    let this = PluginFireWall { plugin_id };
-    ROk(Plugin_TO::from_value(this, TD_Opaque))
+    catch_panics(&plugin_id.clone(), || {
+        let this = PluginFireWall { plugin_id };
+        ROk(Plugin_TO::from_value(CatchPanics(this), TD_Opaque))
+    })
 }
 
 struct PluginFireWall {
@@ -36,10 +67,43 @@ impl Plugin for PluginFireWall {
     fn send_command(
         &mut self,
         command: RStr<'_>,
-        _app: ApplicationMut<'_>,
+        app: ApplicationMut<'_>,
     ) -> RResult<RString, AppError> {
-        println!("command:\n{}", command);
-        ROk(RString::from("send messge to plugin firewall success"))
+        match CommandView::parse(command.as_str()) {
+            Ok(command) => self.handle_command(&command, app).and_then(|reply| {
+                reply
+                    .into_response_json(&command.request_id, &self.plugin_id)
+                    .into()
+            }),
+            Err(e) => RErr(e),
+        }
+    }
+
+    fn handle_command(
+        &mut self,
+        command: &CommandView,
+        mut app: ApplicationMut<'_>,
+    ) -> RResult<CommandReply, AppError> {
+        println!(
+            "command:\n    {} {} (request {})",
+            command.action.as_ref(),
+            command.target,
+            command.request_id
+        );
+        if command.action == OpenC2Action::Query && command.target == "artifact.device/arp" {
+            return ROk(CommandReply::new(vec![json!({
+                "ip": "192.168.1.1",
+                "mac": "00:1a:2b:3c:4d:5e",
+            })]));
+        }
+        app.report_status(
+            command.request_id.as_rstr(),
+            CommandReply::new_status(OpenC2RespStatus::Processing, "pushing cli commands"),
+        );
+        ROk(CommandReply::new_status(
+            OpenC2RespStatus::OK,
+            "send messge to plugin firewall success",
+        ))
     }
 
     fn plugin_id(&self) -> &PluginId {
@@ -4,12 +4,18 @@ use abi_stable::{
     prefix_type::PrefixTypeTrait,
     sabi_extern_fn,
     sabi_trait::prelude::TD_Opaque,
-    std_types::{ROk, RResult, RStr, RString},
+    std_types::{RErr, ROk, RResult, RStr, RString, RVec},
 };
 
 use common::{
-    ApplicationMut, Error as AppError, Plugin, PluginCommand, PluginFactory, PluginFactory_Ref,
-    PluginId, PluginType, Plugin_TO,
+    openc2::{
+        command::OpenC2Action,
+        response::{CommandReply, OpenC2RespStatus},
+        view::CommandView,
+    },
+    panics::{catch_panics, CatchPanics},
+    ApplicationMut, CommandDescription, Error as AppError, Plugin, PluginCommand, PluginFactory,
+    PluginFactory_Ref, PluginId, PluginType, Plugin_TO,
 };
 
 ///////////////////////////////////////////////////////////////////////////////////
@@ -19,15 +25,31 @@ use common::{
 /// This code isn't run until the layout of the type it returns is checked.
 #[export_root_module]
 fn instantiate_root_module() -> PluginFactory_Ref {
-    PluginFactory { new }.leak_into_prefix()
+    PluginFactory {
+        new,
+        supported_commands,
+    }
+    .leak_into_prefix()
 }
 
 //////////////////////////////////////////////////////////////////////////////////////
 
+#[sabi_extern_fn]
+pub fn supported_commands() -> RVec<CommandDescription> {
+    vec![CommandDescription::new(
+        OpenC2Action::Set,
+        "artifact.cmd",
+        "Runs shell commands on the server",
+    )]
+    .into()
+}
+
 #[sabi_extern_fn]
 pub fn new(_sender: RSender<PluginCommand>, plugin_id: PluginId) -> RResult<PluginType, AppError> {
-    let this = PluginServer { plugin_id };
-    ROk(Plugin_TO::from_value(this, TD_Opaque))
+    catch_panics(&plugin_id.clone(), || {
+        let this = PluginServer { plugin_id };
+        ROk(Plugin_TO::from_value(CatchPanics(this), TD_Opaque))
+    })
 }
 
 struct PluginServer {
@@ -38,10 +60,33 @@ impl Plugin for PluginServer {
     fn send_command(
         &mut self,
         command: RStr<'_>,
-        _app: ApplicationMut<'_>,
+        app: ApplicationMut<'_>,
     ) -> RResult<RString, AppError> {
-        println!("command:\n{}", command);
-        ROk(RString::from("send messge to plugin server success"))
+        match CommandView::parse(command.as_str()) {
+            Ok(command) => self.handle_command(&command, app).and_then(|reply| {
+                reply
+                    .into_response_json(&command.request_id, &self.plugin_id)
+                    .into()
+            }),
+            Err(e) => RErr(e),
+        }
+    }
+
+    fn handle_command(
+        &mut self,
+        command: &CommandView,
+        _app: ApplicationMut<'_>,
+    ) -> RResult<CommandReply, AppError> {
+        println!(
+            "command:\n    {} {} (request {})",
+            command.action.as_ref(),
+            command.target,
+            command.request_id
+        );
+        ROk(CommandReply::new_status(
+            OpenC2RespStatus::OK,
+            "send messge to plugin server success",
+        ))
     }
 
     fn plugin_id(&self) -> &PluginId {
@@ -0,0 +1,113 @@
+//! A plugin for switches that runs as a separate process.
+//!
+//! It reads the messages of the application from stdin and writes its answers to stdout,
+//! one JSON value per line, so it logs to stderr.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use common::{
+    ipc::{HostMessage, PluginMessage, ReplyMessage},
+    openc2::{command::OpenC2Action, response::OpenC2RespStatus, view::CommandView},
+};
+
+fn main() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout().lock();
+    let mut plugin_id = String::new();
+
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let message = match serde_json::from_str::<HostMessage>(&line) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("plugin_switch: invalid message {:?}: {}", line, e);
+                continue;
+            }
+        };
+
+        let (id, answer) = match message {
+            HostMessage::New {
+                id,
+                plugin_id: assigned,
+            } => {
+                plugin_id = assigned;
+                (id, Ok(Value::Null))
+            }
+            HostMessage::PluginId { id } => (id, Ok(json!(plugin_id))),
+            HostMessage::SendCommand { id, command } => (
+                id,
+                send_command(&command, &mut stdout)?.map(|reply| json!(reply)),
+            ),
+            HostMessage::HandleResponse { id, .. } => (id, Ok(Value::Null)),
+            HostMessage::Close { id } => {
+                write_message(
+                    &mut stdout,
+                    &PluginMessage::Ok {
+                        id,
+                        value: Value::Null,
+                    },
+                )?;
+                break;
+            }
+        };
+
+        let answer = match answer {
+            Ok(value) => PluginMessage::Ok { id, value },
+            Err(error) => PluginMessage::Err { id, error },
+        };
+        write_message(&mut stdout, &answer)?;
+    }
+    Ok(())
+}
+
+fn write_message(out: &mut impl Write, message: &PluginMessage) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, message)?;
+    out.write_all(b"\n")?;
+    out.flush()
+}
+
+fn reply(status: OpenC2RespStatus, desc: &str, results: Vec<Value>) -> ReplyMessage {
+    let (status, status_text) = status.into();
+    ReplyMessage {
+        status,
+        status_text,
+        desc: desc.into(),
+        results,
+    }
+}
+
+fn send_command(command: &str, out: &mut impl Write) -> io::Result<Result<ReplyMessage, String>> {
+    let command = match CommandView::parse(command) {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e.to_string())),
+    };
+    eprintln!(
+        "command:\n    {} {} (request {})",
+        command.action.as_ref(),
+        command.target,
+        command.request_id
+    );
+
+    if command.action == OpenC2Action::Query && command.target == "artifact.device/mac" {
+        return Ok(Ok(reply(
+            OpenC2RespStatus::OK,
+            "",
+            vec![json!({"port": "ge-0/0/1", "mac": "00:1a:2b:3c:4d:5e", "vlan": 10})],
+        )));
+    }
+
+    write_message(
+        out,
+        &PluginMessage::ReportStatus {
+            request_id: command.request_id.to_string(),
+            reply: reply(OpenC2RespStatus::Processing, "pushing cli commands", vec![]),
+        },
+    )?;
+    Ok(Ok(reply(
+        OpenC2RespStatus::OK,
+        "send messge to plugin switch success",
+        vec![],
+    )))
+}
@@ -0,0 +1,130 @@
+//! Runs the `plugin_switch` executable and talks to it like the application does.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+};
+
+use serde_json::{json, Value};
+
+use common::ipc::{HostMessage, PluginMessage};
+
+struct Switch {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Switch {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_plugin_switch"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Switch {
+            child,
+            stdin,
+            stdout,
+        }
+    }
+
+    /// Sends a message, returning the answer to it and the messages sent before the answer.
+    fn call(&mut self, message: HostMessage) -> (PluginMessage, Vec<PluginMessage>) {
+        serde_json::to_writer(&mut self.stdin, &message).unwrap();
+        self.stdin.write_all(b"\n").unwrap();
+        self.stdin.flush().unwrap();
+
+        let mut notifications = Vec::new();
+        loop {
+            let mut line = String::new();
+            assert_ne!(
+                self.stdout.read_line(&mut line).unwrap(),
+                0,
+                "the plugin exited"
+            );
+            match serde_json::from_str::<PluginMessage>(&line).unwrap() {
+                answer @ (PluginMessage::Ok { .. } | PluginMessage::Err { .. }) => {
+                    return (answer, notifications)
+                }
+                notification => notifications.push(notification),
+            }
+        }
+    }
+}
+
+fn command(request_id: &str, target: Value) -> String {
+    json!({
+        "header": {
+            "request_id": request_id,
+            "msg_type": "request",
+            "version": "1.0",
+            "created": 0,
+            "sender": "gateway"
+        },
+        "command": {"action": "query", "target": target}
+    })
+    .to_string()
+}
+
+fn value(answer: PluginMessage, expected_id: u64) -> Value {
+    match answer {
+        PluginMessage::Ok { id, value } if id == expected_id => value,
+        other => panic!("expected the answer to {}, got {:?}", expected_id, other),
+    }
+}
+
+#[test]
+fn test_plugin_switch_follows_the_protocol() {
+    let mut switch = Switch::start();
+
+    let (answer, _) = switch.call(HostMessage::New {
+        id: 1,
+        plugin_id: "switch".into(),
+    });
+    assert_eq!(value(answer, 1), Value::Null);
+    let (answer, _) = switch.call(HostMessage::PluginId { id: 2 });
+    assert_eq!(value(answer, 2), "switch");
+
+    let (answer, notifications) = switch.call(HostMessage::SendCommand {
+        id: 3,
+        command: command(
+            "sw-1",
+            json!({"artifact": {"mime_type": "device/mac", "payload": {}}}),
+        ),
+    });
+    let reply = value(answer, 3);
+    assert_eq!(reply["status"], 200);
+    assert_eq!(reply["results"][0]["mac"], "00:1a:2b:3c:4d:5e");
+    assert!(notifications.is_empty());
+
+    let (answer, notifications) = switch.call(HostMessage::SendCommand {
+        id: 4,
+        command: command("sw-2", json!({"features": ["versions"]})),
+    });
+    assert_eq!(value(answer, 4)["status"], 200);
+    match notifications.as_slice() {
+        [PluginMessage::ReportStatus { request_id, reply }] => {
+            assert_eq!(request_id, "sw-2");
+            assert_eq!(reply.status, 102);
+        }
+        other => panic!("expected a status report, got {:?}", other),
+    }
+
+    let (answer, _) = switch.call(HostMessage::SendCommand {
+        id: 5,
+        command: "not a command".into(),
+    });
+    assert!(
+        matches!(answer, PluginMessage::Err { id: 5, .. }),
+        "{:?}",
+        answer
+    );
+
+    let (answer, _) = switch.call(HostMessage::Close { id: 6 });
+    assert_eq!(value(answer, 6), Value::Null);
+    assert!(switch.child.wait().unwrap().success());
+}